@@ -0,0 +1,312 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+
+use chrono::NaiveDate;
+use log::{debug, info};
+use quick_xml::Reader;
+use quick_xml::events::Event;
+use rust_decimal::Decimal;
+
+/// Days a rate stays valid, central banks publish no rates on weekends and holidays so the
+/// latest rate before the date is used.
+const MAX_RATE_AGE_DAYS: i64 = 7;
+
+/// Historical daily exchange rates loaded from central bank downloads:
+///
+/// - ECB reference rates, `eurofxref-hist.csv` or `eurofxref-hist.xml`, euro rates
+/// - Norges Bank exchange rates as semicolon separated csv (`BASE_CUR`, `QUOTE_CUR`,
+///   `UNIT_MULT`, `TIME_PERIOD` and `OBS_VALUE` columns), krone rates
+/// - Riksbank series as semicolon separated csv with the columns `Date`, `Series` (e.g.
+///   `SEKUSDPMI`) and `Value`, and an optional `Unit` column for currencies quoted per
+///   100 units, krona rates
+///
+/// Rates between two currencies without a common table are crossed over a currency both
+/// are quoted in, e.g. USD to NOK over EUR with the ECB rates.
+#[derive(Debug, Default)]
+pub struct FxRates {
+    /// Rates by (base, quote) currency, one unit of base costs the rate in quote.
+    rates: HashMap<(String, String), BTreeMap<NaiveDate, Decimal>>,
+}
+
+impl FxRates {
+    pub fn new() -> Self {
+        FxRates::default()
+    }
+
+    /// Adds the rate of one unit of `base` in `quote` on `date`.
+    pub fn insert(&mut self, date: NaiveDate, base: &str, quote: &str, rate: Decimal) {
+        self.rates
+            .entry((base.to_string(), quote.to_string()))
+            .or_default()
+            .insert(date, rate);
+    }
+
+    /// Loads a rate file, the format is recognised from its first line.
+    pub fn load_file(&mut self, file_path: &str) -> Result<(), Box<dyn Error>> {
+        let data = std::fs::read_to_string(file_path)?;
+        let first_line = data.lines().find(|l| !l.trim().is_empty()).unwrap_or("");
+        let count = self.count();
+        if first_line.trim_start().starts_with('<') {
+            self.parse_ecb_xml(&data)?;
+        } else if first_line.contains("BASE_CUR") {
+            self.parse_norges_bank(&data)?;
+        } else if first_line.starts_with("Date,") {
+            self.parse_ecb_csv(&data)?;
+        } else if first_line.contains(';') {
+            self.parse_riksbank(&data)?;
+        } else {
+            return Err(format!("{} is not a known exchange rate file", file_path).into());
+        }
+        info!("Loaded {} rates from {}", self.count() - count, file_path);
+        Ok(())
+    }
+
+    fn count(&self) -> usize {
+        self.rates.values().map(|r| r.len()).sum()
+    }
+
+    fn parse_ecb_csv(&mut self, data: &str) -> Result<(), Box<dyn Error>> {
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let headers = reader.headers()?.clone();
+        for record in reader.records() {
+            let record = record?;
+            let date = NaiveDate::parse_from_str(&record[0], "%Y-%m-%d")?;
+            for (currency, value) in headers.iter().zip(record.iter()).skip(1) {
+                // Currencies the ECB stopped quoting are N/A, the header ends with a comma
+                if currency.is_empty() || value.is_empty() || value == "N/A" {
+                    continue;
+                }
+                self.insert(date, "EUR", currency, value.parse()?);
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_ecb_xml(&mut self, data: &str) -> Result<(), Box<dyn Error>> {
+        let mut reader = Reader::from_str(data);
+        let mut date = None;
+        loop {
+            match reader.read_event()? {
+                Event::Start(element) | Event::Empty(element)
+                    if element.local_name().as_ref() == b"Cube" =>
+                {
+                    let mut currency = None;
+                    let mut rate = None;
+                    for attribute in element.attributes() {
+                        let attribute = attribute?;
+                        let value = attribute.decode_and_unescape_value(&reader)?.to_string();
+                        match attribute.key.as_ref() {
+                            b"time" => date = Some(NaiveDate::parse_from_str(&value, "%Y-%m-%d")?),
+                            b"currency" => currency = Some(value),
+                            b"rate" => rate = Some(value.parse::<Decimal>()?),
+                            _ => {}
+                        }
+                    }
+                    if let (Some(date), Some(currency), Some(rate)) = (date, currency, rate) {
+                        self.insert(date, "EUR", &currency, rate);
+                    }
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_norges_bank(&mut self, data: &str) -> Result<(), Box<dyn Error>> {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(b';')
+            .from_reader(data.as_bytes());
+        let headers = reader.headers()?.clone();
+        let column = |name: &str| {
+            headers
+                .iter()
+                .position(|h| h == name)
+                .ok_or_else(|| format!("Missing column {} in Norges Bank rates", name))
+        };
+        let (base, quote) = (column("BASE_CUR")?, column("QUOTE_CUR")?);
+        let (date, value) = (column("TIME_PERIOD")?, column("OBS_VALUE")?);
+        let unit_mult = headers.iter().position(|h| h == "UNIT_MULT");
+        for record in reader.records() {
+            let record = record?;
+            if record[value].is_empty() {
+                continue;
+            }
+            // Rates of currencies like JPY are quoted per 10^UNIT_MULT units
+            let exponent: u32 = unit_mult
+                .map(|i| record[i].parse())
+                .transpose()?
+                .unwrap_or(0);
+            let divisor = 10u64
+                .checked_pow(exponent)
+                .ok_or_else(|| format!("Invalid UNIT_MULT {} in Norges Bank rates", exponent))?;
+            let rate = parse_rate(&record[value])? / Decimal::from(divisor);
+            let date = NaiveDate::parse_from_str(&record[date], "%Y-%m-%d")?;
+            self.insert(date, &record[base], &record[quote], rate);
+        }
+        Ok(())
+    }
+
+    fn parse_riksbank(&mut self, data: &str) -> Result<(), Box<dyn Error>> {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(b';')
+            .from_reader(data.as_bytes());
+        let headers = reader.headers()?.clone();
+        let column = |names: &[&str]| headers.iter().position(|h| names.contains(&h));
+        let missing = || "Riksbank rates need Date, Series and Value columns".to_string();
+        let date = column(&["Date", "Datum", "Period"]).ok_or_else(missing)?;
+        let series = column(&["Series", "Serie"]).ok_or_else(missing)?;
+        let value = column(&["Value", "Värde"]).ok_or_else(missing)?;
+        let unit = column(&["Unit", "Enhet"]);
+        for record in reader.records() {
+            let record = record?;
+            let currency = record[series]
+                .strip_prefix("SEK")
+                .and_then(|s| s.get(..3))
+                .ok_or_else(|| format!("Unknown Riksbank series {}", &record[series]))?;
+            if record[value].is_empty() || record[value].eq_ignore_ascii_case("n/a") {
+                continue;
+            }
+            let units = match unit {
+                Some(index) if !record[index].is_empty() => parse_rate(&record[index])?,
+                _ => Decimal::ONE,
+            };
+            let date = NaiveDate::parse_from_str(&record[date], "%Y-%m-%d")?;
+            self.insert(date, currency, "SEK", parse_rate(&record[value])? / units);
+        }
+        Ok(())
+    }
+
+    /// Rate of the pair on `date` or the latest day before, the inverse rate is used for
+    /// pairs only quoted the other way round.
+    fn direct(&self, date: NaiveDate, from: &str, to: &str) -> Option<Decimal> {
+        let latest = |base: &str, quote: &str| {
+            self.rates
+                .get(&(base.to_string(), quote.to_string()))
+                .and_then(|r| r.range(..=date).next_back())
+                .filter(|(day, _)| (date - **day).num_days() <= MAX_RATE_AGE_DAYS)
+                .map(|(_, rate)| *rate)
+        };
+        latest(from, to).or_else(|| {
+            latest(to, from)
+                .filter(|r| !r.is_zero())
+                .map(|r| Decimal::ONE / r)
+        })
+    }
+
+    /// Units of `to` for one unit of `from` on `date`.
+    pub fn rate(&self, date: NaiveDate, from: &str, to: &str) -> Result<Decimal, Box<dyn Error>> {
+        if from == to {
+            return Ok(Decimal::ONE);
+        }
+        if let Some(rate) = self.direct(date, from, to) {
+            return Ok(rate);
+        }
+        let mut via: Vec<&str> = self
+            .rates
+            .keys()
+            .filter_map(|(base, quote)| match (base.as_str(), quote.as_str()) {
+                (base, quote) if base == from => Some(quote),
+                (base, quote) if quote == from => Some(base),
+                _ => None,
+            })
+            .collect();
+        via.sort();
+        via.dedup();
+        for currency in via {
+            if let (Some(first), Some(second)) = (
+                self.direct(date, from, currency),
+                self.direct(date, currency, to),
+            ) {
+                debug!("{} to {} on {} over {}", from, to, date, currency);
+                return Ok(first * second);
+            }
+        }
+        Err(format!("No exchange rate from {} to {} on {}", from, to, date).into())
+    }
+}
+
+/// Parses rates written with a decimal point or a decimal comma.
+fn parse_rate(value: &str) -> Result<Decimal, rust_decimal::Error> {
+    value.trim().replace(',', ".").parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn load(files: &[&str]) -> FxRates {
+        let mut fx = FxRates::new();
+        for file in files {
+            fx.load_file(&format!(
+                "{}/test_files/fx/{}",
+                env!("CARGO_MANIFEST_DIR"),
+                file
+            ))
+            .unwrap();
+        }
+        fx
+    }
+
+    fn date(date: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn test_ecb_rates() {
+        let csv = load(&["eurofxref-hist.csv"]);
+        let xml = load(&["eurofxref-hist.xml"]);
+        for fx in [csv, xml] {
+            assert_eq!(
+                fx.rate(date("2024-01-03"), "EUR", "USD").unwrap(),
+                dec!(1.0919)
+            );
+            // Saturday uses the rate of Friday, rates older than a week are not used
+            assert_eq!(
+                fx.rate(date("2024-01-06"), "EUR", "NOK").unwrap(),
+                dec!(11.2025)
+            );
+            assert!(fx.rate(date("2024-03-01"), "EUR", "NOK").is_err());
+            assert_eq!(
+                fx.rate(date("2024-01-03"), "USD", "NOK")
+                    .unwrap()
+                    .round_dp(6),
+                dec!(10.267424)
+            );
+            assert!(fx.rate(date("2024-01-03"), "EUR", "CYP").is_err());
+        }
+    }
+
+    #[test]
+    fn test_norges_bank_and_riksbank_rates() {
+        let fx = load(&["norges_bank.csv", "riksbank.csv"]);
+        assert_eq!(
+            fx.rate(date("2024-01-03"), "USD", "NOK").unwrap(),
+            dec!(10.2677)
+        );
+        assert_eq!(
+            fx.rate(date("2024-01-03"), "JPY", "NOK").unwrap(),
+            dec!(0.071648)
+        );
+        assert_eq!(
+            (dec!(100) * fx.rate(date("2024-01-03"), "NOK", "USD").unwrap()).round_dp(4),
+            dec!(9.7393)
+        );
+        assert_eq!(
+            fx.rate(date("2024-01-03"), "USD", "SEK").unwrap(),
+            dec!(10.2521)
+        );
+        assert_eq!(
+            fx.rate(date("2024-01-03"), "DKK", "SEK").unwrap(),
+            dec!(1.4865)
+        );
+
+        // A unit multiplier too large for the rate is an error instead of a panic
+        let mut fx = FxRates::new();
+        let data =
+            "BASE_CUR;QUOTE_CUR;TIME_PERIOD;OBS_VALUE;UNIT_MULT\nJPY;NOK;2024-01-03;7.1648;20\n";
+        assert!(fx.parse_norges_bank(data).is_err());
+    }
+}
@@ -1,19 +1,314 @@
-use log::{debug, info};
+use log::debug;
+use rust_decimal::Decimal;
 use serde::Deserialize;
 
-use crate::portfolio::{Action, Positions, Stock};
+use crate::portfolio::{Action, Stock};
 
-use super::Importer;
+use super::{FileSniff, Importer, SimpleError, add_account_share};
 
+/// How a sell is told apart from a buy in a broker export.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub enum SellSign {
+    /// The action column decides, the sign of the quantity is ignored.
+    Absolute,
+    /// Negative quantities are sells, positive quantities are buys. The action column is
+    /// optional with this convention and only selects which rows are trades.
+    NegativeIsSell,
+}
+
+/// Mapping profile telling the [`CsvImporter`] which csv header holds which field.
+///
+/// The default profile matches the layout written by `Portfolio::export_csv_to_disk`, so
+/// an exported portfolio can be imported again without a profile:
+///
+/// | Column   | Required | Description                                          |
+/// |----------|----------|------------------------------------------------------|
+/// | Ticker   | yes      | Symbol of the position, e.g. `AAPL`                  |
+/// | Date     | yes      | Trade date formatted as `YYYY-MM-DD`                 |
+/// | Units    | yes      | Number of shares, fractions allowed                  |
+/// | Price    | yes      | Price per share                                      |
+/// | Value    | no       | Units * Price, ignored on import                     |
+/// | Action   | yes      | `Buy`, `Sell`, `Dividend` or `WithholdingTax`        |
+/// | Currency | yes      | Currency of the price, e.g. `USD`                    |
+/// | Metadata | no       | Free text identifying the lot (`ESPP`, `RSU-1-2`...) |
+/// | Fee      | no       | Commissions and fees of the trade, see `Stock::fee`  |
+/// | Id       | no       | Stable id of the transaction, see `Stock::id`        |
+/// | Account  | no       | Id of the account holding the lot                    |
+///
+/// Columns are matched by header name, so their order does not matter and extra columns
+/// are ignored. Profiles are loaded from TOML or JSON with [`CsvMapping::from_file`], any
+/// field left out keeps its default value:
+///
+/// ```toml
+/// delimiter = ";"
+/// decimal_separator = ","
+/// date = "Handelsdag"
+/// date_format = "%d.%m.%Y"
+/// ticker = "Verdipapir"
+/// quantity = "Antall"
+/// price = "Kurs"
+/// currency = "Valuta"
+/// action = "Transaksjonstype"
+/// buy_values = ["KJØPT"]
+/// sell_values = ["SOLGT"]
+/// ```
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct CsvMapping {
+    /// Field delimiter of the file.
+    pub delimiter: char,
+    /// Decimal separator of numbers, `.` or `,`. The other one is treated as a thousands
+    /// separator and removed.
+    pub decimal_separator: char,
+    /// Header of the trade date column.
+    pub date: String,
+    /// `chrono` format string of the trade date.
+    pub date_format: String,
+    /// Header of the ticker column.
+    pub ticker: String,
+    /// Header of the quantity column.
+    pub quantity: String,
+    /// Header of the price per share column.
+    pub price: String,
+    /// Header of the currency column, `default_currency` is used when it is not set.
+    pub currency: Option<String>,
+    /// Currency used when the file has no currency column.
+    pub default_currency: String,
+    /// Header of the action column.
+    pub action: Option<String>,
+    /// Values of the action column meaning buy.
+    pub buy_values: Vec<String>,
+    /// Values of the action column meaning sell.
+    pub sell_values: Vec<String>,
+    /// Values of the action column meaning a dividend payment, the quantity is the number of
+    /// shares and the price the dividend per share.
+    pub dividend_values: Vec<String>,
+    /// Values of the action column meaning tax withheld from a dividend.
+    pub withholding_tax_values: Vec<String>,
+    /// Sign convention used for sells.
+    pub sell_sign: SellSign,
+    /// Header of the column stored as lot metadata.
+    pub metadata: Option<String>,
+    /// Header of the column with the commissions and fees of the trade.
+    pub fee: Option<String>,
+    /// Header of the column holding the id of the transaction. Rows without one get an id
+    /// made of ticker, date and action, numbered when different rows share it.
+    pub id: Option<String>,
+    /// Header of the column with the id of the account holding the lot, lots without one
+    /// are imported into the account chosen for the import.
+    pub account: Option<String>,
+}
+
+impl Default for CsvMapping {
+    fn default() -> Self {
+        CsvMapping {
+            delimiter: ',',
+            decimal_separator: '.',
+            date: "Date".to_string(),
+            date_format: "%Y-%m-%d".to_string(),
+            ticker: "Ticker".to_string(),
+            quantity: "Units".to_string(),
+            price: "Price".to_string(),
+            currency: Some("Currency".to_string()),
+            default_currency: "USD".to_string(),
+            action: Some("Action".to_string()),
+            buy_values: vec!["Buy".to_string()],
+            sell_values: vec!["Sell".to_string()],
+            dividend_values: vec!["Dividend".to_string()],
+            withholding_tax_values: vec!["WithholdingTax".to_string()],
+            sell_sign: SellSign::Absolute,
+            metadata: Some("Metadata".to_string()),
+            fee: Some("Fee".to_string()),
+            id: Some("Id".to_string()),
+            account: Some("Account".to_string()),
+        }
+    }
+}
+
+impl CsvMapping {
+    /// Loads a mapping profile, files ending in `.toml` are read as TOML and everything
+    /// else as JSON.
+    pub fn from_file(file_path: &str) -> Result<CsvMapping, Box<dyn std::error::Error>> {
+        let data = std::fs::read_to_string(file_path)?;
+        let mapping: CsvMapping = if file_path.to_lowercase().ends_with(".toml") {
+            toml::from_str(data.as_str())?
+        } else {
+            serde_json::from_str(data.as_str())?
+        };
+        mapping.validate()?;
+        Ok(mapping)
+    }
+
+    /// Checks the settings the csv reader can not take as they are.
+    fn validate(&self) -> Result<(), SimpleError> {
+        if !self.delimiter.is_ascii() {
+            return Err(SimpleError(format!(
+                "Delimiter {:?} is not an ASCII character",
+                self.delimiter
+            )));
+        }
+        if self.decimal_separator != '.' && self.decimal_separator != ',' {
+            return Err(SimpleError(format!(
+                "Decimal separator {:?} is not . or ,",
+                self.decimal_separator
+            )));
+        }
+        Ok(())
+    }
+
+    fn parse_number(&self, value: &str) -> Result<Decimal, Box<dyn std::error::Error>> {
+        let thousands = if self.decimal_separator == ',' {
+            '.'
+        } else {
+            ','
+        };
+        let cleaned: String = value
+            .chars()
+            .filter(|c| *c != thousands && !c.is_whitespace() && *c != '$')
+            .map(|c| if c == self.decimal_separator { '.' } else { c })
+            .collect();
+        cleaned
+            .parse()
+            .map_err(|_| Box::new(SimpleError(format!("Invalid number {}", value))).into())
+    }
+}
+
+/// Index of every mapped column in the header row of a file.
+struct ColumnIndex {
+    date: usize,
+    ticker: usize,
+    quantity: usize,
+    price: usize,
+    currency: Option<usize>,
+    action: Option<usize>,
+    metadata: Option<usize>,
+    fee: Option<usize>,
+    id: Option<usize>,
+    account: Option<usize>,
+}
+
+impl ColumnIndex {
+    fn new(mapping: &CsvMapping, headers: &csv::StringRecord) -> Result<Self, SimpleError> {
+        let find = |name: &str| headers.iter().position(|h| h == name);
+        let require = |name: &str| {
+            find(name).ok_or_else(|| SimpleError(format!("Missing column {} in csv file", name)))
+        };
+        let action = match &mapping.action {
+            Some(name) if mapping.sell_sign == SellSign::NegativeIsSell => find(name),
+            Some(name) => Some(require(name)?),
+            None if mapping.sell_sign == SellSign::NegativeIsSell => None,
+            None => {
+                return Err(SimpleError(
+                    "An action column is required unless sell_sign is NegativeIsSell".into(),
+                ));
+            }
+        };
+        Ok(ColumnIndex {
+            date: require(&mapping.date)?,
+            ticker: require(&mapping.ticker)?,
+            quantity: require(&mapping.quantity)?,
+            price: require(&mapping.price)?,
+            currency: mapping.currency.as_deref().map(require).transpose()?,
+            action,
+            metadata: mapping.metadata.as_deref().and_then(find),
+            fee: mapping.fee.as_deref().and_then(find),
+            id: mapping.id.as_deref().and_then(find),
+            account: mapping.account.as_deref().and_then(find),
+        })
+    }
+}
+
+/// Generic importer for hand written CSV files, files exported by rustyfolio itself and
+/// broker exports described by a [`CsvMapping`].
 pub struct CsvImporter {
     positions: Vec<crate::Positions>,
+    mapping: CsvMapping,
 }
 
 impl CsvImporter {
     pub fn new() -> Self {
-        CsvImporter { positions: vec![] }
+        CsvImporter::with_mapping(CsvMapping::default())
+    }
+    pub fn with_mapping(mapping: CsvMapping) -> Self {
+        CsvImporter {
+            positions: vec![],
+            mapping,
+        }
     }
-    fn import_csv(file: &String) -> Result<(), Box<dyn std::error::Error>> {
+    fn import_csv(&mut self, file: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.mapping.validate()?;
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(self.mapping.delimiter as u8)
+            .trim(csv::Trim::All)
+            .from_path(file)?;
+        let columns = ColumnIndex::new(&self.mapping, reader.headers()?)?;
+        for val in reader.records() {
+            let record = val?;
+            debug!("{:?}", record);
+            let field = |index: usize| record.get(index).unwrap_or("");
+            let quantity = self.mapping.parse_number(field(columns.quantity))?;
+            let action_value = columns.action.map(field);
+            let is_buy = |value: &str| self.mapping.buy_values.iter().any(|v| v == value);
+            let is_sell = |value: &str| self.mapping.sell_values.iter().any(|v| v == value);
+            let is_dividend = |value: &str| self.mapping.dividend_values.iter().any(|v| v == value);
+            let is_withholding_tax = |value: &str| {
+                self.mapping
+                    .withholding_tax_values
+                    .iter()
+                    .any(|v| v == value)
+            };
+            if let Some(value) = action_value
+                && !is_buy(value)
+                && !is_sell(value)
+                && !is_dividend(value)
+                && !is_withholding_tax(value)
+            {
+                debug!("Skipping row with action {}", value);
+                continue;
+            }
+            let action = match (&self.mapping.sell_sign, action_value) {
+                (_, Some(value)) if is_dividend(value) => Action::Dividend,
+                (_, Some(value)) if is_withholding_tax(value) => Action::WithholdingTax,
+                (SellSign::NegativeIsSell, _) if quantity.is_sign_negative() => Action::Sell,
+                (SellSign::NegativeIsSell, _) => Action::Buy,
+                (SellSign::Absolute, Some(value)) if is_buy(value) => Action::Buy,
+                (SellSign::Absolute, _) => Action::Sell,
+            };
+            let date = chrono::NaiveDate::parse_from_str(
+                field(columns.date),
+                self.mapping.date_format.as_str(),
+            )?;
+            let currency = match columns.currency {
+                Some(index) => field(index).to_string(),
+                None => self.mapping.default_currency.clone(),
+            };
+            let id = match columns.id.map(field) {
+                Some(id) if !id.is_empty() => id.to_string(),
+                _ => format!("CSV-{}-{}-{:?}", field(columns.ticker), date, action),
+            };
+            let fee = match columns.fee.map(field).filter(|fee| !fee.is_empty()) {
+                Some(fee) => self.mapping.parse_number(fee)?.abs(),
+                None => Decimal::ZERO,
+            };
+            let share = Stock {
+                date,
+                price: self.mapping.parse_number(field(columns.price))?.abs(),
+                currency,
+                unit: quantity.abs(),
+                action,
+                metadata: columns.metadata.map(field).unwrap_or("").to_string(),
+                fee,
+                id,
+                espp: None,
+                lots: vec![],
+            };
+            add_account_share(
+                &mut self.positions,
+                columns.account.map(field).unwrap_or(""),
+                field(columns.ticker),
+                share,
+            );
+        }
         Ok(())
     }
 }
@@ -21,20 +316,38 @@ impl CsvImporter {
 impl Importer for CsvImporter {
     fn import(
         &mut self,
-        file_paths: &Vec<String>,
-    ) -> Result<&Vec<crate::Positions>, Box<dyn std::error::Error>> {
+        file_paths: &[String],
+    ) -> Result<Vec<crate::Positions>, Box<dyn std::error::Error>> {
         for file in file_paths.iter() {
-            CsvImporter::import_csv(file)?;
+            self.import_csv(file)?;
+        }
+        Ok(std::mem::take(&mut self.positions))
+    }
+    fn detect(&self, file: &FileSniff) -> u8 {
+        // Any csv file can be mapped, so a matching header only gives a low score
+        let headers: Vec<&str> = file
+            .first_line()
+            .split(self.mapping.delimiter)
+            .map(|h| h.trim().trim_matches('"'))
+            .collect();
+        let mapping = &self.mapping;
+        let required = [
+            &mapping.date,
+            &mapping.ticker,
+            &mapping.quantity,
+            &mapping.price,
+        ];
+        if file.sheets.is_empty() && required.iter().all(|r| headers.contains(&r.as_str())) {
+            50
+        } else {
+            0
         }
-        todo!("Some post processing?");
-
-        Ok(&self.positions)
     }
 }
 
+#[cfg(test)]
 mod tests {
-    use crate::importer::ImporterService;
-    use crate::portfolio::{Portfolio, Positions};
+    use crate::portfolio::Portfolio;
     use std::io::Read;
 
     use super::*;
@@ -56,15 +369,61 @@ mod tests {
     }
 
     #[test]
-    #[ignore = "not yet implemented"]
     fn test_csv_importer() {
         let test_portfolio = init().unwrap();
-        let csvimporter = CsvImporter::new();
-        let mut importer =
-            ImporterService::new_importer(csvimporter).expect("Creating CSV importer failed");
+        let mut csvimporter = CsvImporter::new();
         let custom_csv = format!("{}/test_files/custom.csv", env!("CARGO_MANIFEST_DIR"));
-        let res = importer.run(&vec![custom_csv]).unwrap();
+        let res = csvimporter.import(&[custom_csv]).unwrap();
+        debug!("{:?}", res);
+        assert_eq!(res, test_portfolio.stocks);
+    }
+
+    #[test]
+    fn test_csv_importer_mapping() {
+        let test_portfolio = init().unwrap();
+        let mapping = CsvMapping::from_file(&format!(
+            "{}/test_files/custom_mapping.toml",
+            env!("CARGO_MANIFEST_DIR")
+        ))
+        .expect("Loading mapping profile failed");
+        let mut csvimporter = CsvImporter::with_mapping(mapping);
+        let custom_csv = format!(
+            "{}/test_files/custom_mapped.csv",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let res = csvimporter.import(&[custom_csv]).unwrap();
         debug!("{:?}", res);
-        assert_eq!(res, &test_portfolio.stocks);
+        assert_eq!(res, test_portfolio.stocks);
+    }
+
+    #[test]
+    fn test_csv_export_round_trip() {
+        let mut portfolio = init().unwrap();
+        portfolio.stocks[0].shares[0].fee = rust_decimal_macros::dec!(4.95);
+        portfolio.stocks[0].account = "etrade".to_string();
+        portfolio.stocks[0].shares[0].metadata = "Bought at \"market\", limit 100".to_string();
+        let export = std::env::temp_dir().join("rustyfolio_csv_round_trip.csv");
+        portfolio.export_csv_to_disk(&export).unwrap();
+        let res = CsvImporter::new()
+            .import(&[export.to_string_lossy().to_string()])
+            .unwrap();
+        std::fs::remove_file(&export).unwrap();
+        assert_eq!(res, portfolio.stocks);
+    }
+
+    #[test]
+    fn test_csv_mapping_validation() {
+        let mapping: CsvMapping = toml::from_str("delimiter = \"§\"").unwrap();
+        assert!(mapping.validate().is_err());
+        let mapping: CsvMapping = toml::from_str("decimal_separator = \" \"").unwrap();
+        assert!(mapping.validate().is_err());
+        let mapping: CsvMapping = toml::from_str("delimiter = \";\"").unwrap();
+        assert!(mapping.validate().is_ok());
+        let mut csvimporter = CsvImporter::with_mapping(CsvMapping {
+            delimiter: '§',
+            ..CsvMapping::default()
+        });
+        let custom_csv = format!("{}/test_files/custom.csv", env!("CARGO_MANIFEST_DIR"));
+        assert!(csvimporter.import(&[custom_csv]).is_err());
     }
 }
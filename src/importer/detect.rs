@@ -0,0 +1,209 @@
+use calamine::{Data, Reader, open_workbook_auto};
+use log::{debug, info, warn};
+
+use super::{Importer, decode_text};
+
+/// Number of bytes read from the start of text files to recognise them.
+const SNIFF_BYTES: usize = 16 * 1024;
+/// Number of rows read from the first sheet of spreadsheets to recognise them.
+const SNIFF_ROWS: usize = 20;
+
+/// The start of a file, used by `Importer::detect` to recognise statements without
+/// parsing them completely.
+#[derive(Debug, Default)]
+pub struct FileSniff {
+    /// Lowercase extension without the dot.
+    pub extension: String,
+    /// Start of the file as text. UTF-16 files are decoded, for spreadsheets the first rows
+    /// of the first sheet are given with tab separated cells.
+    pub head: String,
+    /// Sheet names of spreadsheets, empty for text files.
+    pub sheets: Vec<String>,
+}
+
+impl FileSniff {
+    pub fn new(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let extension = std::path::Path::new(path)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if matches!(extension.as_str(), "xlsx" | "xlsm" | "xls" | "ods") {
+            let mut workbook = open_workbook_auto(path)?;
+            let sheets = workbook.sheet_names();
+            let mut head = String::new();
+            if let Some(range) = workbook.worksheet_range_at(0) {
+                for row in range?.rows().take(SNIFF_ROWS) {
+                    let cells: Vec<String> = row
+                        .iter()
+                        .map(|cell| match cell {
+                            Data::Empty => String::new(),
+                            _ => cell.to_string(),
+                        })
+                        .collect();
+                    head.push_str(&cells.join("\t"));
+                    head.push('\n');
+                }
+            }
+            return Ok(FileSniff {
+                extension,
+                head,
+                sheets,
+            });
+        }
+        let mut data = std::fs::read(path)?;
+        data.truncate(SNIFF_BYTES);
+        Ok(FileSniff {
+            extension,
+            head: decode_text(&data),
+            sheets: vec![],
+        })
+    }
+    pub fn contains(&self, text: &str) -> bool {
+        self.head.contains(text)
+    }
+    pub fn has_sheet(&self, name: &str) -> bool {
+        self.sheets.iter().any(|s| s == name)
+    }
+    /// First non empty line of the file.
+    pub fn first_line(&self) -> &str {
+        self.head
+            .lines()
+            .map(|l| l.trim())
+            .find(|l| !l.is_empty())
+            .unwrap_or("")
+    }
+}
+
+/// Lists the files to import for a path, directories are listed without recursing into
+/// sub directories and hidden files are skipped.
+pub fn collect_files(path: &str) -> Result<Vec<String>, std::io::Error> {
+    let path = std::path::Path::new(path);
+    if !path.is_dir() {
+        return Ok(vec![path.to_string_lossy().to_string()]);
+    }
+    let mut files = vec![];
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if entry.file_type()?.is_file() && !hidden {
+            files.push(entry.path().to_string_lossy().to_string());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Routes every file to the importer giving it the highest `Importer::detect` score, on a
+/// tie the importer listed first wins.
+///
+/// Returns the files of each importer in the order of `importers` and the files no importer
+/// recognised.
+pub fn route_files(
+    importers: &[Box<dyn Importer>],
+    files: &[String],
+) -> (Vec<Vec<String>>, Vec<String>) {
+    let mut routed = vec![vec![]; importers.len()];
+    let mut unrecognised = vec![];
+    for file in files {
+        let sniff = match FileSniff::new(file) {
+            Ok(sniff) => sniff,
+            Err(e) => {
+                warn!("Could not read {}: {}", file, e);
+                unrecognised.push(file.clone());
+                continue;
+            }
+        };
+        let mut best: Option<(usize, u8)> = None;
+        for (index, importer) in importers.iter().enumerate() {
+            let score = importer.detect(&sniff);
+            debug!("{} scored {} by importer {}", file, score, index);
+            if score > 0 && best.is_none_or(|(_, best_score)| score > best_score) {
+                best = Some((index, score));
+            }
+        }
+        match best {
+            Some((index, score)) => {
+                info!("{} routed to importer {} with score {}", file, index, score);
+                routed[index].push(file.clone());
+            }
+            None => unrecognised.push(file.clone()),
+        }
+    }
+    (routed, unrecognised)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::importer::{
+        CsvImporter, EtradeImporter, FidelityImporter, IbkrImporter, MorganStanleyImporter,
+        NordnetImporter, OfxImporter, SchwabImporter,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_route_test_files() {
+        let _ = env_logger::builder()
+            .is_test(true)
+            .filter_level(log::LevelFilter::Trace)
+            .try_init();
+        let importers: Vec<Box<dyn Importer>> = vec![
+            Box::new(EtradeImporter::new()),
+            Box::new(CsvImporter::new()),
+            Box::new(SchwabImporter::new()),
+            Box::new(MorganStanleyImporter::new(None)),
+            Box::new(IbkrImporter::new()),
+            Box::new(FidelityImporter::new()),
+            Box::new(OfxImporter::new(HashMap::new())),
+            Box::new(NordnetImporter::new()),
+        ];
+        let dir = format!("{}/test_files", env!("CARGO_MANIFEST_DIR"));
+        let files = collect_files(&dir).unwrap();
+        let (routed, unrecognised) = route_files(&importers, &files);
+        let names = |files: &Vec<String>| -> Vec<String> {
+            files
+                .iter()
+                .map(|f| f.rsplit('/').next().unwrap().to_string())
+                .collect()
+        };
+        let routed: Vec<Vec<String>> = routed.iter().map(names).collect();
+        // The E*Trade spreadsheets are not part of the repository
+        let etrade: Vec<String> = [
+            "BenefitHistory.xlsx",
+            "DownloadTxnHistory.csv",
+            "G&L_Expanded.xlsx",
+        ]
+        .into_iter()
+        .filter(|f| std::path::Path::new(&format!("{}/{}", dir, f)).is_file())
+        .map(|f| f.to_string())
+        .collect();
+        assert_eq!(routed[0], etrade);
+        assert_eq!(routed[1], vec!["custom.csv"]);
+        assert_eq!(
+            routed[2],
+            vec![
+                "EquityAwardsCenter_Transactions.csv",
+                "EquityAwardsCenter_Transactions.json"
+            ]
+        );
+        assert_eq!(routed[3], vec!["Releases.csv", "Withdrawals.csv"]);
+        assert_eq!(routed[4], vec!["ibkr_flex.xml"]);
+        assert_eq!(
+            routed[5],
+            vec![
+                "NetBenefits_Custom_report.csv",
+                "NetBenefits_Transaction_history.csv"
+            ]
+        );
+        assert_eq!(routed[6], vec!["statement.ofx"]);
+        assert_eq!(routed[7], vec!["nordnet_transactions.csv"]);
+        assert!(
+            names(&unrecognised)
+                .iter()
+                .all(|f| !f.ends_with(".xlsx") && !f.ends_with(".xml") && !f.ends_with(".ofx"))
+        );
+        assert!(names(&unrecognised).contains(&"test_portfolio_csv.json".to_string()));
+    }
+}
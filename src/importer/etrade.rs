@@ -1,21 +1,18 @@
-use calamine::{RangeDeserializerBuilder, Reader, Xlsx, deserialize_as_f64_or_none, open_workbook};
+use calamine::{
+    Data, DataType, RangeDeserializerBuilder, Reader, Xlsx, deserialize_as_f64_or_none,
+    open_workbook,
+};
 use log::{debug, info};
+use rust_decimal::Decimal;
 use serde::Deserialize;
+use std::collections::HashMap;
 
-use crate::portfolio::{Action, Positions, Stock};
+use crate::portfolio::{Action, EsppPurchase, Stock};
 
-use super::Importer;
-
-#[derive(Debug)]
-struct SimpleError(String);
-
-impl std::fmt::Display for SimpleError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
-    }
-}
-
-impl std::error::Error for SimpleError {}
+use super::{
+    FileSniff, Importer, SimpleError, add_share, decimal_from_f64, id_part, parse_amount,
+    read_csv_rows,
+};
 
 #[derive(Debug, Deserialize)]
 struct EsppRecord {
@@ -24,13 +21,15 @@ struct EsppRecord {
     #[serde(rename = "Purchase Date")]
     purchase_date: Option<String>,
     #[serde(rename = "Purchase Price")]
-    #[serde(deserialize_with = "deserialize_as_f64_or_none")]
+    #[serde(default, deserialize_with = "deserialize_as_f64_or_none")]
     purchase_price: Option<f64>,
     #[serde(rename = "Purchased Qty.")]
     #[serde(deserialize_with = "deserialize_as_f64_or_none")]
     purchased_qty: Option<f64>,
+    #[serde(rename = "Grant Date")]
+    grant_date: Option<String>,
     #[serde(rename = "Grant Date FMV")]
-    #[serde(deserialize_with = "deserialize_as_f64_or_none")]
+    #[serde(default, deserialize_with = "deserialize_as_f64_or_none")]
     grant_date_fmv: Option<f64>,
     #[serde(rename = "Purchase Date FMV")]
     purchase_date_fmv: Option<String>,
@@ -50,6 +49,7 @@ struct RsuGrant {
 #[derive(Debug, Deserialize)]
 struct RsuGrantVest {
     #[serde(rename = "Record Type")]
+    #[allow(dead_code)]
     record: Option<String>,
     #[serde(rename = "Grant Number")]
     #[serde(deserialize_with = "deserialize_as_f64_or_none")]
@@ -69,6 +69,7 @@ struct RsuGrantVest {
 #[derive(Debug, Deserialize)]
 struct RsuTax {
     #[serde(rename = "Record Type")]
+    #[allow(dead_code)]
     record: Option<String>,
     #[serde(rename = "Grant Number")]
     #[serde(deserialize_with = "deserialize_as_f64_or_none")]
@@ -83,6 +84,7 @@ struct RsuTax {
 #[derive(Debug, Deserialize)]
 struct GainAndLoss {
     #[serde(rename = "Record Type")]
+    #[allow(dead_code)]
     event: Option<String>,
     #[serde(rename = "Symbol")]
     symbol: Option<String>,
@@ -96,10 +98,87 @@ struct GainAndLoss {
     price: Option<f64>,
     #[serde(rename = "Order Type")]
     order_type: Option<String>,
+    /// Values of the `GL_FEES` columns of the row.
+    #[serde(skip)]
+    fees: Vec<f64>,
+    /// Values of the `GL_KEYS` columns of the row joined by `-`, or the row number in the
+    /// sheet when none of them is present.
+    #[serde(skip)]
+    key: String,
+}
+
+impl GainAndLoss {
+    /// Reads the `GL_FEES` and `GL_KEYS` columns of `row`, the row `index` of the sheet after
+    /// the header row `headers`.
+    fn read_optional_columns(&mut self, headers: &[String], row: &[Data], index: usize) {
+        let columns = |names: &[&str]| -> Vec<usize> {
+            headers
+                .iter()
+                .enumerate()
+                .filter(|(_, header)| names.contains(&header.as_str()))
+                .map(|(index, _)| index)
+                .collect()
+        };
+        self.fees = columns(GL_FEES)
+            .iter()
+            .filter_map(|index| row.get(*index).and_then(|cell| cell.as_f64()))
+            .collect();
+        let key: Vec<String> = columns(GL_KEYS)
+            .iter()
+            .filter_map(|index| row.get(*index).map(|cell| cell.to_string()))
+            .filter(|value| !value.is_empty())
+            .collect();
+        // The header is the first row of the sheet
+        self.key = if key.is_empty() {
+            format!("ROW{}", index + 2)
+        } else {
+            key.join("-")
+        };
+    }
 }
 
+/// Commission and fee columns of `G&L_Expanded.xlsx`, they are only read when present.
+const GL_FEES: &[&str] = &["Commissions", "Commission", "Fees", "Other Fees"];
+
+/// Columns of `G&L_Expanded.xlsx` identifying the lot sold by an order, they are only read
+/// when present.
+const GL_KEYS: &[&str] = &[
+    "Order Number",
+    "Grant Number",
+    "Vest Period",
+    "Date Acquired",
+];
+
+/// One row of the brokerage transactions download, keyed by column header.
+type BrokerageRow = HashMap<String, String>;
+
+/// Column headers of the brokerage transactions download, the current layout first and the
+/// older `TransactionDate,TransactionType,...` layout second.
+const BROKERAGE_DATE: &[&str] = &["Activity/Trade Date", "TransactionDate"];
+const BROKERAGE_TYPE: &[&str] = &["Activity Type", "TransactionType"];
+const BROKERAGE_QUANTITY: &[&str] = &["Quantity #", "Quantity"];
+const BROKERAGE_PRICE: &[&str] = &["Price $", "Price"];
+const BROKERAGE_AMOUNT: &[&str] = &["Amount $", "Amount"];
+
+/// Importer for E*Trade, reading the stock plan workbooks `BenefitHistory.xlsx` and
+/// `G&L_Expanded.xlsx` and the transactions download of the brokerage account
+/// (`DownloadTxnHistory.csv`).
+///
+/// Shares transferred from the stock plan into the brokerage account keep the lot they
+/// were bought in, so transfers are only logged and later brokerage sales are matched
+/// against the stock plan lots of the same ticker.
+///
+/// Sales of `G&L_Expanded.xlsx` get the id `ETRADE-SELL-<order number>-...` from its order,
+/// grant and acquisition columns. The brokerage download has no transaction ids, its lots
+/// get the id `ETRADE-BROKERAGE-<date>-<activity>-<symbol>-<amount>` with the spaces of the
+/// activity replaced by `-`. Brokerage trades get the metadata
+/// `ETRADE-BROKERAGE <commission>` and the commission as fee, sales of `G&L_Expanded.xlsx`
+/// the sum of its commission and fee columns. Dividends and withheld tax are stored with the
+/// whole payment as price and the activity type as metadata, dividend reinvestments become buys
+/// with metadata `ETRADE-REINVEST <commission>`.
 pub struct EtradeImporter {
     positions: Vec<crate::Positions>,
+    brokerage: Vec<BrokerageRow>,
     espp: Vec<EsppRecord>,
     rsugrant: Vec<RsuGrant>,
     rsugrantvest: Vec<RsuGrantVest>,
@@ -111,6 +190,7 @@ impl EtradeImporter {
     pub fn new() -> Self {
         EtradeImporter {
             positions: vec![],
+            brokerage: vec![],
             espp: vec![],
             rsugrant: vec![],
             rsugrantvest: vec![],
@@ -118,6 +198,33 @@ impl EtradeImporter {
             gl_expanded: vec![],
         }
     }
+    fn parse_brokerage_csv(&mut self, file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let rows = read_csv_rows(file_path)?;
+        // The download starts with the account name and total before the table
+        let header_index = rows.iter().position(|row| {
+            row.iter()
+                .any(|cell| BROKERAGE_TYPE.contains(&cell.as_str()))
+        });
+        let header_index = match header_index {
+            Some(index) => index,
+            None => {
+                return Err(Box::new(SimpleError(format!(
+                    "{} is not an E*Trade transactions download",
+                    file_path
+                ))));
+            }
+        };
+        let headers = &rows[header_index];
+        for row in rows.iter().skip(header_index + 1) {
+            if row.iter().all(|cell| cell.is_empty()) {
+                continue;
+            }
+            let record: BrokerageRow = headers.iter().cloned().zip(row.iter().cloned()).collect();
+            debug!("{:?}", record);
+            self.brokerage.push(record);
+        }
+        Ok(())
+    }
     fn parse_xlsx_file(&mut self, file_path: &str) -> Result<(), calamine::Error> {
         let mut workbook: Xlsx<_> = open_workbook(file_path)?;
         let espp = workbook.worksheet_range("ESPP");
@@ -126,15 +233,8 @@ impl EtradeImporter {
         if espp.is_ok() {
             debug!("espp:");
             let espp = espp?;
-            let iter = RangeDeserializerBuilder::with_headers(&[
-                "Symbol",
-                "Purchase Date",
-                "Purchase Price",
-                "Purchased Qty.",
-                "Grant Date FMV",
-                "Purchase Date FMV",
-            ])
-            .from_range(&espp)?;
+            // Columns are matched by name, older downloads have no Grant Date
+            let iter = RangeDeserializerBuilder::new().from_range(&espp)?;
             for val in iter {
                 if val.is_err() {
                     continue;
@@ -211,11 +311,20 @@ impl EtradeImporter {
                 "Order Type",
             ])
             .from_range(&gl_expanded)?;
-            for val in iter {
+            let mut rows = gl_expanded.rows();
+            let headers: Vec<String> = match rows.next() {
+                Some(headers) => headers
+                    .iter()
+                    .map(|h| h.to_string().trim().to_string())
+                    .collect(),
+                None => vec![],
+            };
+            for (index, (val, row)) in iter.zip(rows).enumerate() {
                 if val.is_err() {
                     continue;
                 }
-                let record: GainAndLoss = val?;
+                let mut record: GainAndLoss = val?;
+                record.read_optional_columns(&headers, row, index);
                 debug!("{:?}", record);
                 self.gl_expanded.push(record);
             }
@@ -231,9 +340,9 @@ impl EtradeImporter {
             )));
         }
         for rsugrant in self.rsugrant.iter() {
-            let vest_total = rsugrant.total_vested.unwrap();
+            let vest_total = decimal_from_f64(rsugrant.total_vested.unwrap())?;
             let symbol = rsugrant.symbol.clone().unwrap();
-            let mut found_total = 0.0;
+            let mut found_total = Decimal::ZERO;
             let mut rsu_shares: Vec<Stock> = vec![];
             for rsugrantvest in self.rsugrantvest.iter() {
                 if rsugrant.grant_number == rsugrantvest.grant_number {
@@ -245,23 +354,27 @@ impl EtradeImporter {
                                 rsugrantvest.vest_date.clone().unwrap().as_str(),
                                 "%m/%d/%Y",
                             )?;
-                            let amount = rsugrantvest.release_qty.clone().unwrap();
-                            let price = rsutax.taxable_gain.clone().unwrap() / amount;
-                            found_total = found_total + amount;
-                            let grant_number = rsutax.grant_number.clone().unwrap();
-                            let vest_period = rsutax.vest_period.clone().unwrap();
+                            let amount = decimal_from_f64(rsugrantvest.release_qty.unwrap())?;
+                            let price = decimal_from_f64(rsutax.taxable_gain.unwrap())? / amount;
+                            found_total += amount;
+                            let grant_number = rsutax.grant_number.unwrap();
+                            let vest_period = rsutax.vest_period.unwrap();
                             info!(
                                 "RSU {} {:?} {:?} {:?} {}-{}",
                                 symbol, date, amount, price, grant_number, vest_period
                             );
                             let metadata_string = format!("RSU-{}-{}", grant_number, vest_period);
                             rsu_shares.push(Stock {
-                                date: date,
-                                price: price,
+                                date,
+                                price,
                                 currency: "USD".to_string(),
                                 unit: amount,
                                 action: Action::Buy,
                                 metadata: metadata_string,
+                                fee: Decimal::ZERO,
+                                id: format!("ETRADE-RSU-{}-{}", grant_number, vest_period),
+                                espp: None,
+                                lots: vec![],
                             });
                         }
                     }
@@ -272,18 +385,8 @@ impl EtradeImporter {
                     "Did not find the correct numbers of RSUs".into(),
                 )));
             }
-            let mut ticker_found = false;
-            for position in self.positions.iter_mut() {
-                if position.ticker == symbol {
-                    ticker_found = true;
-                    position.shares.append(&mut rsu_shares);
-                }
-            }
-            if !ticker_found {
-                self.positions.push(Positions {
-                    ticker: symbol,
-                    shares: rsu_shares,
-                });
+            for share in rsu_shares {
+                add_share(&mut self.positions, &symbol, share);
             }
         }
 
@@ -296,37 +399,112 @@ impl EtradeImporter {
                 espp_action.purchase_date.clone().unwrap().as_str(),
                 "%d-%b-%Y",
             )?;
-            let amount = espp_action.purchased_qty.unwrap();
+            let amount = decimal_from_f64(espp_action.purchased_qty.unwrap())?;
             let price_string = espp_action
                 .purchase_date_fmv
                 .clone()
                 .unwrap()
                 .replace("$", "");
-            let price: f64 = price_string.parse().unwrap();
+            let price: Decimal = price_string.parse()?;
 
             info!("ESPP {} {:?} {:?} {:?}", symbol, date, amount, price);
-            let metadata_string = format!("ESPP");
+            let espp = match (
+                &espp_action.grant_date,
+                espp_action.purchase_price,
+                espp_action.grant_date_fmv,
+            ) {
+                (Some(grant_date), Some(purchase_price), Some(grant_fmv)) => Some(EsppPurchase {
+                    grant_date: chrono::NaiveDate::parse_from_str(grant_date, "%d-%b-%Y")?,
+                    purchase_price: decimal_from_f64(purchase_price)?,
+                    grant_fmv: decimal_from_f64(grant_fmv)?,
+                    purchase_fmv: price,
+                }),
+                _ => None,
+            };
+            let metadata_string = "ESPP".to_string();
             let share = Stock {
-                date: date,
-                price: price,
+                date,
+                price,
                 currency: "USD".to_string(),
                 unit: amount,
                 action: Action::Buy,
                 metadata: metadata_string,
+                fee: Decimal::ZERO,
+                id: format!("ETRADE-ESPP-{}", date),
+                espp,
+                lots: vec![],
             };
-            let mut ticker_found = false;
-            for position in self.positions.iter_mut() {
-                if position.ticker == symbol {
-                    ticker_found = true;
-                    position.shares.push(share.clone());
+            add_share(&mut self.positions, &symbol, share);
+        }
+        Ok(())
+    }
+    fn process_brokerage(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        debug!("Proccesing brokerage transactions:");
+        for transaction in std::mem::take(&mut self.brokerage).iter() {
+            let activity = brokerage_column(transaction, BROKERAGE_TYPE)?;
+            let symbol = transaction.get("Symbol").map(|s| s.as_str()).unwrap_or("");
+            let date = parse_brokerage_date(brokerage_column(transaction, BROKERAGE_DATE)?)?;
+            let commission = transaction
+                .get("Commission")
+                .map(|c| c.as_str())
+                .unwrap_or("0");
+            let (action, metadata) = match activity {
+                "Bought" => (Action::Buy, format!("ETRADE-BROKERAGE {}", commission)),
+                "Sold" => (Action::Sell, format!("ETRADE-BROKERAGE {}", commission)),
+                _ if activity.contains("Reinvest") => {
+                    (Action::Buy, format!("ETRADE-REINVEST {}", commission))
                 }
-            }
-            if !ticker_found {
-                self.positions.push(Positions {
-                    ticker: symbol,
-                    shares: vec![share],
-                });
-            }
+                _ if activity.contains("Dividend") => {
+                    (Action::Dividend, format!("ETRADE-BROKERAGE {}", activity))
+                }
+                _ if activity.contains("Withholding") || activity.contains("Tax Withheld") => (
+                    Action::WithholdingTax,
+                    format!("ETRADE-BROKERAGE {}", activity),
+                ),
+                _ => {
+                    // Transferred shares keep their stock plan lot
+                    info!("{} {} {:?} not imported", activity, symbol, date);
+                    continue;
+                }
+            };
+            let (amount, price, fee) = if matches!(action, Action::Buy | Action::Sell) {
+                (
+                    parse_amount(brokerage_column(transaction, BROKERAGE_QUANTITY)?)?.abs(),
+                    parse_amount(brokerage_column(transaction, BROKERAGE_PRICE)?)?,
+                    parse_amount(commission).unwrap_or_default().abs(),
+                )
+            } else {
+                (
+                    Decimal::ONE,
+                    parse_amount(brokerage_column(transaction, BROKERAGE_AMOUNT)?)?.abs(),
+                    Decimal::ZERO,
+                )
+            };
+            info!(
+                "{} {} {:?} {:?} {:?}",
+                activity, symbol, date, amount, price
+            );
+            let share = Stock {
+                date,
+                price,
+                currency: "USD".to_string(),
+                unit: amount,
+                action,
+                metadata,
+                fee,
+                id: format!(
+                    "ETRADE-BROKERAGE-{}-{}-{}-{}",
+                    date,
+                    id_part(activity),
+                    symbol,
+                    parse_amount(brokerage_column(transaction, BROKERAGE_AMOUNT)?)?
+                        .abs()
+                        .normalize()
+                ),
+                espp: None,
+                lots: vec![],
+            };
+            add_share(&mut self.positions, symbol, share);
         }
         Ok(())
     }
@@ -337,70 +515,96 @@ impl EtradeImporter {
                 gl_action.date.clone().unwrap().as_str(),
                 "%m/%d/%Y",
             )?;
-            let amount = gl_action.num.unwrap();
-            let price: f64 = gl_action.price.unwrap();
+            let amount = decimal_from_f64(gl_action.num.unwrap())?;
+            let price = decimal_from_f64(gl_action.price.unwrap())?;
+            let mut fee = Decimal::ZERO;
+            for value in gl_action.fees.iter() {
+                fee += decimal_from_f64(*value)?.abs();
+            }
             let order_type = gl_action.order_type.clone().unwrap();
 
-            let metadata_string = format!("{}", order_type);
+            let metadata_string = order_type.to_string();
             let share = Stock {
-                date: date,
-                price: price,
+                date,
+                price,
                 currency: "USD".to_string(),
                 unit: amount,
                 action: Action::Sell,
                 metadata: metadata_string,
+                fee,
+                id: format!("ETRADE-SELL-{}", gl_action.key),
+                espp: None,
+                lots: vec![],
             };
-            let mut ticker_found = false;
-            for position in self.positions.iter_mut() {
-                if position.ticker == symbol {
-                    ticker_found = true;
-                    position.shares.push(share.clone());
-                }
-            }
-            if !ticker_found {
-                self.positions.push(Positions {
-                    ticker: symbol,
-                    shares: vec![share],
-                });
-            }
+            add_share(&mut self.positions, &symbol, share);
         }
         Ok(())
     }
 }
 
+fn brokerage_column<'a>(row: &'a BrokerageRow, keys: &[&str]) -> Result<&'a str, SimpleError> {
+    keys.iter()
+        .find_map(|key| row.get(*key))
+        .map(|v| v.as_str())
+        .ok_or_else(|| SimpleError(format!("Missing column {} in download", keys.join("/"))))
+}
+
+fn parse_brokerage_date(date: &str) -> Result<chrono::NaiveDate, chrono::ParseError> {
+    chrono::NaiveDate::parse_from_str(date, "%m/%d/%y")
+        .or_else(|_| chrono::NaiveDate::parse_from_str(date, "%m/%d/%Y"))
+}
+
 impl Importer for EtradeImporter {
     fn import(
         &mut self,
-        file_paths: &Vec<String>,
-    ) -> Result<&Vec<crate::Positions>, Box<dyn std::error::Error>> {
-        // Need BenefitHistory.xlsx and G&L_Expanded.xlsx
+        file_paths: &[String],
+    ) -> Result<Vec<crate::Positions>, Box<dyn std::error::Error>> {
+        // Need BenefitHistory.xlsx and G&L_Expanded.xlsx, brokerage transactions are csv
         for file in file_paths.iter() {
-            self.parse_xlsx_file(file)?;
+            if file.to_lowercase().ends_with(".csv") {
+                self.parse_brokerage_csv(file)?;
+            } else {
+                self.parse_xlsx_file(file)?;
+            }
         }
         self.process_rsu()?;
         self.process_espp()?;
         self.process_gl()?;
+        self.process_brokerage()?;
 
-        Ok(&self.positions)
+        Ok(std::mem::take(&mut self.positions))
+    }
+    fn detect(&self, file: &FileSniff) -> u8 {
+        let benefit_history = file.has_sheet("ESPP") || file.has_sheet("Restricted Stock");
+        let brokerage = BROKERAGE_TYPE.iter().any(|h| file.contains(h))
+            && BROKERAGE_DATE.iter().any(|h| file.contains(h));
+        if benefit_history || file.has_sheet("G&L_Expanded") {
+            100
+        } else if brokerage && file.extension == "csv" {
+            90
+        } else {
+            0
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::importer::ImporterService;
-    use crate::portfolio::{Portfolio, Positions};
+    use crate::portfolio::Portfolio;
+    use rust_decimal_macros::dec;
     use std::io::Read;
 
     use super::*;
 
-    fn init() -> Result<Portfolio, std::io::Error> {
+    fn init(portfolio_file: &str) -> Result<Portfolio, std::io::Error> {
         let _ = env_logger::builder()
             .is_test(true)
             .filter_level(log::LevelFilter::Trace)
             .try_init();
         let test_portfolio = format!(
-            "{}/test_files/test_portfolio_etrade.json",
-            env!("CARGO_MANIFEST_DIR")
+            "{}/test_files/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            portfolio_file
         );
         let mut file = std::fs::File::open(test_portfolio)?;
         let mut porfolio_data = String::new();
@@ -411,10 +615,8 @@ mod tests {
 
     #[test]
     fn test_etrade_importer() {
-        let test_portfolio = init().unwrap();
-        let etrade = EtradeImporter::new();
-        let mut importer =
-            ImporterService::new_importer(etrade).expect("Creating Etrade importer failed");
+        let test_portfolio = init("test_portfolio_etrade.json").unwrap();
+        let mut etrade = EtradeImporter::new();
         let benifit_history = format!(
             "{}/test_files/G&L_Expanded.xlsx",
             env!("CARGO_MANIFEST_DIR")
@@ -423,8 +625,111 @@ mod tests {
             "{}/test_files/BenefitHistory.xlsx",
             env!("CARGO_MANIFEST_DIR")
         );
-        let res = importer.run(&vec![benifit_history, gl_expanded]).unwrap();
+        let res = etrade.import(&[benifit_history, gl_expanded]).unwrap();
+        debug!("{:?}", res);
+        assert_eq!(res, test_portfolio.stocks);
+    }
+
+    #[test]
+    fn test_etrade_gl_fees_and_ids() {
+        let headers: Vec<String> = [
+            "Record Type",
+            "Symbol",
+            "Qty.",
+            "Date Sold",
+            "Proceeds Per Share",
+            "Order Type",
+            "Commission",
+            "Other Fees",
+            "Order Number",
+            "Grant Number",
+        ]
+        .iter()
+        .map(|h| h.to_string())
+        .collect();
+        let row = |order: &str| -> Vec<Data> {
+            vec![
+                Data::String("Sell".to_string()),
+                Data::String("ACME".to_string()),
+                Data::Float(10.0),
+                Data::String("03/15/2023".to_string()),
+                Data::Float(150.25),
+                Data::String("Sell Restricted Stock".to_string()),
+                Data::Float(4.95),
+                Data::Float(-0.03),
+                Data::String(order.to_string()),
+                Data::String("1001".to_string()),
+            ]
+        };
+        let mut etrade = EtradeImporter::new();
+        for (index, order) in ["9001", "", "9001"].iter().enumerate() {
+            let mut record = GainAndLoss {
+                event: Some("Sell".to_string()),
+                symbol: Some("ACME".to_string()),
+                num: Some(10.0),
+                date: Some("03/15/2023".to_string()),
+                price: Some(150.25),
+                order_type: Some("Sell Restricted Stock".to_string()),
+                fees: vec![],
+                key: String::new(),
+            };
+            record.read_optional_columns(&headers, &row(order), index);
+            etrade.gl_expanded.push(record);
+        }
+        etrade.process_gl().unwrap();
+        // The same row listed twice is imported once, rows without an order number use the
+        // grant number
+        let sales: Vec<(&str, Decimal)> = etrade.positions[0]
+            .shares
+            .iter()
+            .map(|s| (s.id.as_str(), s.fee))
+            .collect();
+        assert_eq!(
+            sales,
+            vec![
+                ("ETRADE-SELL-9001-1001", dec!(4.98)),
+                ("ETRADE-SELL-1001", dec!(4.98))
+            ]
+        );
+
+        // Without key columns the row number identifies the sale
+        let mut record = etrade.gl_expanded.remove(0);
+        record.read_optional_columns(&headers[..6], &row("9001"), 4);
+        assert_eq!((record.fees, record.key), (vec![], "ROW6".to_string()));
+    }
+
+    #[test]
+    fn test_etrade_espp_price() {
+        let record = |purchase_fmv: &str| EsppRecord {
+            symbol: Some("ACME".to_string()),
+            purchase_date: Some("30-Jun-2023".to_string()),
+            purchase_price: None,
+            purchased_qty: Some(10.0),
+            grant_date: None,
+            grant_date_fmv: None,
+            purchase_date_fmv: Some(purchase_fmv.to_string()),
+        };
+        let mut etrade = EtradeImporter::new();
+        etrade.espp.push(record("$150.25"));
+        etrade.process_espp().unwrap();
+        assert_eq!(etrade.positions[0].shares[0].price, dec!(150.25));
+
+        // A malformed price fails the import instead of panicking
+        let mut etrade = EtradeImporter::new();
+        etrade.espp.push(record("N/A"));
+        assert!(etrade.process_espp().is_err());
+    }
+
+    #[test]
+    fn test_etrade_brokerage_importer() {
+        let test_portfolio = init("test_portfolio_etrade_brokerage.json").unwrap();
+        let mut etrade = EtradeImporter::new();
+        let transactions = format!(
+            "{}/test_files/DownloadTxnHistory.csv",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let res = etrade.import(&[transactions]).unwrap();
         debug!("{:?}", res);
-        assert_eq!(res, &test_portfolio.stocks);
+        assert_eq!(res, test_portfolio.stocks);
     }
 }
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+
+use log::{debug, info};
+use rust_decimal::Decimal;
+
+use crate::portfolio::{Action, EsppPurchase, Stock};
+
+use super::{FileSniff, Importer, SimpleError, add_share, parse_amount, read_csv_rows};
+
+/// One row of an export, keyed by column header.
+type ExportRow = HashMap<String, String>;
+
+/// Importer for the Fidelity NetBenefits stock plan exports.
+///
+/// The "Transaction history" export provides RSU releases and sales. Its ESPP purchases
+/// only show the discounted purchase price, so ESPP lots are read from a "Custom report"
+/// with the `Purchase Date FMV` and `Offering Period FMV` columns instead.
+///
+/// Lots follow the conventions of the `EtradeImporter`: RSU releases are stored at the
+/// release price with metadata `RSU-<grant id>-<release date>`, ESPP purchases at the
+/// purchase date fair market value with metadata `ESPP` and sales at the sale price with
+/// metadata `Sale`, the `Commission` and `Fees` columns as fee and the id
+/// `FIDELITY-SALE-<date>-<quantity>-<price>`.
+pub struct FidelityImporter {
+    positions: Vec<crate::Positions>,
+    history: Vec<ExportRow>,
+    espp: Vec<ExportRow>,
+}
+
+impl FidelityImporter {
+    pub fn new() -> Self {
+        FidelityImporter {
+            positions: vec![],
+            history: vec![],
+            espp: vec![],
+        }
+    }
+    fn parse_csv_file(&mut self, file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let rows = read_csv_rows(file_path)?;
+        // Exports start with the plan name and end with a disclaimer around the table
+        let header_index = rows.iter().position(|row| {
+            row.iter()
+                .any(|cell| cell == "Transaction Type" || cell == "Purchase Date FMV")
+        });
+        let header_index = match header_index {
+            Some(index) => index,
+            None => {
+                return Err(Box::new(SimpleError(format!(
+                    "{} is not a Transaction history or Custom report export",
+                    file_path
+                ))));
+            }
+        };
+        let headers = &rows[header_index];
+        let is_history = headers.iter().any(|h| h == "Transaction Type");
+        for row in rows.iter().skip(header_index + 1) {
+            if row.len() < headers.len() {
+                continue;
+            }
+            let record: ExportRow = headers.iter().cloned().zip(row.iter().cloned()).collect();
+            debug!("{:?}", record);
+            if is_history {
+                self.history.push(record);
+            } else {
+                self.espp.push(record);
+            }
+        }
+        Ok(())
+    }
+    fn process_history(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        debug!("Proccesing transaction history:");
+        for transaction in std::mem::take(&mut self.history).iter() {
+            let transaction_type = column(transaction, "Transaction Type")?;
+            let (action, metadata) = match transaction_type {
+                "RSU Release" | "Restricted Stock Release" => (Action::Buy, None),
+                "Sale" | "Sell" => (Action::Sell, Some("Sale".to_string())),
+                _ => {
+                    debug!("Skipping {:?}", transaction);
+                    continue;
+                }
+            };
+            let symbol = column(transaction, "Symbol")?;
+            let date = parse_date(column(transaction, "Date")?)?;
+            let amount = parse_amount(column(transaction, "Quantity")?)?.abs();
+            let price = parse_amount(column(transaction, "Price")?)?;
+            let metadata = match metadata {
+                Some(metadata) => metadata,
+                None => format!(
+                    "RSU-{}-{}",
+                    transaction
+                        .get("Grant ID")
+                        .map(|g| g.as_str())
+                        .unwrap_or(""),
+                    date.format("%Y%m%d")
+                ),
+            };
+            let mut fee = Decimal::ZERO;
+            for cost in ["Commission", "Fees"] {
+                if let Some(value) = transaction.get(cost).filter(|v| !v.is_empty()) {
+                    fee += parse_amount(value)?.abs();
+                }
+            }
+            info!(
+                "{} {} {:?} {:?} {:?} fee {:?}",
+                transaction_type, symbol, date, amount, price, fee
+            );
+            let id = match action {
+                Action::Sell => format!("FIDELITY-SALE-{}-{}-{}", date, amount.normalize(), price),
+                _ => format!("FIDELITY-{}", metadata),
+            };
+            let share = Stock {
+                date,
+                price,
+                currency: "USD".to_string(),
+                unit: amount,
+                action,
+                id,
+                metadata,
+                fee,
+                espp: None,
+                lots: vec![],
+            };
+            add_share(&mut self.positions, symbol, share);
+        }
+        Ok(())
+    }
+    fn process_espp(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        debug!("Proccesing ESPP purchases:");
+        for purchase in std::mem::take(&mut self.espp).iter() {
+            if purchase.get("Plan Type").is_some_and(|plan| plan != "ESPP") {
+                continue;
+            }
+            let symbol = column(purchase, "Symbol")?;
+            let date = parse_date(column(purchase, "Purchase Date")?)?;
+            let amount = parse_amount(column(purchase, "Shares Purchased")?)?;
+            let price = parse_amount(column(purchase, "Purchase Date FMV")?)?;
+            let offering_fmv = parse_amount(column(purchase, "Offering Period FMV")?)?;
+            let espp = EsppPurchase {
+                grant_date: parse_date(column(purchase, "Offering Period Start")?)?,
+                purchase_price: parse_amount(column(purchase, "Purchase Price")?)?,
+                grant_fmv: offering_fmv,
+                purchase_fmv: price,
+            };
+            info!(
+                "ESPP {} {:?} {:?} {:?} offering period FMV {:?}",
+                symbol, date, amount, price, offering_fmv
+            );
+            let share = Stock {
+                date,
+                price,
+                currency: "USD".to_string(),
+                unit: amount,
+                action: Action::Buy,
+                metadata: "ESPP".to_string(),
+                fee: Decimal::ZERO,
+                id: format!("FIDELITY-ESPP-{}", date),
+                espp: Some(espp),
+                lots: vec![],
+            };
+            add_share(&mut self.positions, symbol, share);
+        }
+        Ok(())
+    }
+}
+
+fn column<'a>(row: &'a ExportRow, key: &str) -> Result<&'a str, SimpleError> {
+    row.get(key)
+        .map(|v| v.as_str())
+        .ok_or_else(|| SimpleError(format!("Missing column {} in export", key)))
+}
+
+fn parse_date(date: &str) -> Result<chrono::NaiveDate, chrono::ParseError> {
+    chrono::NaiveDate::parse_from_str(date, "%m/%d/%Y")
+        .or_else(|_| chrono::NaiveDate::parse_from_str(date, "%b-%d-%Y"))
+}
+
+impl Importer for FidelityImporter {
+    fn import(
+        &mut self,
+        file_paths: &[String],
+    ) -> Result<Vec<crate::Positions>, Box<dyn std::error::Error>> {
+        // Needs the Transaction history and a Custom report with the ESPP purchases
+        for file in file_paths.iter() {
+            self.parse_csv_file(file)?;
+        }
+        self.process_history()?;
+        self.process_espp()?;
+
+        Ok(std::mem::take(&mut self.positions))
+    }
+    fn detect(&self, file: &FileSniff) -> u8 {
+        let history = file.contains("Transaction Type") && file.contains("Grant ID");
+        if history || file.contains("Purchase Date FMV") {
+            90
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::portfolio::Portfolio;
+    use std::io::Read;
+
+    use super::*;
+
+    fn init() -> Result<Portfolio, std::io::Error> {
+        let _ = env_logger::builder()
+            .is_test(true)
+            .filter_level(log::LevelFilter::Trace)
+            .try_init();
+        let test_portfolio = format!(
+            "{}/test_files/test_portfolio_fidelity.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let mut file = std::fs::File::open(test_portfolio)?;
+        let mut porfolio_data = String::new();
+        file.read_to_string(&mut porfolio_data)?;
+        let port: Portfolio = serde_json::from_str(porfolio_data.as_str())?;
+        Ok(port)
+    }
+
+    #[test]
+    fn test_fidelity_importer() {
+        let test_portfolio = init().unwrap();
+        let mut fidelity = FidelityImporter::new();
+        let history = format!(
+            "{}/test_files/NetBenefits_Transaction_history.csv",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let custom_report = format!(
+            "{}/test_files/NetBenefits_Custom_report.csv",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let res = fidelity.import(&[history, custom_report]).unwrap();
+        debug!("{:?}", res);
+        assert_eq!(res, test_portfolio.stocks);
+    }
+}
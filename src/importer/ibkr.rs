@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+
+use log::{debug, info};
+use quick_xml::Reader;
+use quick_xml::events::Event;
+use rust_decimal::Decimal;
+
+use crate::money::round_money;
+use crate::portfolio::{Action, CorporateAction, CorporateActionKind, Stock};
+
+use super::{FileSniff, Importer, SimpleError, add_action, add_share, id_part};
+
+/// Attributes of one `Trade`, `CashTransaction` or `CorporateAction` element.
+type FlexRecord = HashMap<String, String>;
+
+/// Importer for Interactive Brokers Flex Query statements in XML format.
+///
+/// Stock executions in the `Trades` section become buys and sells in their trade
+/// currency with the commission as fee, a commission charged in the base currency of the
+/// account is converted with `fxRateToBase`. The IBKR trade ID and the commission are kept
+/// in the metadata as `IBKR-<trade id> <commission> <commission currency>`, which makes
+/// every trade unique so re-importing overlapping statements does not add trades twice.
+///
+/// Dividends and withholding tax of the `CashTransactions` section are stored with the whole
+/// payment as price and the description as metadata. Forward and reverse splits of the
+/// `CorporateActions` section become stock splits with metadata `IBKR-<action id>`, other
+/// corporate actions are only logged.
+pub struct IbkrImporter {
+    positions: Vec<crate::Positions>,
+    trades: Vec<FlexRecord>,
+    cash_transactions: Vec<FlexRecord>,
+    corporate_actions: Vec<FlexRecord>,
+}
+
+impl IbkrImporter {
+    pub fn new() -> Self {
+        IbkrImporter {
+            positions: vec![],
+            trades: vec![],
+            cash_transactions: vec![],
+            corporate_actions: vec![],
+        }
+    }
+    fn parse_xml_file(&mut self, file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let data = std::fs::read_to_string(file_path)?;
+        let mut reader = Reader::from_str(data.as_str());
+        let mut found_statement = false;
+        loop {
+            match reader.read_event()? {
+                Event::Start(element) | Event::Empty(element) => {
+                    let name = String::from_utf8_lossy(element.name().as_ref()).to_string();
+                    let target = match name.as_str() {
+                        "FlexStatement" => {
+                            found_statement = true;
+                            continue;
+                        }
+                        "Trade" => &mut self.trades,
+                        "CashTransaction" => &mut self.cash_transactions,
+                        "CorporateAction" => &mut self.corporate_actions,
+                        _ => continue,
+                    };
+                    let mut record = FlexRecord::new();
+                    for attribute in element.attributes() {
+                        let attribute = attribute?;
+                        record.insert(
+                            String::from_utf8_lossy(attribute.key.as_ref()).to_string(),
+                            attribute.decode_and_unescape_value(&reader)?.to_string(),
+                        );
+                    }
+                    debug!("{} {:?}", name, record);
+                    target.push(record);
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        if !found_statement {
+            return Err(Box::new(SimpleError(format!(
+                "{} is not a Flex Query statement",
+                file_path
+            ))));
+        }
+        Ok(())
+    }
+    fn process_trades(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        debug!("Proccesing trades:");
+        for trade in self.trades.iter() {
+            if field(trade, "assetCategory").unwrap_or("STK") != "STK" {
+                debug!("Skipping non stock trade {:?}", trade);
+                continue;
+            }
+            // Statements with lot details repeat every execution as closed lots
+            if !matches!(
+                field(trade, "levelOfDetail").unwrap_or("EXECUTION"),
+                "EXECUTION" | "ORDER"
+            ) {
+                continue;
+            }
+            let symbol = require(trade, "symbol")?;
+            let date = parse_date(require(trade, "tradeDate")?)?;
+            let quantity: Decimal = require(trade, "quantity")?.parse()?;
+            let price: Decimal = require(trade, "tradePrice")?.parse()?;
+            let currency = require(trade, "currency")?;
+            let action = match field(trade, "buySell") {
+                Some(buy_sell) if buy_sell.starts_with("BUY") => Action::Buy,
+                Some(buy_sell) if buy_sell.starts_with("SELL") => Action::Sell,
+                _ if quantity.is_sign_negative() => Action::Sell,
+                _ => Action::Buy,
+            };
+            let commission: Decimal = field(trade, "ibCommission").unwrap_or("0").parse()?;
+            let fee = match field(trade, "ibCommissionCurrency") {
+                Some(commission_currency) if commission_currency != currency => {
+                    let rate: Decimal = require(trade, "fxRateToBase")?.parse()?;
+                    if rate.is_zero() {
+                        return Err(Box::new(SimpleError(format!(
+                            "Trade {} has no exchange rate for its commission",
+                            require(trade, "tradeID")?
+                        ))));
+                    }
+                    round_money(commission.abs() / rate, currency)
+                }
+                _ => commission.abs(),
+            };
+            let metadata = format!(
+                "IBKR-{} {} {}",
+                require(trade, "tradeID")?,
+                field(trade, "ibCommission").unwrap_or("0"),
+                field(trade, "ibCommissionCurrency").unwrap_or(currency)
+            );
+            info!(
+                "{:?} {} {:?} {:?} {:?} {}",
+                action, symbol, date, quantity, price, currency
+            );
+            let share = Stock {
+                date,
+                price,
+                currency: currency.to_string(),
+                unit: quantity.abs(),
+                action,
+                metadata,
+                fee,
+                id: format!("IBKR-{}", require(trade, "tradeID")?),
+                espp: None,
+                lots: vec![],
+            };
+            add_share(&mut self.positions, symbol, share);
+        }
+        Ok(())
+    }
+    fn process_cash_transactions(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        debug!("Proccesing cash transactions:");
+        for cash_transaction in self.cash_transactions.iter() {
+            let cash_type = field(cash_transaction, "type").unwrap_or("");
+            let action = match cash_type {
+                "Dividends" | "Payment In Lieu Of Dividends" => Action::Dividend,
+                "Withholding Tax" => Action::WithholdingTax,
+                _ => {
+                    info!(
+                        "Cash transaction {} {} {} not imported",
+                        cash_type,
+                        field(cash_transaction, "symbol").unwrap_or(""),
+                        field(cash_transaction, "amount").unwrap_or("")
+                    );
+                    continue;
+                }
+            };
+            let symbol = require(cash_transaction, "symbol")?;
+            let date = match field(cash_transaction, "dateTime") {
+                Some(date_time) => parse_date(date_time)?,
+                None => parse_date(require(cash_transaction, "reportDate")?)?,
+            };
+            let amount: Decimal = require(cash_transaction, "amount")?.parse()?;
+            let currency = require(cash_transaction, "currency")?;
+            info!(
+                "{} {} {:?} {:?} {}",
+                cash_type, symbol, date, amount, currency
+            );
+            let share = Stock {
+                date,
+                price: amount.abs(),
+                currency: currency.to_string(),
+                unit: Decimal::ONE,
+                action,
+                metadata: field(cash_transaction, "description")
+                    .unwrap_or(cash_type)
+                    .to_string(),
+                fee: Decimal::ZERO,
+                id: match field(cash_transaction, "transactionID") {
+                    Some(transaction_id) => format!("IBKR-{}", transaction_id),
+                    None => format!("IBKR-{}-{}-{}", symbol, date, id_part(cash_type)),
+                },
+                espp: None,
+                lots: vec![],
+            };
+            add_share(&mut self.positions, symbol, share);
+        }
+        Ok(())
+    }
+    fn process_corporate_actions(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        debug!("Proccesing corporate actions:");
+        for corporate_action in self.corporate_actions.iter() {
+            let action_type = field(corporate_action, "type").unwrap_or("");
+            let description = field(corporate_action, "description").unwrap_or("");
+            // Forward and reverse splits, other actions can not be represented yet
+            let ratio = match action_type {
+                "FS" | "RS" => parse_split(description),
+                _ => None,
+            };
+            let (numerator, denominator) = match ratio {
+                Some(ratio) => ratio,
+                None => {
+                    info!(
+                        "Corporate action {} {} not imported",
+                        action_type, description
+                    );
+                    continue;
+                }
+            };
+            let symbol = require(corporate_action, "symbol")?;
+            let date = match field(corporate_action, "dateTime") {
+                Some(date_time) => parse_date(date_time)?,
+                None => parse_date(require(corporate_action, "reportDate")?)?,
+            };
+            info!(
+                "Split {} {:?} {} for {}",
+                symbol, date, numerator, denominator
+            );
+            let action = CorporateAction {
+                date,
+                kind: CorporateActionKind::Split {
+                    numerator,
+                    denominator,
+                },
+                metadata: format!(
+                    "IBKR-{}",
+                    field(corporate_action, "actionID").unwrap_or(action_type)
+                ),
+            };
+            add_action(&mut self.positions, symbol, action);
+        }
+        Ok(())
+    }
+}
+
+/// Reads the ratio of descriptions like `AAPL(US0378331005) SPLIT 4 FOR 1 (...)`.
+fn parse_split(description: &str) -> Option<(Decimal, Decimal)> {
+    let mut words = description.split_whitespace();
+    words.find(|w| *w == "SPLIT")?;
+    let numerator = words.next()?.parse().ok()?;
+    if words.next()? != "FOR" {
+        return None;
+    }
+    let denominator = words.next()?.parse().ok()?;
+    Some((numerator, denominator))
+}
+
+fn field<'a>(record: &'a FlexRecord, key: &str) -> Option<&'a str> {
+    record
+        .get(key)
+        .map(|v| v.as_str())
+        .filter(|v| !v.is_empty())
+}
+
+fn require<'a>(record: &'a FlexRecord, key: &str) -> Result<&'a str, SimpleError> {
+    field(record, key).ok_or_else(|| SimpleError(format!("Missing attribute {} in statement", key)))
+}
+
+/// Parses the date formats selectable in the Flex Query settings, dates with a time like
+/// `20240115;093000` are cut at the separator.
+fn parse_date(date: &str) -> Result<chrono::NaiveDate, chrono::ParseError> {
+    let date = date.split([';', ',', ' ']).next().unwrap_or(date);
+    chrono::NaiveDate::parse_from_str(date, "%Y%m%d")
+        .or_else(|_| chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d"))
+        .or_else(|_| chrono::NaiveDate::parse_from_str(date, "%m/%d/%Y"))
+}
+
+impl Importer for IbkrImporter {
+    fn import(
+        &mut self,
+        file_paths: &[String],
+    ) -> Result<Vec<crate::Positions>, Box<dyn std::error::Error>> {
+        // Needs Flex Query statements in XML format
+        for file in file_paths.iter() {
+            self.parse_xml_file(file)?;
+        }
+        self.process_trades()?;
+        self.process_cash_transactions()?;
+        self.process_corporate_actions()?;
+
+        Ok(std::mem::take(&mut self.positions))
+    }
+    fn detect(&self, file: &FileSniff) -> u8 {
+        if file.contains("<FlexQueryResponse") || file.contains("<FlexStatement") {
+            100
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::portfolio::Portfolio;
+    use std::io::Read;
+
+    use super::*;
+
+    fn init() -> Result<Portfolio, std::io::Error> {
+        let _ = env_logger::builder()
+            .is_test(true)
+            .filter_level(log::LevelFilter::Trace)
+            .try_init();
+        let test_portfolio = format!(
+            "{}/test_files/test_portfolio_ibkr.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let mut file = std::fs::File::open(test_portfolio)?;
+        let mut porfolio_data = String::new();
+        file.read_to_string(&mut porfolio_data)?;
+        let port: Portfolio = serde_json::from_str(porfolio_data.as_str())?;
+        Ok(port)
+    }
+
+    #[test]
+    fn test_ibkr_importer() {
+        let test_portfolio = init().unwrap();
+        let mut ibkr = IbkrImporter::new();
+        let statement = format!("{}/test_files/ibkr_flex.xml", env!("CARGO_MANIFEST_DIR"));
+        let res = ibkr.import(&[statement]).unwrap();
+        debug!("{:?}", res);
+        assert_eq!(res, test_portfolio.stocks);
+    }
+
+    #[test]
+    fn test_ibkr_overlapping_statements() {
+        let test_portfolio = init().unwrap();
+        let mut portfolio = Portfolio::new().unwrap();
+        let statement = format!("{}/test_files/ibkr_flex.xml", env!("CARGO_MANIFEST_DIR"));
+        for _ in 0..2 {
+            let mut ibkr = IbkrImporter::new();
+            let res = ibkr.import(std::slice::from_ref(&statement)).unwrap();
+            portfolio.merge_postions(&res).unwrap();
+        }
+        assert_eq!(portfolio.stocks, test_portfolio.stocks);
+        // The same statement passed twice in one import adds its lots once
+        let mut ibkr = IbkrImporter::new();
+        let res = ibkr.import(&[statement.clone(), statement]).unwrap();
+        assert_eq!(res, test_portfolio.stocks);
+    }
+}
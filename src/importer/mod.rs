@@ -1,30 +1,161 @@
 mod csv;
+mod detect;
 mod etrade;
+mod fidelity;
+mod ibkr;
+mod morgan_stanley;
+mod nordnet;
+mod ofx;
+mod registry;
+mod schwab;
 
-use log::{debug, info};
+use crate::portfolio::{CorporateAction, Positions, Stock, is_id_separator};
+use log::debug;
+use rust_decimal::Decimal;
 
-pub use crate::importer::csv::CsvImporter;
+pub use crate::importer::csv::{CsvImporter, CsvMapping};
+pub use crate::importer::detect::{FileSniff, collect_files, route_files};
 pub use crate::importer::etrade::EtradeImporter;
+pub use crate::importer::fidelity::FidelityImporter;
+pub use crate::importer::ibkr::IbkrImporter;
+pub use crate::importer::morgan_stanley::MorganStanleyImporter;
+pub use crate::importer::nordnet::NordnetImporter;
+pub use crate::importer::ofx::OfxImporter;
+pub use crate::importer::registry::{ImporterOptions, ImporterRegistry};
+pub use crate::importer::schwab::SchwabImporter;
+
+#[derive(Debug)]
+struct SimpleError(String);
+
+impl std::fmt::Display for SimpleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SimpleError {}
 
 pub trait Importer {
     fn import(
         &mut self,
-        file_paths: &Vec<String>,
-    ) -> Result<&Vec<crate::Positions>, Box<dyn std::error::Error>>;
+        file_paths: &[String],
+    ) -> Result<Vec<crate::Positions>, Box<dyn std::error::Error>>;
+    /// Scores how sure the importer is that it can read `file`, from 0 for a file it does
+    /// not recognise to 100 for a file with an unmistakable signature.
+    fn detect(&self, _file: &FileSniff) -> u8 {
+        0
+    }
 }
 
-pub struct ImporterService<I: Importer> {
-    importer: I,
+/// Adds a share to the position of `ticker`, creating the position if it is not there yet.
+/// A share equal to one already added, e.g. from overlapping statements or the same file
+/// passed twice, is skipped. Source keys are not always unique, so a different share with an
+/// id already used in the import gets the suffix `-2`, `-3`... in the order of the files.
+fn add_share(positions: &mut Vec<Positions>, ticker: &str, share: Stock) {
+    add_account_share(positions, "", ticker, share)
 }
 
-impl<I: Importer> ImporterService<I> {
-    pub fn new_importer(imp: I) -> Result<Self, Box<dyn std::error::Error>> {
-        Ok(ImporterService { importer: imp })
+/// Like [`add_share`] for files listing the account of every trade.
+fn add_account_share(
+    positions: &mut Vec<Positions>,
+    account: &str,
+    ticker: &str,
+    mut share: Stock,
+) {
+    let position = positions
+        .iter()
+        .position(|p| p.account == account && p.ticker == ticker);
+    if position.is_some_and(|index| positions[index].shares.contains(&share)) {
+        debug!("Skipping share imported twice {:?}", share);
+        return;
     }
-    pub fn run(
-        &mut self,
-        file_paths: &Vec<String>,
-    ) -> Result<&Vec<crate::Positions>, Box<dyn std::error::Error>> {
-        self.importer.import(file_paths)
+    if !share.id.is_empty() {
+        let used = |id: &str| positions.iter().flat_map(|p| &p.shares).any(|s| s.id == id);
+        let base = share.id.clone();
+        let mut count = 1;
+        while used(&share.id) {
+            count += 1;
+            share.id = format!("{}-{}", base, count);
+        }
+    }
+    match position {
+        Some(index) => positions[index].shares.push(share),
+        None => positions.push(Positions {
+            ticker: ticker.to_string(),
+            account: account.to_string(),
+            shares: vec![share],
+            actions: vec![],
+        }),
+    }
+}
+
+/// Adds a corporate action to the position of `ticker`, creating the position if it is not
+/// there yet. Statements often list an action once for every affected lot, an action equal
+/// to one already added is skipped.
+fn add_action(positions: &mut Vec<Positions>, ticker: &str, action: CorporateAction) {
+    match positions.iter_mut().find(|p| p.ticker == ticker) {
+        Some(position) => {
+            if !position.actions.contains(&action) {
+                position.actions.push(action)
+            }
+        }
+        None => positions.push(Positions {
+            ticker: ticker.to_string(),
+            account: String::new(),
+            shares: vec![],
+            actions: vec![action],
+        }),
+    }
+}
+
+/// `text` made usable as part of a lot id, spaces, commas and semicolons become `-`.
+fn id_part(text: &str) -> String {
+    text.split(is_id_separator)
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Parses amounts like `$1,234.56`.
+fn parse_amount(amount: &str) -> Result<Decimal, rust_decimal::Error> {
+    amount.replace(['$', ','], "").parse()
+}
+
+/// Converts a number read from a spreadsheet cell, keeping the digits shown in the cell
+/// instead of the binary value of the float.
+fn decimal_from_f64(value: f64) -> Result<Decimal, rust_decimal::Error> {
+    value.to_string().parse()
+}
+
+/// Reads all rows of a csv file without treating the first row as header, for broker
+/// reports that start with a few lines of account information before the table.
+fn read_csv_rows(file_path: &str) -> Result<Vec<Vec<String>>, Box<dyn std::error::Error>> {
+    let mut reader = ::csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(::csv::Trim::All)
+        .from_path(file_path)?;
+    let mut rows = vec![];
+    for val in reader.records() {
+        rows.push(val?.iter().map(|v| v.to_string()).collect());
+    }
+    Ok(rows)
+}
+
+/// Decodes text files written as UTF-16 with byte order mark, like the Nordnet exports, or
+/// as UTF-8. Invalid characters are replaced.
+fn decode_text(data: &[u8]) -> String {
+    let utf16 = |bytes: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        let units: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|c| from_bytes([c[0], c[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    };
+    match data {
+        [0xFF, 0xFE, rest @ ..] => utf16(rest, u16::from_le_bytes),
+        [0xFE, 0xFF, rest @ ..] => utf16(rest, u16::from_be_bytes),
+        [0xEF, 0xBB, 0xBF, rest @ ..] => String::from_utf8_lossy(rest).to_string(),
+        _ => String::from_utf8_lossy(data).to_string(),
     }
 }
@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+
+use calamine::{Data, Reader, Xlsx, open_workbook};
+use log::{debug, info};
+use rust_decimal::Decimal;
+
+use crate::portfolio::{Action, Stock};
+
+use super::{FileSniff, Importer, SimpleError, add_share, parse_amount, read_csv_rows};
+
+/// One row of a report, keyed by column header.
+type ReportRow = HashMap<String, String>;
+
+/// Importer for the "Releases" and "Withdrawals" reports of Morgan Stanley at Work
+/// (formerly StockPlan Connect), which replaced the E*Trade stock plan accounts.
+///
+/// Reports can be passed as xlsx or csv, the kind of report is detected from its columns.
+/// Completed releases become RSU lots at the release price with metadata
+/// `RSU-MS-<order number>`. When the taxes were paid by withholding shares only the net
+/// shares are stored, otherwise the full released quantity is stored and the sell to cover
+/// shows up as a withdrawal. Completed sale withdrawals become sells with the withdrawal
+/// type as metadata, like the order type of the `EtradeImporter`, and the difference between
+/// the proceeds and the `Net Amount` as fee.
+pub struct MorganStanleyImporter {
+    positions: Vec<crate::Positions>,
+    ticker: Option<String>,
+    releases: Vec<ReportRow>,
+    withdrawals: Vec<ReportRow>,
+}
+
+impl MorganStanleyImporter {
+    /// `ticker` is used for reports without a `Symbol` column.
+    pub fn new(ticker: Option<String>) -> Self {
+        MorganStanleyImporter {
+            positions: vec![],
+            ticker,
+            releases: vec![],
+            withdrawals: vec![],
+        }
+    }
+    fn parse_xlsx_file(file_path: &str) -> Result<Vec<Vec<String>>, Box<dyn std::error::Error>> {
+        let mut workbook: Xlsx<_> = open_workbook(file_path)?;
+        let range = match workbook.worksheet_range_at(0) {
+            Some(range) => range?,
+            None => {
+                return Err(Box::new(SimpleError(format!(
+                    "{} has no sheets",
+                    file_path
+                ))));
+            }
+        };
+        let rows = range
+            .rows()
+            .map(|row| {
+                row.iter()
+                    .map(|cell| match cell {
+                        Data::DateTime(date) => date
+                            .as_datetime()
+                            .map(|d| d.format("%m/%d/%Y").to_string())
+                            .unwrap_or_default(),
+                        _ => cell.to_string().trim().to_string(),
+                    })
+                    .collect()
+            })
+            .collect();
+        Ok(rows)
+    }
+    fn parse_report(&mut self, file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let rows = if file_path.to_lowercase().ends_with(".csv") {
+            read_csv_rows(file_path)?
+        } else {
+            Self::parse_xlsx_file(file_path)?
+        };
+        // Reports start with a few lines describing the account before the table itself
+        let header_index = rows.iter().position(|row| {
+            row.iter()
+                .any(|cell| cell == "Vest Date" || cell == "Execution Date")
+        });
+        let header_index = match header_index {
+            Some(index) => index,
+            None => {
+                return Err(Box::new(SimpleError(format!(
+                    "{} is not a Releases or Withdrawals report",
+                    file_path
+                ))));
+            }
+        };
+        let headers = &rows[header_index];
+        let is_release = headers.iter().any(|h| h == "Vest Date");
+        for row in rows.iter().skip(header_index + 1) {
+            if row.iter().all(|cell| cell.is_empty()) {
+                continue;
+            }
+            let record: ReportRow = headers.iter().cloned().zip(row.iter().cloned()).collect();
+            debug!("{:?}", record);
+            if is_release {
+                self.releases.push(record);
+            } else {
+                self.withdrawals.push(record);
+            }
+        }
+        Ok(())
+    }
+    fn symbol(&self, row: &ReportRow) -> Result<String, SimpleError> {
+        match row.get("Symbol").filter(|s| !s.is_empty()) {
+            Some(symbol) => Ok(symbol.clone()),
+            None => self.ticker.clone().ok_or_else(|| {
+                SimpleError("Report has no Symbol column and no ticker was given".into())
+            }),
+        }
+    }
+    fn process_releases(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        debug!("Proccesing releases:");
+        for release in std::mem::take(&mut self.releases).iter() {
+            if !column(release, "Status")?.eq_ignore_ascii_case("Complete") {
+                debug!("Skipping release {:?}", release);
+                continue;
+            }
+            let symbol = self.symbol(release)?;
+            let date = parse_date(column(release, "Vest Date")?)?;
+            let price = parse_amount(column(release, "Price")?)?;
+            let withheld_shares = release
+                .get("Tax Payment Method")
+                .is_some_and(|m| m.eq_ignore_ascii_case("Withhold Shares"));
+            let amount = if withheld_shares {
+                parse_amount(column(release, "Net Share Proceeds")?)?
+            } else {
+                parse_amount(column(release, "Quantity")?)?
+            };
+            let order_number = column(release, "Order Number")?;
+            info!(
+                "RSU {} {:?} {:?} {:?} {}",
+                symbol, date, amount, price, order_number
+            );
+            let share = Stock {
+                date,
+                price,
+                currency: "USD".to_string(),
+                unit: amount,
+                action: Action::Buy,
+                metadata: format!("RSU-MS-{}", order_number),
+                fee: Decimal::ZERO,
+                id: format!("MS-{}", order_number),
+                espp: None,
+                lots: vec![],
+            };
+            add_share(&mut self.positions, &symbol, share);
+        }
+        Ok(())
+    }
+    fn process_withdrawals(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        debug!("Proccesing withdrawals:");
+        for withdrawal in std::mem::take(&mut self.withdrawals).iter() {
+            let status = withdrawal
+                .get("Order Status")
+                .or(withdrawal.get("Status"))
+                .map(|s| s.as_str())
+                .unwrap_or("");
+            let order_type = column(withdrawal, "Type")?;
+            if !status.eq_ignore_ascii_case("Complete") || !order_type.contains("Sale") {
+                // Share transfers to other brokers and cancelled orders do not sell anything
+                debug!("Skipping withdrawal {:?}", withdrawal);
+                continue;
+            }
+            let symbol = self.symbol(withdrawal)?;
+            let date = parse_date(column(withdrawal, "Execution Date")?)?;
+            let price = parse_amount(column(withdrawal, "Price")?)?;
+            let amount = parse_amount(column(withdrawal, "Quantity")?)?.abs();
+            // The net amount is the proceeds less fees, it is zero when the proceeds paid
+            // the tax of a sell to cover
+            let net_amount = match withdrawal.get("Net Amount").filter(|a| !a.is_empty()) {
+                Some(net_amount) => parse_amount(net_amount)?,
+                None => Decimal::ZERO,
+            };
+            let fee = if net_amount.is_zero() {
+                Decimal::ZERO
+            } else {
+                (amount * price - net_amount).max(Decimal::ZERO)
+            };
+            info!(
+                "Sale {} {:?} {:?} {:?} fee {:?}",
+                symbol, date, amount, price, fee
+            );
+            let share = Stock {
+                date,
+                price,
+                currency: "USD".to_string(),
+                unit: amount,
+                action: Action::Sell,
+                metadata: order_type.to_string(),
+                fee,
+                id: match withdrawal.get("Order Number").filter(|o| !o.is_empty()) {
+                    Some(order_number) => format!("MS-{}", order_number),
+                    None => format!("MS-SALE-{}", date),
+                },
+                espp: None,
+                lots: vec![],
+            };
+            add_share(&mut self.positions, &symbol, share);
+        }
+        Ok(())
+    }
+}
+
+fn column<'a>(row: &'a ReportRow, key: &str) -> Result<&'a str, SimpleError> {
+    row.get(key)
+        .map(|v| v.as_str())
+        .ok_or_else(|| SimpleError(format!("Missing column {} in report", key)))
+}
+
+fn parse_date(date: &str) -> Result<chrono::NaiveDate, chrono::ParseError> {
+    chrono::NaiveDate::parse_from_str(date, "%m/%d/%Y")
+        .or_else(|_| chrono::NaiveDate::parse_from_str(date, "%d-%b-%Y"))
+}
+
+impl Importer for MorganStanleyImporter {
+    fn import(
+        &mut self,
+        file_paths: &[String],
+    ) -> Result<Vec<crate::Positions>, Box<dyn std::error::Error>> {
+        // Needs the Releases and Withdrawals reports
+        for file in file_paths.iter() {
+            self.parse_report(file)?;
+        }
+        self.process_releases()?;
+        self.process_withdrawals()?;
+
+        Ok(std::mem::take(&mut self.positions))
+    }
+    fn detect(&self, file: &FileSniff) -> u8 {
+        let report = file.contains("Vest Date") || file.contains("Execution Date");
+        if report && file.contains("Order Number") {
+            90
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::portfolio::Portfolio;
+    use rust_decimal_macros::dec;
+    use std::io::Read;
+
+    use super::*;
+
+    fn init() -> Result<Portfolio, std::io::Error> {
+        let _ = env_logger::builder()
+            .is_test(true)
+            .filter_level(log::LevelFilter::Trace)
+            .try_init();
+        let test_portfolio = format!(
+            "{}/test_files/test_portfolio_morgan_stanley.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let mut file = std::fs::File::open(test_portfolio)?;
+        let mut porfolio_data = String::new();
+        file.read_to_string(&mut porfolio_data)?;
+        let port: Portfolio = serde_json::from_str(porfolio_data.as_str())?;
+        Ok(port)
+    }
+
+    #[test]
+    fn test_morgan_stanley_importer() {
+        let test_portfolio = init().unwrap();
+        let mut morgan_stanley = MorganStanleyImporter::new(Some("ACME".to_string()));
+        let releases = format!("{}/test_files/Releases.csv", env!("CARGO_MANIFEST_DIR"));
+        let withdrawals = format!("{}/test_files/Withdrawals.csv", env!("CARGO_MANIFEST_DIR"));
+        let res = morgan_stanley.import(&[withdrawals, releases]).unwrap();
+        debug!("{:?}", res);
+        assert_eq!(res, test_portfolio.stocks);
+        // 10 shares sold at 152.00 for a net amount of 1519.95, the sell to cover has no net
+        // amount to take the fee from
+        let fees: Vec<(&str, Decimal)> = res[0]
+            .shares
+            .iter()
+            .filter(|s| s.action == Action::Sell)
+            .map(|s| (s.id.as_str(), s.fee))
+            .collect();
+        assert_eq!(
+            fees,
+            vec![
+                ("MS-W-20240617-1", Decimal::ZERO),
+                ("MS-W-20240801-1", dec!(0.05))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_with_etrade_history() {
+        // Lots from the E*Trade era and the Morgan Stanley reports end up in one position
+        let test_portfolio = init().unwrap();
+        let mut portfolio = Portfolio::new().unwrap();
+        let etrade = vec![crate::Positions {
+            ticker: "ACME".to_string(),
+            account: String::new(),
+            shares: vec![Stock {
+                date: chrono::NaiveDate::from_ymd_opt(2022, 9, 15).unwrap(),
+                price: dec!(101.25),
+                currency: "USD".to_string(),
+                unit: dec!(12.0),
+                action: Action::Buy,
+                metadata: "RSU-1001-2".to_string(),
+                fee: Decimal::ZERO,
+                id: "ETRADE-RSU-1001-2".to_string(),
+                espp: None,
+                lots: vec![],
+            }],
+            actions: vec![],
+        }];
+        portfolio.merge_postions(&etrade).unwrap();
+        let releases = format!("{}/test_files/Releases.csv", env!("CARGO_MANIFEST_DIR"));
+        let withdrawals = format!("{}/test_files/Withdrawals.csv", env!("CARGO_MANIFEST_DIR"));
+        for _ in 0..2 {
+            // Importing the same reports twice must not add the lots twice
+            let mut morgan_stanley = MorganStanleyImporter::new(Some("ACME".to_string()));
+            let res = morgan_stanley
+                .import(&[withdrawals.clone(), releases.clone()])
+                .unwrap();
+            portfolio.merge_postions(&res).unwrap();
+        }
+        assert_eq!(portfolio.stocks.len(), 1);
+        assert_eq!(
+            portfolio.stocks[0].shares.len(),
+            test_portfolio.stocks[0].shares.len() + 1
+        );
+    }
+}
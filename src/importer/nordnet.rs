@@ -0,0 +1,281 @@
+use log::{debug, info};
+use rust_decimal::Decimal;
+
+use crate::money::round_money;
+use crate::portfolio::{Action, Stock};
+
+use super::{FileSniff, Importer, SimpleError, add_share, decode_text};
+
+/// Norwegian and Swedish headers of the columns used from `transactions.csv`.
+const DATE: &[&str] = &["Handelsdag", "Affärsdag"];
+const TRANSACTION_TYPE: &[&str] = &["Transaksjonstype", "Transaktionstyp"];
+const SECURITY: &[&str] = &["Verdipapir", "Värdepapper"];
+const QUANTITY: &[&str] = &["Antall", "Antal"];
+const PRICE: &[&str] = &["Kurs"];
+const CURRENCY: &[&str] = &["Valuta"];
+const AMOUNT: &[&str] = &["Beløp", "Belopp"];
+const FEES: &[&str] = &["Totale Avgifter", "Totala Avgifter", "Avgifter"];
+const EXCHANGE_RATE: &[&str] = &["Vekslingskurs", "Växlingskurs"];
+const CANCELLED: &[&str] = &["Makuleringsdato", "Makuleringsdatum"];
+const ID: &[&str] = &["Id"];
+
+const BUY: &[&str] = &["KJØPT", "KÖPT"];
+const SELL: &[&str] = &["SOLGT", "SÅLT"];
+const DIVIDEND: &[&str] = &["UTBYTTE", "UTDELNING"];
+const WITHHOLDING_TAX: &[&str] = &["KUPONGSKATT", "UTL. KUPSKATT", "UTL KUPSKATT"];
+
+/// Parses numbers like `1 234,56`.
+fn parse_number(value: &str) -> Result<Decimal, rust_decimal::Error> {
+    value
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| if c == ',' { '.' } else { c })
+        .collect::<String>()
+        .parse()
+}
+
+/// Importer for the `transactions.csv` export of Nordnet.
+///
+/// The export is UTF-16 encoded and tab separated with comma as decimal separator, the
+/// headers are Norwegian or Swedish depending on the site it was downloaded from. Trades
+/// (`KJØPT`/`SOLGT`, `KÖPT`/`SÅLT`) are stored in the currency of the price, the Nordnet
+/// transaction id, the exchange rate and the account currency are kept in the metadata as
+/// `NORDNET-<id> <exchange rate> <account currency>`.
+///
+/// The total fees of a trade are charged in the account currency, they are converted to the
+/// currency of the price with the exchange rate of the trade.
+///
+/// Dividends (`UTBYTTE`/`UTDELNING`) are stored per share in the currency of the
+/// security. Withheld tax (`KUPONGSKATT`) is charged in the account currency, it is stored as
+/// the whole amount in the currency of the dividend of the same security and day, converted
+/// with the exchange rate of the tax or else of the dividend.
+/// Other transactions like splits are only logged. Cancelled transactions are skipped.
+pub struct NordnetImporter {
+    positions: Vec<crate::Positions>,
+}
+
+impl NordnetImporter {
+    pub fn new() -> Self {
+        NordnetImporter { positions: vec![] }
+    }
+    fn parse_transactions_file(
+        &mut self,
+        file_path: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let data = decode_text(&std::fs::read(file_path)?);
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader(data.as_bytes());
+        let headers = reader.headers()?.clone();
+        // Valuta is repeated for every amount, the first one is the currency of the price
+        let find = |names: &[&str]| headers.iter().position(|h| names.contains(&h));
+        let require = |names: &[&str]| {
+            find(names).ok_or_else(|| {
+                SimpleError(format!(
+                    "Missing column {} in {}",
+                    names.join("/"),
+                    file_path
+                ))
+            })
+        };
+        let date_column = require(DATE)?;
+        let type_column = require(TRANSACTION_TYPE)?;
+        let security_column = require(SECURITY)?;
+        let quantity_column = require(QUANTITY)?;
+        let price_column = require(PRICE)?;
+        let currency_column = require(CURRENCY)?;
+        let amount_column = require(AMOUNT)?;
+        let account_currency_column = headers
+            .iter()
+            .enumerate()
+            .filter(|(_, h)| CURRENCY.contains(h))
+            .map(|(i, _)| i)
+            .nth(1);
+        let fees_column = find(FEES);
+        let exchange_rate_column = find(EXCHANGE_RATE);
+        let cancelled_column = find(CANCELLED);
+        let id_column = require(ID)?;
+        let records = reader.records().collect::<Result<Vec<_>, _>>()?;
+        // Withheld tax is charged in the account currency, it is stored in the currency of
+        // the dividend paid on the same day
+        let dividend_currency = |security: &str, date: &str| {
+            records
+                .iter()
+                .find(|r| {
+                    DIVIDEND.contains(&r.get(type_column).unwrap_or(""))
+                        && r.get(security_column) == Some(security)
+                        && r.get(date_column) == Some(date)
+                })
+                .map(|r| {
+                    let rate = exchange_rate_column.and_then(|c| r.get(c)).unwrap_or("");
+                    (r.get(currency_column).unwrap_or(""), rate)
+                })
+        };
+        for record in records.iter() {
+            debug!("{:?}", record);
+            let field = |index: usize| record.get(index).unwrap_or("");
+            if cancelled_column.is_some_and(|c| !field(c).is_empty()) {
+                debug!("Skipping cancelled transaction {}", field(id_column));
+                continue;
+            }
+            let transaction_type = field(type_column);
+            let action = if BUY.contains(&transaction_type) {
+                Action::Buy
+            } else if SELL.contains(&transaction_type) {
+                Action::Sell
+            } else if DIVIDEND.contains(&transaction_type) {
+                Action::Dividend
+            } else if WITHHOLDING_TAX.contains(&transaction_type) {
+                Action::WithholdingTax
+            } else {
+                info!(
+                    "{} {} {} not imported",
+                    transaction_type,
+                    field(security_column),
+                    field(date_column)
+                );
+                continue;
+            };
+            let ticker = field(security_column);
+            let date = chrono::NaiveDate::parse_from_str(field(date_column), "%Y-%m-%d")?;
+            let mut exchange_rate = exchange_rate_column
+                .map(field)
+                .filter(|rate| !rate.is_empty())
+                .unwrap_or("1");
+            let account_currency = account_currency_column
+                .map(field)
+                .unwrap_or(field(currency_column));
+            let (amount, price, currency) = if action == Action::WithholdingTax {
+                // The tax is only given as amount in the account currency
+                let tax = parse_number(field(amount_column))?.abs();
+                match dividend_currency(ticker, field(date_column)) {
+                    Some((currency, dividend_rate))
+                        if !currency.is_empty() && currency != account_currency =>
+                    {
+                        if exchange_rate_column.map(field).is_none_or(str::is_empty) {
+                            exchange_rate = dividend_rate;
+                        }
+                        let rate = parse_number(exchange_rate)?;
+                        if rate.is_zero() {
+                            return Err(Box::new(SimpleError(format!(
+                                "Transaction {} has no exchange rate for its tax",
+                                field(id_column)
+                            ))));
+                        }
+                        (Decimal::ONE, round_money(tax / rate, currency), currency)
+                    }
+                    _ => (Decimal::ONE, tax, account_currency),
+                }
+            } else {
+                (
+                    parse_number(field(quantity_column))?.abs(),
+                    parse_number(field(price_column))?,
+                    field(currency_column),
+                )
+            };
+            let fee = match fees_column.map(field).filter(|fee| !fee.is_empty()) {
+                Some(fee) if matches!(action, Action::Buy | Action::Sell) => {
+                    let fee = parse_number(fee)?.abs();
+                    if currency == account_currency {
+                        fee
+                    } else {
+                        let rate = parse_number(exchange_rate)?;
+                        if rate.is_zero() {
+                            return Err(Box::new(SimpleError(format!(
+                                "Transaction {} has no exchange rate for its fees",
+                                field(id_column)
+                            ))));
+                        }
+                        round_money(fee / rate, currency)
+                    }
+                }
+                _ => Decimal::ZERO,
+            };
+            info!(
+                "{:?} {} {:?} {:?} {:?} {} fee {:?}",
+                action, ticker, date, amount, price, currency, fee
+            );
+            let share = Stock {
+                date,
+                price,
+                currency: currency.to_string(),
+                unit: amount,
+                action,
+                metadata: format!(
+                    "NORDNET-{} {} {}",
+                    field(id_column),
+                    exchange_rate,
+                    account_currency
+                ),
+                fee,
+                id: format!("NORDNET-{}", field(id_column)),
+                espp: None,
+                lots: vec![],
+            };
+            add_share(&mut self.positions, ticker, share);
+        }
+        Ok(())
+    }
+}
+
+impl Importer for NordnetImporter {
+    fn import(
+        &mut self,
+        file_paths: &[String],
+    ) -> Result<Vec<crate::Positions>, Box<dyn std::error::Error>> {
+        // Needs transactions.csv
+        for file in file_paths.iter() {
+            self.parse_transactions_file(file)?;
+        }
+
+        Ok(std::mem::take(&mut self.positions))
+    }
+    fn detect(&self, file: &FileSniff) -> u8 {
+        if TRANSACTION_TYPE.iter().any(|h| file.contains(h))
+            && SECURITY.iter().any(|h| file.contains(h))
+        {
+            100
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::portfolio::Portfolio;
+    use std::io::Read;
+
+    use super::*;
+
+    fn init() -> Result<Portfolio, std::io::Error> {
+        let _ = env_logger::builder()
+            .is_test(true)
+            .filter_level(log::LevelFilter::Trace)
+            .try_init();
+        let test_portfolio = format!(
+            "{}/test_files/test_portfolio_nordnet.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let mut file = std::fs::File::open(test_portfolio)?;
+        let mut porfolio_data = String::new();
+        file.read_to_string(&mut porfolio_data)?;
+        let port: Portfolio = serde_json::from_str(porfolio_data.as_str())?;
+        Ok(port)
+    }
+
+    #[test]
+    fn test_nordnet_importer() {
+        let test_portfolio = init().unwrap();
+        let mut nordnet = NordnetImporter::new();
+        let transactions = format!(
+            "{}/test_files/nordnet_transactions.csv",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let res = nordnet.import(&[transactions]).unwrap();
+        debug!("{:?}", res);
+        assert_eq!(res, test_portfolio.stocks);
+    }
+}
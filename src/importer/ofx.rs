@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+
+use log::{debug, info};
+use rust_decimal::Decimal;
+
+use crate::portfolio::{Action, CorporateAction, CorporateActionKind, Stock};
+
+use super::{FileSniff, Importer, SimpleError, add_action, add_share, read_csv_rows};
+
+/// Element of an OFX document. Leaf elements like `<UNITS>10` carry a value, aggregates
+/// like `<INVBUY>` carry children.
+#[derive(Debug, Default)]
+struct OfxNode {
+    name: String,
+    value: Option<String>,
+    children: Vec<OfxNode>,
+}
+
+impl OfxNode {
+    fn child(&self, name: &str) -> Option<&OfxNode> {
+        self.children.iter().find(|c| c.name == name)
+    }
+    /// Value of the leaf at `path`, e.g. `INVTRAN/DTTRADE`.
+    fn value(&self, path: &str) -> Option<&str> {
+        let mut node = self;
+        for name in path.split('/') {
+            node = node.child(name)?;
+        }
+        node.value.as_deref()
+    }
+    fn require(&self, path: &str) -> Result<&str, SimpleError> {
+        self.value(path)
+            .ok_or_else(|| SimpleError(format!("Missing {} in {}", path, self.name)))
+    }
+    /// All descendants with the given name.
+    fn find_all<'a>(&'a self, name: &str, found: &mut Vec<&'a OfxNode>) {
+        for child in self.children.iter() {
+            if child.name == name {
+                found.push(child);
+            }
+            child.find_all(name, found);
+        }
+    }
+}
+
+/// Parses OFX 1.x (SGML, leaf elements are not closed) and OFX 2.x (XML) documents.
+fn parse_ofx(data: &str) -> Result<OfxNode, SimpleError> {
+    let start = data
+        .find("<OFX>")
+        .ok_or_else(|| SimpleError("No <OFX> element found".into()))?;
+    let mut stack = vec![OfxNode::default()];
+    let mut rest = &data[start..];
+    while let Some(open) = rest.find('<') {
+        let close = rest[open..]
+            .find('>')
+            .ok_or_else(|| SimpleError("Unterminated tag".into()))?
+            + open;
+        let tag = rest[open + 1..close].trim();
+        rest = &rest[close + 1..];
+        let text_end = rest.find('<').unwrap_or(rest.len());
+        let text = rest[..text_end].trim();
+        if let Some(name) = tag.strip_prefix('/') {
+            // Closing a leaf that was already completed by its value is a no-op
+            if let Some(index) = stack.iter().rposition(|n| n.name == name) {
+                while stack.len() > index {
+                    let node = stack.pop().unwrap();
+                    stack.last_mut().unwrap().children.push(node);
+                }
+            }
+        } else if !text.is_empty() {
+            stack.last_mut().unwrap().children.push(OfxNode {
+                name: tag.to_string(),
+                value: Some(text.to_string()),
+                children: vec![],
+            });
+        } else {
+            stack.push(OfxNode {
+                name: tag.to_string(),
+                ..Default::default()
+            });
+        }
+    }
+    while stack.len() > 1 {
+        let node = stack.pop().unwrap();
+        stack.last_mut().unwrap().children.push(node);
+    }
+    Ok(stack.pop().unwrap())
+}
+
+/// Importer for OFX/QFX investment statements, which most brokers can export.
+///
+/// `BUYSTOCK`/`BUYMF`/`BUYOTHER` and `REINVEST` transactions become buys and
+/// `SELLSTOCK`/`SELLMF`/`SELLOTHER` transactions become sells, with the `FITID` of the
+/// transaction as metadata `OFX-<fitid>` so re-imports are recognised and the `COMMISSION`
+/// and `FEES` of the transaction as fee. Securities are identified by CUSIP in OFX, they
+/// are mapped to tickers with a user supplied lookup table and otherwise with the `TICKER`
+/// of the statement's security list.
+///
+/// Dividend `INCOME` transactions become dividends with the `TOTAL` as price and their
+/// `WITHHOLDING` becomes withheld tax, `REINVEST` transactions of a dividend add the
+/// dividend as well as the buy. `SPLIT` transactions become stock splits of the position.
+pub struct OfxImporter {
+    positions: Vec<crate::Positions>,
+    lookup: HashMap<String, String>,
+}
+
+impl OfxImporter {
+    pub fn new(lookup: HashMap<String, String>) -> Self {
+        OfxImporter {
+            positions: vec![],
+            lookup,
+        }
+    }
+    /// Loads a CUSIP to ticker lookup table from a csv file with the CUSIP in the first and
+    /// the ticker in the second column.
+    pub fn load_lookup(
+        file_path: &str,
+    ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+        let mut lookup = HashMap::new();
+        for row in read_csv_rows(file_path)? {
+            if let [cusip, ticker, ..] = row.as_slice()
+                && !cusip.is_empty()
+                && !cusip.eq_ignore_ascii_case("cusip")
+            {
+                lookup.insert(cusip.clone(), ticker.clone());
+            }
+        }
+        Ok(lookup)
+    }
+    fn parse_ofx_file(&mut self, file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let data = std::fs::read_to_string(file_path)?;
+        let ofx = parse_ofx(data.as_str())?;
+        let mut securities = vec![];
+        ofx.find_all("SECINFO", &mut securities);
+        let mut tickers = self.lookup.clone();
+        for security in securities {
+            if let (Some(id), Some(ticker)) =
+                (security.value("SECID/UNIQUEID"), security.value("TICKER"))
+            {
+                tickers.entry(id.to_string()).or_insert(ticker.to_string());
+            }
+        }
+        let mut statements = vec![];
+        ofx.find_all("INVSTMTRS", &mut statements);
+        for statement in statements {
+            let default_currency = statement.value("CURDEF").unwrap_or("USD");
+            let transactions = match statement.child("INVTRANLIST") {
+                Some(transactions) => transactions,
+                None => continue,
+            };
+            for transaction in transactions.children.iter() {
+                debug!("{:?}", transaction);
+                let (action, details) = match transaction.name.as_str() {
+                    "BUYSTOCK" | "BUYMF" | "BUYOTHER" => (Action::Buy, transaction.child("INVBUY")),
+                    "SELLSTOCK" | "SELLMF" | "SELLOTHER" => {
+                        (Action::Sell, transaction.child("INVSELL"))
+                    }
+                    "REINVEST" => (Action::Buy, Some(transaction)),
+                    "INCOME" => {
+                        self.process_income(transaction, &tickers, default_currency)?;
+                        continue;
+                    }
+                    "SPLIT" => {
+                        self.process_split(transaction, &tickers)?;
+                        continue;
+                    }
+                    _ => {
+                        debug!("Skipping {}", transaction.name);
+                        continue;
+                    }
+                };
+                let details = details.ok_or_else(|| {
+                    SimpleError(format!("{} without transaction details", transaction.name))
+                })?;
+                let cusip = details.require("SECID/UNIQUEID")?;
+                let ticker = tickers.get(cusip).ok_or_else(|| {
+                    SimpleError(format!(
+                        "No ticker for {}, add it to the lookup table",
+                        cusip
+                    ))
+                })?;
+                let date = parse_date(details.require("INVTRAN/DTTRADE")?)?;
+                let amount: Decimal = details.require("UNITS")?.parse()?;
+                let price: Decimal = details.require("UNITPRICE")?.parse()?;
+                let currency = details
+                    .value("CURRENCY/CURSYM")
+                    .or(details.value("ORIGCURRENCY/CURSYM"))
+                    .unwrap_or(default_currency);
+                let fitid = details.require("INVTRAN/FITID")?;
+                let mut fee = Decimal::ZERO;
+                for cost in ["COMMISSION", "FEES"] {
+                    if let Some(value) = details.value(cost) {
+                        fee += value.parse::<Decimal>()?.abs();
+                    }
+                }
+                info!(
+                    "{} {} {:?} {:?} {:?} {}",
+                    transaction.name, ticker, date, amount, price, currency
+                );
+                let share = Stock {
+                    date,
+                    price,
+                    currency: currency.to_string(),
+                    unit: amount.abs(),
+                    action,
+                    metadata: format!("OFX-{}", fitid),
+                    fee,
+                    id: format!("OFX-{}", fitid),
+                    espp: None,
+                    lots: vec![],
+                };
+                add_share(&mut self.positions, ticker, share);
+                if transaction.name == "REINVEST" {
+                    self.process_income(transaction, &tickers, default_currency)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Adds the stock split of a `SPLIT` transaction as `NUMERATOR` new shares for
+    /// `DENOMINATOR` old shares.
+    fn process_split(
+        &mut self,
+        transaction: &OfxNode,
+        tickers: &HashMap<String, String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let cusip = transaction.require("SECID/UNIQUEID")?;
+        let ticker = tickers.get(cusip).ok_or_else(|| {
+            SimpleError(format!(
+                "No ticker for {}, add it to the lookup table",
+                cusip
+            ))
+        })?;
+        let date = parse_date(transaction.require("INVTRAN/DTTRADE")?)?;
+        let numerator: Decimal = transaction.require("NUMERATOR")?.parse()?;
+        let denominator: Decimal = transaction.require("DENOMINATOR")?.parse()?;
+        let fitid = transaction.require("INVTRAN/FITID")?;
+        info!("SPLIT {} {:?} {}:{}", ticker, date, numerator, denominator);
+        let action = CorporateAction {
+            date,
+            kind: CorporateActionKind::Split {
+                numerator,
+                denominator,
+            },
+            metadata: format!("OFX-{}", fitid),
+        };
+        add_action(&mut self.positions, ticker, action);
+        Ok(())
+    }
+    /// Adds the dividend and withheld tax of an `INCOME` or `REINVEST` transaction, other
+    /// income like capital gains distributions and interest is only logged.
+    fn process_income(
+        &mut self,
+        transaction: &OfxNode,
+        tickers: &HashMap<String, String>,
+        default_currency: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let cusip = transaction.require("SECID/UNIQUEID")?;
+        let income_type = transaction.value("INCOMETYPE").unwrap_or("");
+        if income_type != "DIV" {
+            info!(
+                "{} {} of {} not imported",
+                transaction.name, income_type, cusip
+            );
+            return Ok(());
+        }
+        let ticker = tickers.get(cusip).ok_or_else(|| {
+            SimpleError(format!(
+                "No ticker for {}, add it to the lookup table",
+                cusip
+            ))
+        })?;
+        let date = parse_date(transaction.require("INVTRAN/DTTRADE")?)?;
+        let total: Decimal = transaction.require("TOTAL")?.parse()?;
+        let currency = transaction
+            .value("CURRENCY/CURSYM")
+            .or(transaction.value("ORIGCURRENCY/CURSYM"))
+            .unwrap_or(default_currency);
+        let metadata = format!("OFX-{}", transaction.require("INVTRAN/FITID")?);
+        info!("Dividend {} {:?} {:?} {}", ticker, date, total, currency);
+        let mut lots = vec![(Action::Dividend, total.abs())];
+        if let Some(withholding) = transaction.value("WITHHOLDING") {
+            lots.push((
+                Action::WithholdingTax,
+                withholding.parse::<Decimal>()?.abs(),
+            ));
+        }
+        for (action, amount) in lots {
+            let share = Stock {
+                date,
+                price: amount,
+                currency: currency.to_string(),
+                unit: Decimal::ONE,
+                action,
+                metadata: metadata.clone(),
+                fee: Decimal::ZERO,
+                id: metadata.clone(),
+                espp: None,
+                lots: vec![],
+            };
+            add_share(&mut self.positions, ticker, share);
+        }
+        Ok(())
+    }
+}
+
+/// Parses OFX dates like `20240115` or `20240115120000.000[-5:EST]`.
+fn parse_date(date: &str) -> Result<chrono::NaiveDate, chrono::ParseError> {
+    chrono::NaiveDate::parse_from_str(date.get(..8).unwrap_or(date), "%Y%m%d")
+}
+
+impl Importer for OfxImporter {
+    fn import(
+        &mut self,
+        file_paths: &[String],
+    ) -> Result<Vec<crate::Positions>, Box<dyn std::error::Error>> {
+        // Needs OFX or QFX investment statements
+        for file in file_paths.iter() {
+            self.parse_ofx_file(file)?;
+        }
+
+        Ok(std::mem::take(&mut self.positions))
+    }
+    fn detect(&self, file: &FileSniff) -> u8 {
+        if file.contains("OFXHEADER") || file.contains("<OFX>") {
+            100
+        } else if matches!(file.extension.as_str(), "ofx" | "qfx") {
+            60
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::portfolio::Portfolio;
+    use std::io::Read;
+
+    use super::*;
+
+    fn init() -> Result<Portfolio, std::io::Error> {
+        let _ = env_logger::builder()
+            .is_test(true)
+            .filter_level(log::LevelFilter::Trace)
+            .try_init();
+        let test_portfolio = format!(
+            "{}/test_files/test_portfolio_ofx.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let mut file = std::fs::File::open(test_portfolio)?;
+        let mut porfolio_data = String::new();
+        file.read_to_string(&mut porfolio_data)?;
+        let port: Portfolio = serde_json::from_str(porfolio_data.as_str())?;
+        Ok(port)
+    }
+
+    #[test]
+    fn test_ofx_importer() {
+        let test_portfolio = init().unwrap();
+        let lookup = OfxImporter::load_lookup(&format!(
+            "{}/test_files/cusip_lookup.csv",
+            env!("CARGO_MANIFEST_DIR")
+        ))
+        .unwrap();
+        let mut ofx = OfxImporter::new(lookup);
+        let statement = format!("{}/test_files/statement.ofx", env!("CARGO_MANIFEST_DIR"));
+        let res = ofx.import(&[statement]).unwrap();
+        debug!("{:?}", res);
+        assert_eq!(res, test_portfolio.stocks);
+    }
+}
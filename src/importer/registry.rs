@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+
+use super::{
+    CsvImporter, CsvMapping, EtradeImporter, FidelityImporter, IbkrImporter, Importer,
+    MorganStanleyImporter, NordnetImporter, OfxImporter, SchwabImporter, SimpleError, route_files,
+};
+
+/// Option values by `ImporterOption::key`, options the user left empty are missing.
+pub type ImporterOptions = HashMap<String, String>;
+
+/// Creates a new importer from the options given by the user.
+pub type ImporterFactory =
+    Box<dyn Fn(&ImporterOptions) -> Result<Box<dyn Importer>, Box<dyn std::error::Error>>>;
+
+/// Names of importers with the files routed to them.
+pub type DetectedFiles = Vec<(String, Vec<String>)>;
+
+/// Extra input an importer asks for before the files, like a mapping profile.
+#[derive(Debug, Clone)]
+pub struct ImporterOption {
+    pub key: String,
+    /// Question shown to the user, an empty answer keeps the default.
+    pub prompt: String,
+}
+
+/// An importer known to the registry.
+pub struct ImporterEntry {
+    pub name: String,
+    pub description: String,
+    /// Files the importer needs and where to download them.
+    pub file_hints: Vec<String>,
+    pub options: Vec<ImporterOption>,
+    pub factory: ImporterFactory,
+}
+
+/// Importers available by name, in the order they were registered.
+///
+/// `ImporterRegistry::new` knows all importers of rustyfolio, more importers can be added
+/// with `register` at startup.
+pub struct ImporterRegistry {
+    entries: Vec<ImporterEntry>,
+}
+
+impl ImporterRegistry {
+    pub fn new() -> Self {
+        let mut registry = ImporterRegistry::empty();
+        registry.register(ImporterEntry {
+            name: "Etrade".to_string(),
+            description: "E*Trade at work stock plan and brokerage account".to_string(),
+            file_hints: vec![
+                "BenefitHistory.xlsx".to_string(),
+                "G&L_Expanded.xlsx".to_string(),
+                "DownloadTxnHistory.csv of the brokerage account".to_string(),
+            ],
+            options: vec![],
+            factory: Box::new(|_| Ok(Box::new(EtradeImporter::new()))),
+        });
+        registry.register(ImporterEntry {
+            name: "CSV".to_string(),
+            description: "Generic csv file with configurable columns".to_string(),
+            file_hints: vec!["csv files".to_string()],
+            options: vec![ImporterOption {
+                key: "mapping".to_string(),
+                prompt: "Pass path for a column mapping profile (.toml/.json), empty for the default layout".to_string(),
+            }],
+            factory: Box::new(|options| match options.get("mapping") {
+                Some(mapping) => Ok(Box::new(CsvImporter::with_mapping(
+                    CsvMapping::from_file(mapping)?,
+                ))),
+                None => Ok(Box::new(CsvImporter::new())),
+            }),
+        });
+        registry.register(ImporterEntry {
+            name: "Schwab".to_string(),
+            description: "Charles Schwab Equity Award Center".to_string(),
+            file_hints: vec!["EquityAwardsCenter_Transactions.json or .csv".to_string()],
+            options: vec![],
+            factory: Box::new(|_| Ok(Box::new(SchwabImporter::new()))),
+        });
+        registry.register(ImporterEntry {
+            name: "Morgan Stanley at Work".to_string(),
+            description: "Morgan Stanley at Work stock plan reports".to_string(),
+            file_hints: vec![
+                "Releases report".to_string(),
+                "Withdrawals report".to_string(),
+            ],
+            options: vec![ImporterOption {
+                key: "ticker".to_string(),
+                prompt: "Ticker of the plan shares, empty if the reports have a Symbol column"
+                    .to_string(),
+            }],
+            factory: Box::new(|options| {
+                Ok(Box::new(MorganStanleyImporter::new(
+                    options.get("ticker").cloned(),
+                )))
+            }),
+        });
+        registry.register(ImporterEntry {
+            name: "Interactive Brokers".to_string(),
+            description: "Interactive Brokers Flex Query".to_string(),
+            file_hints: vec!["Flex Query statements in XML format".to_string()],
+            options: vec![],
+            factory: Box::new(|_| Ok(Box::new(IbkrImporter::new()))),
+        });
+        registry.register(ImporterEntry {
+            name: "Fidelity NetBenefits".to_string(),
+            description: "Fidelity NetBenefits stock plan".to_string(),
+            file_hints: vec![
+                "Transaction history csv".to_string(),
+                "Custom report csv".to_string(),
+            ],
+            options: vec![],
+            factory: Box::new(|_| Ok(Box::new(FidelityImporter::new()))),
+        });
+        registry.register(ImporterEntry {
+            name: "OFX/QFX statement".to_string(),
+            description: "OFX/QFX investment statement".to_string(),
+            file_hints: vec!["OFX/QFX statements".to_string()],
+            options: vec![ImporterOption {
+                key: "lookup".to_string(),
+                prompt: "Pass path for a CUSIP,Ticker lookup table, empty to use the tickers of the statement".to_string(),
+            }],
+            factory: Box::new(|options| {
+                let lookup = match options.get("lookup") {
+                    Some(lookup) => OfxImporter::load_lookup(lookup)?,
+                    None => HashMap::new(),
+                };
+                Ok(Box::new(OfxImporter::new(lookup)))
+            }),
+        });
+        registry.register(ImporterEntry {
+            name: "Nordnet".to_string(),
+            description: "Nordnet transactions".to_string(),
+            file_hints: vec!["transactions.csv".to_string()],
+            options: vec![],
+            factory: Box::new(|_| Ok(Box::new(NordnetImporter::new()))),
+        });
+        registry
+    }
+    /// A registry without any importers.
+    pub fn empty() -> Self {
+        ImporterRegistry { entries: vec![] }
+    }
+    /// Adds an importer, an importer registered earlier with the same name is replaced.
+    pub fn register(&mut self, entry: ImporterEntry) {
+        match self.entries.iter_mut().find(|e| e.name == entry.name) {
+            Some(existing) => *existing = entry,
+            None => self.entries.push(entry),
+        }
+    }
+    pub fn entries(&self) -> &[ImporterEntry] {
+        &self.entries
+    }
+    pub fn get(&self, name: &str) -> Option<&ImporterEntry> {
+        self.entries.iter().find(|e| e.name == name)
+    }
+    pub fn create(
+        &self,
+        name: &str,
+        options: &ImporterOptions,
+    ) -> Result<Box<dyn Importer>, Box<dyn std::error::Error>> {
+        let entry = self
+            .get(name)
+            .ok_or_else(|| SimpleError(format!("No importer named {}", name)))?;
+        (entry.factory)(options)
+    }
+    /// Imports `file_paths` with the importer called `name`.
+    pub fn run(
+        &self,
+        name: &str,
+        options: &ImporterOptions,
+        file_paths: &[String],
+    ) -> Result<Vec<crate::Positions>, Box<dyn std::error::Error>> {
+        self.create(name, options)?.import(file_paths)
+    }
+    /// Routes `file_paths` to the importers recognising them, see `route_files`. Importers
+    /// are created with their default options.
+    ///
+    /// Returns the names of the importers with the files they recognised and the files no
+    /// importer recognised.
+    pub fn detect(
+        &self,
+        file_paths: &[String],
+    ) -> Result<(DetectedFiles, Vec<String>), Box<dyn std::error::Error>> {
+        let mut importers = vec![];
+        for entry in self.entries.iter() {
+            importers.push((entry.factory)(&ImporterOptions::new())?);
+        }
+        let (routed, unrecognised) = route_files(&importers, file_paths);
+        let routed = self
+            .entries
+            .iter()
+            .zip(routed)
+            .filter(|(_, files)| !files.is_empty())
+            .map(|(entry, files)| (entry.name.clone(), files))
+            .collect();
+        Ok((routed, unrecognised))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::importer::FileSniff;
+    use crate::portfolio::Portfolio;
+
+    use super::*;
+
+    struct EmptyImporter;
+
+    impl Importer for EmptyImporter {
+        fn import(
+            &mut self,
+            _file_paths: &[String],
+        ) -> Result<Vec<crate::Positions>, Box<dyn std::error::Error>> {
+            Ok(vec![])
+        }
+        fn detect(&self, file: &FileSniff) -> u8 {
+            if file.extension == "toml" { 10 } else { 0 }
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = ImporterRegistry::new();
+        let dir = format!("{}/test_files", env!("CARGO_MANIFEST_DIR"));
+        let mut options = ImporterOptions::new();
+        options.insert("ticker".to_string(), "ACME".to_string());
+        let res = registry
+            .run(
+                "Morgan Stanley at Work",
+                &options,
+                &[
+                    format!("{}/Releases.csv", dir),
+                    format!("{}/Withdrawals.csv", dir),
+                ],
+            )
+            .unwrap();
+        let expected: Portfolio = serde_json::from_str(
+            &std::fs::read_to_string(format!("{}/test_portfolio_morgan_stanley.json", dir))
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(res, expected.stocks);
+        assert!(registry.create("Unknown", &options).is_err());
+
+        registry.register(ImporterEntry {
+            name: "Empty".to_string(),
+            description: "Importer registered at startup".to_string(),
+            file_hints: vec![],
+            options: vec![],
+            factory: Box::new(|_| Ok(Box::new(EmptyImporter))),
+        });
+        assert_eq!(registry.entries().last().unwrap().name, "Empty");
+        let (routed, _) = registry
+            .detect(&[
+                format!("{}/custom_mapping.toml", dir),
+                format!("{}/ibkr_flex.xml", dir),
+            ])
+            .unwrap();
+        assert_eq!(
+            routed,
+            vec![
+                (
+                    "Interactive Brokers".to_string(),
+                    vec![format!("{}/ibkr_flex.xml", dir)]
+                ),
+                (
+                    "Empty".to_string(),
+                    vec![format!("{}/custom_mapping.toml", dir)]
+                ),
+            ]
+        );
+    }
+}
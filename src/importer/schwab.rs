@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+
+use log::{debug, info};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::portfolio::{Action, EsppPurchase, Stock};
+
+use super::{FileSniff, Importer, SimpleError, add_share, parse_amount};
+
+#[derive(Debug, Deserialize)]
+struct EacExport {
+    #[serde(rename = "Transactions")]
+    transactions: Vec<EacTransaction>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EacTransaction {
+    #[serde(rename = "Date")]
+    date: String,
+    #[serde(rename = "Action")]
+    action: String,
+    #[serde(rename = "Symbol")]
+    symbol: Option<String>,
+    #[serde(rename = "Quantity")]
+    quantity: Option<String>,
+    #[serde(rename = "Description")]
+    description: Option<String>,
+    #[serde(rename = "FeesAndCommissions")]
+    #[serde(default)]
+    fees: Option<String>,
+    #[serde(rename = "TransactionDetails")]
+    #[serde(default)]
+    details: Vec<EacTransactionDetail>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EacTransactionDetail {
+    #[serde(rename = "Details")]
+    details: HashMap<String, Option<String>>,
+}
+
+impl EacTransactionDetail {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.details
+            .get(key)
+            .and_then(|v| v.as_deref())
+            .filter(|v| !v.is_empty())
+    }
+}
+
+/// Importer for the transaction history of the Charles Schwab Equity Award Center.
+///
+/// Both the JSON and the CSV download of the transaction history are supported. In the CSV
+/// download every transaction row is followed by detail rows with an empty `Date` column,
+/// they are read into the same structure as the `TransactionDetails` of the JSON download.
+///
+/// Lots follow the conventions of the `EtradeImporter`: RSU deposits are stored at the vest
+/// date fair market value with metadata `RSU-<award id>-<vest date>`, ESPP deposits at the
+/// purchase date fair market value with metadata `ESPP` and sales at the sale price with the
+/// description of the sale as metadata and `FeesAndCommissions` as fee. The history has no
+/// transaction ids, sales get the id `SCHWAB-SALE-<date>-<quantity>-<price>`.
+pub struct SchwabImporter {
+    positions: Vec<crate::Positions>,
+    transactions: Vec<EacTransaction>,
+}
+
+impl SchwabImporter {
+    pub fn new() -> Self {
+        SchwabImporter {
+            positions: vec![],
+            transactions: vec![],
+        }
+    }
+    fn parse_json_file(&mut self, file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let data = std::fs::read_to_string(file_path)?;
+        let mut export: EacExport = serde_json::from_str(data.as_str())?;
+        self.transactions.append(&mut export.transactions);
+        Ok(())
+    }
+    fn parse_csv_file(&mut self, file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_path(file_path)?;
+        let headers = reader.headers()?.clone();
+        for val in reader.records() {
+            let record = val?;
+            let row: HashMap<String, Option<String>> = headers
+                .iter()
+                .zip(record.iter())
+                .filter(|(_, v)| !v.is_empty())
+                .map(|(h, v)| (h.to_string(), Some(v.to_string())))
+                .collect();
+            let column = |key: &str| row.get(key).cloned().flatten();
+            match column("Date") {
+                Some(date) => self.transactions.push(EacTransaction {
+                    date,
+                    action: column("Action").unwrap_or_default(),
+                    symbol: column("Symbol"),
+                    quantity: column("Quantity"),
+                    description: column("Description"),
+                    fees: column("FeesAndCommissions"),
+                    details: vec![],
+                }),
+                None => match self.transactions.last_mut() {
+                    Some(transaction) => transaction
+                        .details
+                        .push(EacTransactionDetail { details: row }),
+                    None => {
+                        return Err(Box::new(SimpleError(
+                            "Found transaction details before any transaction".into(),
+                        )));
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+    fn process_transactions(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut transactions = std::mem::take(&mut self.transactions);
+        transactions.sort_by_key(|t| parse_date(&t.date).ok());
+        for transaction in transactions.iter() {
+            debug!("{:?}", transaction);
+            let symbol = match &transaction.symbol {
+                Some(symbol) => symbol.as_str(),
+                None => continue,
+            };
+            let description = transaction.description.clone().unwrap_or_default();
+            match (transaction.action.as_str(), description.as_str()) {
+                ("Deposit", "RS") => {
+                    for detail in transaction.details.iter() {
+                        let date = parse_date(require(detail, "VestDate")?)?;
+                        let price = parse_amount(require(detail, "VestFairMarketValue")?)?;
+                        let amount = match detail.get("Shares") {
+                            Some(shares) => parse_amount(shares)?,
+                            None => parse_amount(transaction.quantity.as_deref().unwrap_or("0"))?,
+                        };
+                        let award_id = detail.get("AwardId").unwrap_or("");
+                        info!(
+                            "RSU {} {:?} {:?} {:?} {}",
+                            symbol, date, amount, price, award_id
+                        );
+                        let share = Stock {
+                            date,
+                            price,
+                            currency: "USD".to_string(),
+                            unit: amount,
+                            action: Action::Buy,
+                            metadata: format!("RSU-{}-{}", award_id, date.format("%Y%m%d")),
+                            fee: Decimal::ZERO,
+                            id: format!("SCHWAB-RSU-{}-{}", award_id, date.format("%Y%m%d")),
+                            espp: None,
+                            lots: vec![],
+                        };
+                        add_share(&mut self.positions, symbol, share);
+                    }
+                }
+                ("Deposit", "ESPP") => {
+                    for detail in transaction.details.iter() {
+                        let date = parse_date(require(detail, "PurchaseDate")?)?;
+                        let price = parse_amount(require(detail, "PurchaseFairMarketValue")?)?;
+                        let espp = EsppPurchase {
+                            grant_date: parse_date(require(detail, "SubscriptionDate")?)?,
+                            purchase_price: parse_amount(require(detail, "PurchasePrice")?)?,
+                            grant_fmv: parse_amount(require(
+                                detail,
+                                "SubscriptionFairMarketValue",
+                            )?)?,
+                            purchase_fmv: price,
+                        };
+                        let amount = match detail.get("Shares") {
+                            Some(shares) => parse_amount(shares)?,
+                            None => parse_amount(transaction.quantity.as_deref().unwrap_or("0"))?,
+                        };
+                        info!("ESPP {} {:?} {:?} {:?}", symbol, date, amount, price);
+                        let share = Stock {
+                            date,
+                            price,
+                            currency: "USD".to_string(),
+                            unit: amount,
+                            action: Action::Buy,
+                            metadata: "ESPP".to_string(),
+                            fee: Decimal::ZERO,
+                            id: format!("SCHWAB-ESPP-{}", date),
+                            espp: Some(espp),
+                            lots: vec![],
+                        };
+                        add_share(&mut self.positions, symbol, share);
+                    }
+                }
+                ("Sale" | "Quick Sale" | "Forced Quick Sell", _) => {
+                    // All lots of a sale are sold at the same price, so the sale is stored as
+                    // one sell of the total quantity.
+                    let date = parse_date(&transaction.date)?;
+                    let amount = parse_amount(transaction.quantity.as_deref().unwrap_or("0"))?;
+                    let price = match transaction.details.first() {
+                        Some(detail) => parse_amount(require(detail, "SalePrice")?)?,
+                        None => {
+                            return Err(Box::new(SimpleError(format!(
+                                "Sale of {} on {} has no sale price",
+                                symbol, transaction.date
+                            ))));
+                        }
+                    };
+                    let fee = match transaction.fees.as_deref() {
+                        Some(fees) => parse_amount(fees)?.abs(),
+                        None => Decimal::ZERO,
+                    };
+                    info!(
+                        "Sale {} {:?} {:?} {:?} fee {:?}",
+                        symbol, date, amount, price, fee
+                    );
+                    let share = Stock {
+                        date,
+                        price,
+                        currency: "USD".to_string(),
+                        unit: amount,
+                        action: Action::Sell,
+                        metadata: description,
+                        fee,
+                        id: format!("SCHWAB-SALE-{}-{}-{}", date, amount.normalize(), price),
+                        espp: None,
+                        lots: vec![],
+                    };
+                    add_share(&mut self.positions, symbol, share);
+                }
+                (action, _) => {
+                    debug!("Skipping {} {} {}", action, symbol, transaction.date);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn require<'a>(
+    detail: &'a EacTransactionDetail,
+    key: &str,
+) -> Result<&'a str, Box<dyn std::error::Error>> {
+    detail.get(key).ok_or_else(|| {
+        Box::new(SimpleError(format!(
+            "Missing {} in transaction details",
+            key
+        )))
+        .into()
+    })
+}
+
+fn parse_date(date: &str) -> Result<chrono::NaiveDate, chrono::ParseError> {
+    chrono::NaiveDate::parse_from_str(date, "%m/%d/%Y")
+}
+
+impl Importer for SchwabImporter {
+    fn import(
+        &mut self,
+        file_paths: &[String],
+    ) -> Result<Vec<crate::Positions>, Box<dyn std::error::Error>> {
+        // Needs the JSON or CSV transaction history download
+        for file in file_paths.iter() {
+            if file.to_lowercase().ends_with(".json") {
+                self.parse_json_file(file)?;
+            } else {
+                self.parse_csv_file(file)?;
+            }
+        }
+        self.process_transactions()?;
+
+        Ok(std::mem::take(&mut self.positions))
+    }
+    fn detect(&self, file: &FileSniff) -> u8 {
+        let json = file.extension == "json"
+            && file.contains("\"Transactions\"")
+            && file.contains("\"TransactionDetails\"");
+        let csv = file.first_line().contains("\"FeesAndCommissions\"")
+            && file.first_line().contains("\"VestFairMarketValue\"");
+        if json || csv { 90 } else { 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::portfolio::Portfolio;
+    use std::io::Read;
+
+    use super::*;
+
+    fn init() -> Result<Portfolio, std::io::Error> {
+        let _ = env_logger::builder()
+            .is_test(true)
+            .filter_level(log::LevelFilter::Trace)
+            .try_init();
+        let test_portfolio = format!(
+            "{}/test_files/test_portfolio_schwab.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let mut file = std::fs::File::open(test_portfolio)?;
+        let mut porfolio_data = String::new();
+        file.read_to_string(&mut porfolio_data)?;
+        let port: Portfolio = serde_json::from_str(porfolio_data.as_str())?;
+        Ok(port)
+    }
+
+    #[test]
+    fn test_schwab_importer_json() {
+        let test_portfolio = init().unwrap();
+        let mut schwab = SchwabImporter::new();
+        let history = format!(
+            "{}/test_files/EquityAwardsCenter_Transactions.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let res = schwab.import(&[history]).unwrap();
+        debug!("{:?}", res);
+        assert_eq!(res, test_portfolio.stocks);
+    }
+
+    #[test]
+    fn test_schwab_importer_csv() {
+        let test_portfolio = init().unwrap();
+        let mut schwab = SchwabImporter::new();
+        let history = format!(
+            "{}/test_files/EquityAwardsCenter_Transactions.csv",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let res = schwab.import(&[history]).unwrap();
+        debug!("{:?}", res);
+        assert_eq!(res, test_portfolio.stocks);
+    }
+}
@@ -1,31 +1,774 @@
+mod fx;
 mod importer;
+mod money;
 mod portfolio;
 mod tax;
-use importer::{EtradeImporter, ImporterService};
-use log::{error, info};
-use portfolio::{Portfolio, Positions, Stock};
+mod workspace;
+use clap::{Parser, Subcommand};
+use importer::{ImporterOptions, ImporterRegistry, collect_files};
+use log::error;
+use portfolio::{
+    Account, AccountKind, Action, CorporateAction, CorporateActionKind, Form1099B, Portfolio,
+    Positions, Resolution, Stock, is_id_separator,
+};
+use rust_decimal::Decimal;
+use workspace::Workspace;
 
 static MENU_OPTIONS: &str = r#"
     1. Show Position
     2. Import statement
-    3. Run Fifo calc
+    3. Calculate gains
     4. Export portfolio to csv
+    5. Dividend report
+    6. Add corporate action
+    7. Edit lot
+    8. Switch portfolio
     9. Exit
+    10. Norwegian tax report (RF-1159)
+    11. US Form 8949 and Schedule D
     "#;
-static IMPORTER_SUBMENU_OPTIONS: &str = r#"
-    1. Etrade
-    Other option go back
+/// Portfolio tracker and capital gains calculator. Without a command the interactive menu
+/// is started.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Directory holding the portfolios, one json file per portfolio.
+    #[arg(short, long, default_value = "portfolios")]
+    workspace: std::path::PathBuf,
+    /// Portfolio to work on, asked for when the workspace has several.
+    #[arg(short, long)]
+    portfolio: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Lists the portfolios of the workspace.
+    List,
+    /// Copies a portfolio to a new portfolio.
+    Copy { from: String, to: String },
+    /// Merges the accounts and lots of a portfolio into another portfolio.
+    Merge { from: String, into: String },
+    /// Calculates the realized gains of the portfolio.
+    #[command(alias = "fifo")]
+    Gains {
+        /// Match sales with the lots of their account only instead of all accounts of the
+        /// owner.
+        #[arg(long)]
+        per_account: bool,
+        /// Lots sales are taken from: fifo, lifo, hifo, lofo or specific-id.
+        #[arg(long, default_value = "fifo")]
+        method: tax::LotSelection,
+        /// Currency gains are reported in, e.g. NOK. Trades are converted with the rates in
+        /// the `rates` directory of the workspace and the files given with --rates.
+        #[arg(long)]
+        currency: Option<String>,
+        /// Exchange rate files from the ECB, Norges Bank or Riksbank.
+        #[arg(long)]
+        rates: Vec<String>,
+    },
+    /// Compares the gains per tax year of all lot selection methods.
+    Compare {
+        #[arg(long)]
+        per_account: bool,
+        #[arg(long)]
+        currency: Option<String>,
+        #[arg(long)]
+        rates: Vec<String>,
+        /// Planned sale today as TICKER:UNITS:PRICE, optionally followed by :LOT-IDS for
+        /// specific-id, added to the portfolio for the comparison only.
+        #[arg(long)]
+        sell: Option<String>,
+        /// Account of the planned sale.
+        #[arg(long, default_value = "")]
+        account: String,
+    },
+    /// Writes the dividend report of the portfolio.
+    Dividends,
+    /// Writes the Norwegian RF-1159 figures and the shielding deduction of every lot.
+    Norway {
+        /// Tax year to report.
+        year: i32,
+        /// Exchange rate files with NOK rates, besides the `rates` directory.
+        #[arg(long)]
+        rates: Vec<String>,
+        /// Shielding rate of a year in percent missing from the built-in rates, e.g.
+        /// 2026=3.4.
+        #[arg(long)]
+        shielding_rate: Vec<String>,
+    },
+    /// Writes the US Form 8949 rows and Schedule D totals of every tax year in USD.
+    Form8949 {
+        /// Lots sales are taken from: fifo, lifo, hifo, lofo or specific-id.
+        #[arg(long, default_value = "fifo")]
+        method: tax::LotSelection,
+        /// Exchange rate files with USD rates, besides the `rates` directory.
+        #[arg(long)]
+        rates: Vec<String>,
+    },
+    /// Exports the portfolio to csv next to its json file.
+    Export,
+}
+
+static CORPORATE_ACTION_OPTIONS: &str = r#"
+    1. Split or reverse split
+    2. Ticker change
+    3. Spin-off
+    4. Merger or acquisition
+    "#;
+static ACCOUNT_KIND_OPTIONS: &str = r#"
+    1. Taxable
+    2. Pension
+    3. ISK
+    4. ASK
     "#;
+static FORM_1099B_OPTIONS: &str = r#"
+    1. No Form 1099-B
+    2. Form 1099-B with the cost basis reported to the IRS
+    3. Form 1099-B without the cost basis, e.g. a stock plan account with RSU or ESPP shares
+    "#;
+static TAX_SCOPE_OPTIONS: &str = r#"
+    1. Per person, lots of all taxable accounts together (Norway)
+    2. Per account (US)
+    "#;
+static LOT_SELECTION_OPTIONS: &str = r#"
+    1. FIFO, oldest lots first
+    2. LIFO, newest lots first
+    3. HIFO, highest cost first
+    4. LOFO, lowest cost first
+    5. Specific lots, ids set on the sale with Edit lot
+    "#;
+static COMPARE_OPTION: &str = "6. Compare all methods\n    ";
+fn parse_selection(answer: &str) -> tax::LotSelection {
+    match answer {
+        "2" => tax::LotSelection::Lifo,
+        "3" => tax::LotSelection::Hifo,
+        "4" => tax::LotSelection::Lofo,
+        "5" => tax::LotSelection::SpecificId,
+        _ => tax::LotSelection::Fifo,
+    }
+}
+/// Asks for the lot selection method.
+fn choose_selection() -> Result<tax::LotSelection, std::io::Error> {
+    Ok(parse_selection(&ask(LOT_SELECTION_OPTIONS)?))
+}
+/// Asks for the lot selection method or to compare all methods, `None` to compare.
+fn choose_selection_or_compare() -> Result<Option<tax::LotSelection>, std::io::Error> {
+    let answer = ask(&format!("{}{}", LOT_SELECTION_OPTIONS, COMPARE_OPTION))?;
+    Ok(match answer.as_str() {
+        "6" => None,
+        answer => Some(parse_selection(answer)),
+    })
+}
+/// Lists the registered importers followed by automatic detection.
+fn importer_submenu(registry: &ImporterRegistry) -> String {
+    let mut menu = String::new();
+    for (index, entry) in registry.entries().iter().enumerate() {
+        menu.push_str(&format!(
+            "    {}. {} - {}\n",
+            index + 1,
+            entry.name,
+            entry.description
+        ));
+    }
+    menu.push_str(&format!(
+        "    {}. Detect importers for files or folders\n",
+        registry.entries().len() + 1
+    ));
+    menu.push_str("    Other option go back\n");
+    menu
+}
+
+/// Reads file paths from stdin until an empty line is entered.
+/// Returns `None` if any of the paths is not a file.
+fn read_file_paths() -> Result<Option<Vec<String>>, std::io::Error> {
+    let mut files: Vec<String> = vec![];
+    loop {
+        let mut buffer = String::new();
+        std::io::stdin().read_line(&mut buffer)?;
+        buffer = buffer.replace("\r", "");
+        buffer = buffer.replace("\n", "");
+        if buffer.is_empty() {
+            break;
+        }
+        files.push(buffer);
+    }
+    let mut invalid_files = false;
+    for f in files.iter() {
+        let path = std::path::Path::new(f);
+        if !path.is_file() {
+            invalid_files = true;
+            error!("{} is not a valid file", path.to_str().unwrap());
+        }
+    }
+    if invalid_files {
+        return Ok(None);
+    }
+    Ok(Some(files))
+}
+
+/// Reads file and folder paths from stdin until an empty line is entered and lists the
+/// files in them. Returns `None` if any of the paths does not exist.
+fn read_folder_paths() -> Result<Option<Vec<String>>, std::io::Error> {
+    let mut files: Vec<String> = vec![];
+    loop {
+        let mut buffer = String::new();
+        std::io::stdin().read_line(&mut buffer)?;
+        buffer = buffer.replace("\r", "");
+        buffer = buffer.replace("\n", "");
+        if buffer.is_empty() {
+            break;
+        }
+        if !std::path::Path::new(&buffer).exists() {
+            error!("{} does not exist", buffer);
+            return Ok(None);
+        }
+        files.append(&mut collect_files(&buffer)?);
+    }
+    Ok(Some(files))
+}
+
+fn format_lot(share: &Stock) -> String {
+    format!(
+        "{} {} {:?} {} @ {} {} {} {}",
+        share.id,
+        share.date,
+        share.action,
+        share.unit,
+        share.price,
+        share.currency,
+        share.metadata,
+        share.lots.join(" ")
+    )
+}
+
+/// Lot ids separated by spaces, commas or semicolons.
+fn lot_ids(text: &str) -> Vec<String> {
+    text.split(is_id_separator)
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Prints `prompt` and reads one line from stdin, failing at the end of the input.
+fn ask(prompt: &str) -> Result<String, std::io::Error> {
+    println!("{}", prompt);
+    let mut buffer = String::new();
+    if std::io::stdin().read_line(&mut buffer)? == 0 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buffer.trim().to_string())
+}
+
+/// Asks for a corporate action of a position and adds it to the portfolio.
+fn add_corporate_action(portfolio: &mut Portfolio) -> Result<(), Box<dyn std::error::Error>> {
+    let ticker = ask("Ticker")?;
+    let date = chrono::NaiveDate::parse_from_str(&ask("Date (YYYY-MM-DD)")?, "%Y-%m-%d")?;
+    let kind = match ask(CORPORATE_ACTION_OPTIONS)?.as_str() {
+        "1" => CorporateActionKind::Split {
+            numerator: ask("New shares")?.parse()?,
+            denominator: ask("For old shares")?.parse()?,
+        },
+        "2" => CorporateActionKind::TickerChange {
+            new_ticker: ask("New ticker")?,
+        },
+        "3" => CorporateActionKind::SpinOff {
+            new_ticker: ask("Ticker of the spun off company")?,
+            ratio: ask("New shares per share held")?.parse()?,
+            cost_allocation: ask("Percent of the cost basis allocated to the new shares")?
+                .parse::<Decimal>()?
+                / Decimal::ONE_HUNDRED,
+        },
+        "4" => CorporateActionKind::Merger {
+            new_ticker: ask("Ticker of the acquiring company")?,
+            ratio: ask("New shares per share held, 0 for a cash merger")?.parse()?,
+            cash_per_share: ask("Cash per share held, 0 for a stock merger")?.parse()?,
+            cost_allocation: ask(
+                "Percent of the cost basis allocated to the new shares, 0 for a cash merger and 100 for a stock merger",
+            )?
+                .parse::<Decimal>()?
+                / Decimal::ONE_HUNDRED,
+        },
+        _ => {
+            println!("Invalid option");
+            return Ok(());
+        }
+    };
+    let action = CorporateAction {
+        date,
+        kind,
+        metadata: ask("Description")?,
+    };
+    portfolio.add_corporate_action(&ticker, action)?;
+    Ok(())
+}
+
+/// Lists the lots of a ticker per account and changes or deletes the lot the user picks by
+/// account and id. Empty
+/// answers keep the current value. Sales also take the ids of the lots they are taken from
+/// with specific identification, `-` clears them.
+fn edit_lot(portfolio: &mut Portfolio) -> Result<(), Box<dyn std::error::Error>> {
+    let ticker = ask("Ticker")?;
+    let positions: Vec<&Positions> = portfolio
+        .stocks
+        .iter()
+        .filter(|p| p.ticker == ticker)
+        .collect();
+    if positions.is_empty() {
+        return Err(format!("{} is not in the portfolio", ticker).into());
+    }
+    for position in positions.iter() {
+        println!("  Account {:?}", position.account);
+        for share in position.shares.iter() {
+            println!("    {}", format_lot(share));
+        }
+    }
+    // Ids are only unique within the position of an account
+    let account = match positions.as_slice() {
+        [only] => only.account.clone(),
+        _ => ask("Account of the lot, empty for the default account")?,
+    };
+    let id = ask("Id of the lot")?;
+    let missing = || format!("No lot with id {} in account {:?}", id, account);
+    if ask("Delete the lot? [y/N]")?.eq_ignore_ascii_case("y") {
+        let share = portfolio
+            .remove_lot(&account, &ticker, &id)
+            .ok_or_else(missing)?;
+        println!("Deleted {}", format_lot(&share));
+        return Ok(());
+    }
+    let share = portfolio
+        .lot_mut(&account, &ticker, &id)
+        .ok_or_else(missing)?;
+    let unit = ask(&format!("Units [{}]", share.unit))?;
+    let price = ask(&format!("Price [{}]", share.price))?;
+    let fee = ask(&format!("Fee [{}]", share.fee))?;
+    if !unit.is_empty() {
+        share.unit = unit.parse()?;
+    }
+    if !price.is_empty() {
+        share.price = price.parse()?;
+    }
+    if !fee.is_empty() {
+        share.fee = fee.parse()?;
+    }
+    if share.action == Action::Sell {
+        let lots = ask(&format!(
+            "Ids of the lots sold for specific-id, separated by spaces [{}]",
+            share.lots.join(" ")
+        ))?;
+        match lots.as_str() {
+            "" => {}
+            "-" => share.lots.clear(),
+            _ => share.lots = lot_ids(&lots),
+        }
+    }
+    println!("Changed {}", format_lot(share));
+    Ok(())
+}
+
+/// Asks which account an import goes to and adds the account when its id is new. An empty
+/// answer is the default account.
+fn choose_account(portfolio: &mut Portfolio) -> Result<String, Box<dyn std::error::Error>> {
+    for account in portfolio.accounts.iter() {
+        println!(
+            "    {} {} {} {:?} {} {:?}",
+            account.id,
+            account.broker,
+            account.number,
+            account.kind,
+            account.owner,
+            account.form_1099b
+        );
+    }
+    let id = ask("Account id, empty for the default account")?;
+    if !id.is_empty() && portfolio.account(&id).is_none() {
+        let kind = match ask(ACCOUNT_KIND_OPTIONS)?.as_str() {
+            "2" => AccountKind::Pension,
+            "3" => AccountKind::Isk,
+            "4" => AccountKind::Ask,
+            _ => AccountKind::Taxable,
+        };
+        portfolio.add_account(Account {
+            id: id.clone(),
+            broker: ask("Broker")?,
+            number: ask("Account number")?,
+            kind,
+            owner: ask("Owner")?,
+            form_1099b: match ask(FORM_1099B_OPTIONS)?.as_str() {
+                "2" => Form1099B::BasisReported,
+                "3" => Form1099B::BasisNotReported,
+                _ => Form1099B::None,
+            },
+        })?;
+    }
+    Ok(id)
+}
+
+/// Moves imported positions without an account to `account`.
+fn into_account(mut imported: Vec<Positions>, account: &str) -> Vec<Positions> {
+    for position in imported.iter_mut().filter(|p| p.account.is_empty()) {
+        position.account = account.to_string();
+    }
+    imported
+}
+
+/// Prints the units and cost basis held of every ticker per account and for all accounts
+/// together.
+fn show_positions(portfolio: &Portfolio) -> Result<(), Box<dyn std::error::Error>> {
+    let mut views = vec![("Default account".to_string(), portfolio.account_view(""))];
+    for account in portfolio.accounts.iter() {
+        views.push((
+            format!("{} {} {}", account.id, account.broker, account.number),
+            portfolio.account_view(&account.id),
+        ));
+    }
+    views.push(("All accounts".to_string(), portfolio.consolidated()));
+    for (name, view) in views {
+        if view.stocks.is_empty() {
+            continue;
+        }
+        println!("{}", name);
+        let book = tax::LotBook::from_portfolio(&view, tax::LotSelection::Fifo)?;
+        for ticker in book.tickers() {
+            let lots = book.open_lots(ticker);
+            let units: Decimal = lots.iter().map(|l| l.unit).sum();
+            let cost: Decimal = lots.iter().map(|l| l.unit * l.price).sum();
+            let currency = &lots[0].buy.currency;
+            println!(
+                "    {} {} units, cost basis {} {}",
+                ticker,
+                units,
+                money::round_money(cost, currency),
+                currency
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Shows which lots an import adds to the portfolio, asks what to do with every near duplicate
+/// and merges the import when the user accepts.
+fn merge_with_preview(
+    portfolio: &mut Portfolio,
+    imported: &[Positions],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let previews = portfolio.preview_merge(imported);
+    let mut changes = 0;
+    let mut resolutions = vec![];
+    for preview in previews.iter() {
+        println!(
+            "{}{}{}: {} to add, {} duplicates, {} near duplicates",
+            preview.ticker,
+            if preview.account.is_empty() {
+                String::new()
+            } else {
+                format!(" in {}", preview.account)
+            },
+            if preview.new_ticker { " (new)" } else { "" },
+            preview.added.len(),
+            preview.duplicates.len(),
+            preview.near_duplicates.len()
+        );
+        for share in preview.added.iter() {
+            println!("    + {}", format_lot(share));
+        }
+        for share in preview.duplicates.iter() {
+            println!("    = {}", format_lot(share));
+        }
+        for action in preview.actions.iter() {
+            println!(
+                "    + {} {:?} {}",
+                action.date, action.kind, action.metadata
+            );
+        }
+        for near_duplicate in preview.near_duplicates.iter() {
+            println!("    ~ {}", format_lot(&near_duplicate.imported));
+            println!("      existing {}", format_lot(&near_duplicate.existing));
+            let resolution =
+                match ask("      [r]eplace existing, [k]eep existing, [a]dd as new lot? (r/K/a)")?
+                    .to_lowercase()
+                    .as_str()
+                {
+                    "r" => Resolution::Replace,
+                    "a" => Resolution::Add,
+                    _ => Resolution::Keep,
+                };
+            if resolution != Resolution::Keep {
+                changes += 1;
+            }
+            resolutions.push(resolution);
+        }
+        changes += preview.added.len() + preview.actions.len();
+    }
+    if changes == 0 {
+        println!("Nothing new to import");
+        return Ok(());
+    }
+    if ask(&format!(
+        "Apply {} changes to the portfolio? (y/N)",
+        changes
+    ))?
+    .eq_ignore_ascii_case("y")
+    {
+        let mut resolutions = resolutions.into_iter();
+        portfolio.merge_resolved(imported, |_| resolutions.next().unwrap_or(Resolution::Keep))?;
+    } else {
+        println!("Import aborted, the portfolio is unchanged");
+    }
+    Ok(())
+}
+
+/// Asks for an importer and its files and merges the imported lots into the portfolio.
+fn import_statements(
+    registry: &ImporterRegistry,
+    portfolio: &mut Portfolio,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", importer_submenu(registry));
+    let mut buffer = String::new();
+    std::io::stdin().read_line(&mut buffer)?;
+    buffer = buffer.replace("\r", "");
+    buffer = buffer.replace("\n", "");
+    let choice = buffer.parse::<usize>().unwrap_or(0);
+    let entries = registry.entries();
+    if (1..=entries.len()).contains(&choice) {
+        let entry = &entries[choice - 1];
+        let mut options = ImporterOptions::new();
+        for option in entry.options.iter() {
+            println!("{}", option.prompt);
+            let mut buffer = String::new();
+            std::io::stdin().read_line(&mut buffer)?;
+            buffer = buffer.replace("\r", "");
+            buffer = buffer.replace("\n", "");
+            if !buffer.is_empty() {
+                options.insert(option.key.clone(), buffer);
+            }
+        }
+        let mut importer = registry.create(&entry.name, &options)?;
+        let account = choose_account(portfolio)?;
+        println!("Pass path for {}", entry.file_hints.join(" and "));
+        if let Some(files) = read_file_paths()? {
+            let imported_port = into_account(importer.import(&files)?, &account);
+            merge_with_preview(portfolio, &imported_port)?;
+        }
+    } else if choice == entries.len() + 1 {
+        let account = choose_account(portfolio)?;
+        println!("Pass paths of statements or folders with statements");
+        if let Some(files) = read_folder_paths()? {
+            let (routed, unrecognised) = registry.detect(&files)?;
+            for file in unrecognised.iter() {
+                println!("No importer recognised {}", file);
+            }
+            for (name, files) in routed.iter() {
+                println!("Importing {} with {}", files.join(", "), name);
+                let imported_port = into_account(
+                    registry.run(name, &ImporterOptions::new(), files)?,
+                    &account,
+                );
+                merge_with_preview(portfolio, &imported_port)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copy of `portfolio` with a sale today of `sale`, written as TICKER:UNITS:PRICE with
+/// optional lot ids for specific identification, in `account`.
+fn plan_sale(
+    portfolio: &Portfolio,
+    account: &str,
+    sale: &str,
+) -> Result<Portfolio, Box<dyn std::error::Error>> {
+    let mut parts = sale.splitn(4, ':');
+    let (ticker, units, price) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(ticker), Some(units), Some(price)) => (ticker, units.parse()?, price.parse()?),
+        _ => return Err(format!("Planned sale {} is not TICKER:UNITS:PRICE", sale).into()),
+    };
+    let mut planned = portfolio.clone();
+    let position = planned
+        .stocks
+        .iter_mut()
+        .find(|p| p.ticker == ticker && p.account == account)
+        .ok_or_else(|| format!("No {} held in account {:?}", ticker, account))?;
+    let currency = position
+        .shares
+        .last()
+        .map(|s| s.currency.clone())
+        .unwrap_or_default();
+    position.shares.push(Stock {
+        date: chrono::Local::now().date_naive(),
+        price,
+        currency,
+        unit: units,
+        action: Action::Sell,
+        metadata: String::new(),
+        fee: Decimal::ZERO,
+        id: "PLANNED-SALE".to_string(),
+        espp: None,
+        lots: lot_ids(parts.next().unwrap_or("")),
+    });
+    Ok(planned)
+}
+
+/// Asks for the tax year and the shielding rates missing from the built-in rates and writes
+/// the Norwegian tax report.
+fn norway_report(
+    workspace: &Workspace,
+    portfolio: &Portfolio,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let year = ask("Tax year")?.parse()?;
+    let overrides =
+        ask("Shielding rates missing from the built-in rates, e.g. 2026=3.4, separated by spaces")?;
+    let overrides: Vec<String> = overrides.split_whitespace().map(String::from).collect();
+    let rates = tax::NorwegianTaxService::shielding_rates(&overrides)?;
+    tax::NorwegianTaxService::rf1159_report(portfolio, &workspace.fx_rates()?, &rates, year);
+    Ok(())
+}
+
+/// Lists the portfolios of the workspace and asks for the one to work on.
+fn choose_portfolio(workspace: &Workspace) -> Result<String, Box<dyn std::error::Error>> {
+    for name in workspace.list()? {
+        println!("    {}", name);
+    }
+    loop {
+        let name = ask("Portfolio name, a new name creates a portfolio")?;
+        if !name.is_empty() {
+            return Ok(name);
+        }
+    }
+}
+
+/// Stores the open portfolio and opens the one the user chooses. The open portfolio stays
+/// open when either fails.
+fn switch_portfolio(
+    workspace: &Workspace,
+    portfolio: &mut Portfolio,
+) -> Result<(), Box<dyn std::error::Error>> {
+    workspace.store(portfolio)?;
+    *portfolio = workspace.load(&choose_portfolio(workspace)?)?;
+    Ok(())
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     //env_logger::init();
     let _ = env_logger::builder()
         .filter_level(log::LevelFilter::Trace)
         .try_init();
-    let mut portfolio = Portfolio::new().expect("Failed to create portfolio");
+    let cli = Cli::parse();
+    let workspace = Workspace::open(&cli.workspace)?;
+    if let Some(name) = workspace.migrate(std::path::Path::new("test_portfolio.json"))? {
+        println!(
+            "Copied test_portfolio.json into the workspace as portfolio {}",
+            name
+        );
+    }
+    let command = match cli.command {
+        Some(Command::List) => {
+            for name in workspace.list()? {
+                println!("{}", name);
+            }
+            return Ok(());
+        }
+        Some(Command::Copy { from, to }) => return workspace.copy(&from, &to),
+        Some(Command::Merge { from, into }) => return workspace.merge(&from, &into),
+        command => command,
+    };
+    // The only portfolio of the workspace opens without asking
+    let name = match (cli.portfolio, workspace.list()?.as_slice()) {
+        (Some(name), _) => name,
+        (None, [only]) => only.clone(),
+        (None, _) => choose_portfolio(&workspace)?,
+    };
+    let mut portfolio = workspace.load(&name)?;
+    match command {
+        Some(Command::Gains {
+            per_account,
+            method,
+            currency,
+            rates,
+        }) => {
+            let scope = if per_account {
+                tax::TaxScope::PerAccount
+            } else {
+                tax::TaxScope::PerPerson
+            };
+            let mut fx = workspace.fx_rates()?;
+            for file in rates.iter() {
+                fx.load_file(file)?;
+            }
+            tax::TaxCalculatorService::gains_calculation(
+                &portfolio,
+                scope,
+                method,
+                &fx,
+                currency.as_deref(),
+            );
+            return Ok(());
+        }
+        Some(Command::Compare {
+            per_account,
+            currency,
+            rates,
+            sell,
+            account,
+        }) => {
+            let scope = if per_account {
+                tax::TaxScope::PerAccount
+            } else {
+                tax::TaxScope::PerPerson
+            };
+            let mut fx = workspace.fx_rates()?;
+            for file in rates.iter() {
+                fx.load_file(file)?;
+            }
+            let portfolio = match sell {
+                Some(sale) => plan_sale(&portfolio, &account, &sale)?,
+                None => portfolio,
+            };
+            tax::TaxCalculatorService::comparison_report(
+                &portfolio,
+                scope,
+                &fx,
+                currency.as_deref(),
+            );
+            return Ok(());
+        }
+        Some(Command::Dividends) => {
+            tax::TaxCalculatorService::dividend_report(&portfolio);
+            return Ok(());
+        }
+        Some(Command::Norway {
+            year,
+            rates,
+            shielding_rate,
+        }) => {
+            let mut fx = workspace.fx_rates()?;
+            for file in rates.iter() {
+                fx.load_file(file)?;
+            }
+            let shielding_rates = tax::NorwegianTaxService::shielding_rates(&shielding_rate)?;
+            tax::NorwegianTaxService::rf1159_report(&portfolio, &fx, &shielding_rates, year);
+            return Ok(());
+        }
+        Some(Command::Form8949 { method, rates }) => {
+            let mut fx = workspace.fx_rates()?;
+            for file in rates.iter() {
+                fx.load_file(file)?;
+            }
+            tax::UsTaxService::form_8949_report(&portfolio, method, &fx);
+            return Ok(());
+        }
+        Some(Command::Export) => {
+            portfolio.export_csv_to_disk(&workspace.file(&portfolio.name, "csv")?)?;
+            return Ok(());
+        }
+        _ => {}
+    }
+    let registry = ImporterRegistry::new();
     println!("Welcome to rustyfolio! What do you want todo?");
-    portfolio.load_from_disk()?;
     loop {
+        println!("Portfolio {}", portfolio.name);
         println!("{}", MENU_OPTIONS);
         let mut buffer = String::new();
         std::io::stdin().read_line(&mut buffer)?;
@@ -33,60 +776,107 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         buffer = buffer.replace("\n", "");
         match buffer.as_str() {
             "1" => {
-                println!("tbd");
+                if let Err(err) = show_positions(&portfolio) {
+                    error!("Failed to show positions: {}", err);
+                }
             }
             "2" => {
-                println!("{}", IMPORTER_SUBMENU_OPTIONS);
-                let mut buffer = String::new();
-                std::io::stdin().read_line(&mut buffer)?;
-                buffer = buffer.replace("\r", "");
-                buffer = buffer.replace("\n", "");
-                match buffer.as_str() {
-                    "1" => {
-                        println!("Pass path for BenefitHistory.xlsx and G&L_Expanded.xlsx");
-                        let mut files: Vec<String> = vec![];
-                        loop {
-                            let mut buffer = String::new();
-                            std::io::stdin().read_line(&mut buffer)?;
-                            buffer = buffer.replace("\r", "");
-                            buffer = buffer.replace("\n", "");
-                            if buffer.len() == 0 {
-                                break;
-                            }
-                            files.push(buffer);
-                        }
-                        let mut invalid_files = false;
-                        for f in files.iter() {
-                            let path = std::path::Path::new(f);
-                            if !path.is_file() {
-                                invalid_files = true;
-                                error!("{} is not a valid file", path.to_str().unwrap());
-                            }
-                        }
-                        if !invalid_files {
-                            let imp = EtradeImporter::new();
-                            let mut importer = ImporterService::new_importer(imp)?;
-                            let imported_port = importer.run(&files)?;
-                            portfolio.merge_postions(imported_port)?;
-                        }
-                    }
-                    _ => {}
+                if let Err(err) = import_statements(&registry, &mut portfolio) {
+                    error!("Import failed: {}", err);
                 }
             }
             "3" => {
-                tax::TaxCalculatorService::fifo_calculation(&portfolio);
+                let scope = match ask(TAX_SCOPE_OPTIONS)?.as_str() {
+                    "2" => tax::TaxScope::PerAccount,
+                    _ => tax::TaxScope::PerPerson,
+                };
+                let selection = choose_selection_or_compare()?;
+                let currency = ask("Reporting currency, empty to report in the trade currency")?;
+                let currency = Some(currency.as_str()).filter(|c| !c.is_empty());
+                match (workspace.fx_rates(), selection) {
+                    (Ok(fx), Some(selection)) => tax::TaxCalculatorService::gains_calculation(
+                        &portfolio, scope, selection, &fx, currency,
+                    ),
+                    (Ok(fx), None) => tax::TaxCalculatorService::comparison_report(
+                        &portfolio, scope, &fx, currency,
+                    ),
+                    (Err(err), _) => error!("Failed to load exchange rates: {}", err),
+                }
             }
             "4" => {
-                portfolio.export_csv_to_disk()?;
+                let export = workspace
+                    .file(&portfolio.name, "csv")
+                    .and_then(|path| Ok(portfolio.export_csv_to_disk(&path)?));
+                if let Err(err) = export {
+                    error!("Export failed: {}", err);
+                }
+            }
+            "5" => {
+                tax::TaxCalculatorService::dividend_report(&portfolio);
+            }
+            "6" => {
+                if let Err(err) = add_corporate_action(&mut portfolio) {
+                    error!("Failed to add corporate action: {}", err);
+                }
+            }
+            "7" => {
+                if let Err(err) = edit_lot(&mut portfolio) {
+                    error!("Failed to edit lot: {}", err);
+                }
+            }
+            "8" => {
+                if let Err(err) = switch_portfolio(&workspace, &mut portfolio) {
+                    error!("Failed to switch portfolio: {}", err);
+                }
             }
             "9" => {
                 break;
             }
+            "10" => {
+                if let Err(err) = norway_report(&workspace, &portfolio) {
+                    error!("Norwegian tax report failed: {}", err);
+                }
+            }
+            "11" => match workspace.fx_rates() {
+                Ok(fx) => tax::UsTaxService::form_8949_report(&portfolio, choose_selection()?, &fx),
+                Err(err) => error!("Failed to load exchange rates: {}", err),
+            },
             _ => {
                 println!("Invalid option")
             }
         }
     }
-    portfolio.store_to_disk()?;
+    workspace.store(&portfolio)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lot_ids() {
+        assert_eq!(
+            lot_ids(" IBKR-1001, MS-7;OFX-1 "),
+            vec!["IBKR-1001", "MS-7", "OFX-1"]
+        );
+        // Every id the importers give their lots can be listed for specific-id
+        let dir = format!("{}/test_files", env!("CARGO_MANIFEST_DIR"));
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy();
+            if !name.starts_with("test_portfolio") || !name.ends_with(".json") {
+                continue;
+            }
+            let portfolio = Portfolio::load_from_disk(&path).unwrap();
+            for share in portfolio.stocks.iter().flat_map(|p| p.shares.iter()) {
+                assert_eq!(
+                    lot_ids(&share.id),
+                    vec![share.id.clone()],
+                    "{}",
+                    path.display()
+                );
+            }
+        }
+    }
+}
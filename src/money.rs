@@ -0,0 +1,40 @@
+use rust_decimal::{Decimal, RoundingStrategy};
+
+/// Decimals of the smallest unit of `currency` (ISO 4217), currencies not listed have cents.
+pub fn minor_units(currency: &str) -> u32 {
+    match currency {
+        "JPY" | "KRW" | "ISK" | "CLP" | "VND" | "UGX" | "XAF" | "XOF" => 0,
+        "BHD" | "IQD" | "JOD" | "KWD" | "LYD" | "OMR" | "TND" => 3,
+        _ => 2,
+    }
+}
+
+/// Rounds an amount of money to the smallest unit of `currency`, halves are rounded away from
+/// zero like on broker statements. Prices and quantities are never rounded, only amounts
+/// reported in totals.
+pub fn round_money(amount: Decimal, currency: &str) -> Decimal {
+    amount.round_dp_with_strategy(
+        minor_units(currency),
+        RoundingStrategy::MidpointAwayFromZero,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_round_money() {
+        assert_eq!(round_money(dec!(2.345), "USD"), dec!(2.35));
+        assert_eq!(round_money(dec!(-2.345), "USD"), dec!(-2.35));
+        assert_eq!(round_money(dec!(1234.5), "JPY"), dec!(1235));
+        assert_eq!(round_money(dec!(1.2345), "KWD"), dec!(1.235));
+        // 12.3456 ESPP shares at 98.76 reconcile to the cent
+        assert_eq!(
+            round_money(dec!(12.3456) * dec!(98.76), "USD"),
+            dec!(1219.25)
+        );
+    }
+}
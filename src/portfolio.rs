@@ -1,141 +1,977 @@
 use log::debug;
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::{Read, Write};
+use std::path::Path;
 
 #[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub enum Action {
     Buy,
     Sell,
+    /// Dividend paid on `unit` shares with `price` per share. When the number of shares is
+    /// not known `unit` is 1 and `price` the whole payment.
+    Dividend,
+    /// Tax withheld from a dividend, stored like `Dividend` with positive amounts.
+    WithholdingTax,
 }
 
 #[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct Stock {
     pub date: chrono::NaiveDate,
-    pub price: f64,
+    pub price: Decimal,
     pub currency: String,
-    pub unit: f64,
+    pub unit: Decimal,
     pub action: Action,
     pub metadata: String,
+    /// Commissions and fees of the trade in `currency`, for all units together. They add to
+    /// the cost basis of buys and reduce the proceeds of sells.
+    #[serde(default, skip_serializing_if = "Decimal::is_zero")]
+    pub fee: Decimal,
+    /// Stable identifier of the lot, derived by the importer from the source (grant number,
+    /// order number, transaction id...) so that importing the same file again gives the same
+    /// id. Empty for lots added before ids were introduced.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub id: String,
+    /// Terms of the purchase of ESPP shares, `price` is the fair market value on the
+    /// purchase date.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub espp: Option<EsppPurchase>,
+    /// Ids of the lots a sale takes its units from with specific identification, in the
+    /// order they are sold.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lots: Vec<String>,
+}
+
+/// Characters separating the lot ids a user lists, importers keep them out of ids.
+pub fn is_id_separator(c: char) -> bool {
+    c.is_whitespace() || c == ',' || c == ';'
+}
+
+/// Terms of an ESPP purchase, needed to split the gain of a sale into ordinary income and
+/// capital gain under US tax rules. Prices are per share in the currency of the lot.
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub struct EsppPurchase {
+    /// Grant date of the purchase right, the first day of the offering period.
+    pub grant_date: chrono::NaiveDate,
+    /// Discounted price paid per share.
+    pub purchase_price: Decimal,
+    /// Fair market value on the grant date.
+    pub grant_fmv: Decimal,
+    /// Fair market value on the purchase date.
+    pub purchase_fmv: Decimal,
+}
+
+impl Stock {
+    /// True when `self` and `other` describe the same transaction. Lots are compared by id
+    /// when both have one, otherwise by date and metadata.
+    pub fn same_lot(&self, other: &Stock) -> bool {
+        if !self.id.is_empty() && !other.id.is_empty() {
+            self.id == other.id
+        } else {
+            self.date == other.date && self.metadata == other.metadata
+        }
+    }
+
+    /// True when `self` and `other` are equal apart from their ids and ESPP terms.
+    fn same_values(&self, other: &Stock) -> bool {
+        self.date == other.date
+            && self.price == other.price
+            && self.currency == other.currency
+            && self.unit == other.unit
+            && self.action == other.action
+            && self.metadata == other.metadata
+            && self.fee == other.fee
+    }
+}
+
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub enum CorporateActionKind {
+    /// `numerator` new shares for every `denominator` shares held, a 4:1 split is 4/1 and a
+    /// 1:10 reverse split 1/10.
+    Split {
+        numerator: Decimal,
+        denominator: Decimal,
+    },
+    /// The position continues under `new_ticker`, lots bought before keep their date and
+    /// price.
+    TickerChange { new_ticker: String },
+    /// The security got a new CUSIP, which does not change any lot.
+    CusipChange {
+        old_cusip: String,
+        new_cusip: String,
+    },
+    /// `ratio` shares of `new_ticker` are distributed for every share held. The fraction
+    /// `cost_allocation` of the cost basis of every lot moves to the new shares, which keep
+    /// the acquisition date of the lot.
+    SpinOff {
+        new_ticker: String,
+        ratio: Decimal,
+        cost_allocation: Decimal,
+    },
+    /// The company is acquired for `ratio` shares of `new_ticker` and `cash_per_share` for
+    /// every share held. The new shares keep the acquisition date of the lot and the fraction
+    /// `cost_allocation` of its cost basis, the cash is a sale of the rest of the basis. A cash
+    /// merger has a `cost_allocation` of 0 and a stock merger of 1.
+    Merger {
+        new_ticker: String,
+        ratio: Decimal,
+        cash_per_share: Decimal,
+        cost_allocation: Decimal,
+    },
+}
+
+/// Event changing the shares of a position without buying or selling, effective from the
+/// start of `date`.
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub struct CorporateAction {
+    pub date: chrono::NaiveDate,
+    pub kind: CorporateActionKind,
+    pub metadata: String,
+}
+
+impl CorporateAction {
+    /// Number of shares after the action for every share before it.
+    pub fn split_ratio(&self) -> Decimal {
+        match self.kind {
+            CorporateActionKind::Split {
+                numerator,
+                denominator,
+            } => numerator / denominator,
+            _ => Decimal::ONE,
+        }
+    }
+    /// Ticker of the position the action creates or moves lots to.
+    pub fn new_ticker(&self) -> Option<&str> {
+        match &self.kind {
+            CorporateActionKind::TickerChange { new_ticker }
+            | CorporateActionKind::SpinOff { new_ticker, .. }
+            | CorporateActionKind::Merger { new_ticker, .. } => Some(new_ticker),
+            CorporateActionKind::Split { .. } | CorporateActionKind::CusipChange { .. } => None,
+        }
+    }
+}
+
+/// Tax treatment of an account.
+#[derive(PartialEq, Clone, Copy, Serialize, Deserialize, Debug, Default)]
+pub enum AccountKind {
+    /// Ordinary brokerage account, gains are taxed when realized.
+    #[default]
+    Taxable,
+    /// Pension or retirement account like an IRA or 401(k).
+    Pension,
+    /// Swedish investeringssparkonto, taxed on a standard yield instead of gains.
+    Isk,
+    /// Norwegian aksjesparekonto, gains are taxed when withdrawn from the account.
+    Ask,
+}
+
+/// What the broker reports to the IRS on Form 1099-B about the sales of an account.
+#[derive(PartialEq, Clone, Copy, Serialize, Deserialize, Debug, Default)]
+pub enum Form1099B {
+    /// No Form 1099-B, like most brokers outside the US.
+    #[default]
+    None,
+    /// Form 1099-B with the cost basis reported to the IRS.
+    BasisReported,
+    /// Form 1099-B without the cost basis or with a basis too low, like stock plan accounts
+    /// holding RSU and ESPP shares.
+    BasisNotReported,
+}
+
+impl AccountKind {
+    /// True for accounts whose sales are not taxed as they happen. Their lots are always
+    /// matched within the account.
+    pub fn is_tax_sheltered(&self) -> bool {
+        *self != AccountKind::Taxable
+    }
+}
+
+/// Broker account holding positions of the portfolio.
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub struct Account {
+    /// Short name used by positions to refer to the account, e.g. `etrade`.
+    pub id: String,
+    pub broker: String,
+    pub number: String,
+    pub kind: AccountKind,
+    /// Person owning the account, lots of the same owner are matched together when taxes
+    /// are calculated per person.
+    #[serde(default)]
+    pub owner: String,
+    /// Form 1099-B the broker sends for the account, it decides the Form 8949 box of its sales.
+    #[serde(default)]
+    pub form_1099b: Form1099B,
 }
 
 #[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
 pub struct Positions {
     pub ticker: String,
+    /// Id of the [`Account`] holding the position, empty for the default taxable account of
+    /// the portfolio owner.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub account: String,
     pub shares: Vec<Stock>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<CorporateAction>,
+}
+
+impl Positions {
+    fn is(&self, account: &str, ticker: &str) -> bool {
+        self.account == account && self.ticker == ticker
+    }
+}
+
+/// An imported lot matching a lot in the portfolio by id, or by date and metadata, but with a
+/// different price or number of units. The lot in the portfolio may have been edited or the
+/// statement corrected, so the user decides what happens to it.
+#[derive(PartialEq, Debug, Clone)]
+pub struct NearDuplicate {
+    pub existing: Stock,
+    pub imported: Stock,
+}
+
+/// What merging does with a [`NearDuplicate`].
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Resolution {
+    /// The imported lot takes the place of the existing lot.
+    Replace,
+    /// The existing lot stays as it is and the imported lot is skipped.
+    Keep,
+    /// The imported lot is added next to the existing lot.
+    Add,
+}
+
+/// What merging imported positions would change for one ticker.
+#[derive(PartialEq, Debug, Clone)]
+pub struct MergePreview {
+    pub ticker: String,
+    pub account: String,
+    /// The ticker is not in the account yet.
+    pub new_ticker: bool,
+    pub added: Vec<Stock>,
+    pub duplicates: Vec<Stock>,
+    pub near_duplicates: Vec<NearDuplicate>,
+    /// Corporate actions not in the portfolio yet.
+    pub actions: Vec<CorporateAction>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Portfolio {
     pub name: String,
     pub description: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub accounts: Vec<Account>,
     pub stocks: Vec<Positions>, // This should be an hashmap to make lookup logic better, Vec chosen since it does not work out of the box with Serialization.
 }
 
-static PORTFOLO_CONFIG_FILE: &str = "test_portfolio.json";
-static PORTFOLO_CONFIG_FILE_CSV: &str = "test_portfolio.csv";
-
 impl Portfolio {
     pub fn new() -> Result<Portfolio, Box<dyn std::error::Error>> {
         Ok(Portfolio {
             name: "My Portfolio".to_string(),
             description: "".to_string(),
+            accounts: vec![],
             stocks: vec![],
         })
     }
 
+    pub fn account(&self, id: &str) -> Option<&Account> {
+        self.accounts.iter().find(|a| a.id == id)
+    }
+
+    /// Adds an account positions can be imported into, ids have to be unique.
+    pub fn add_account(&mut self, account: Account) -> Result<(), Box<dyn std::error::Error>> {
+        if account.id.is_empty() || self.account(&account.id).is_some() {
+            return Err(format!("Account id {:?} is empty or already used", account.id).into());
+        }
+        self.accounts.push(account);
+        Ok(())
+    }
+
+    /// Copy of the portfolio with only the positions of `account`.
+    pub fn account_view(&self, account: &str) -> Portfolio {
+        Portfolio {
+            stocks: self
+                .stocks
+                .iter()
+                .filter(|p| p.account == account)
+                .cloned()
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Copy of the portfolio with the positions of all accounts merged into one position per
+    /// ticker in the default account. Lots are ordered by date and corporate actions listed
+    /// by several accounts are kept once.
+    pub fn consolidated(&self) -> Portfolio {
+        let mut stocks: Vec<Positions> = vec![];
+        for position in self.stocks.iter() {
+            let index = match stocks.iter().position(|p| p.ticker == position.ticker) {
+                Some(index) => index,
+                None => {
+                    stocks.push(Positions {
+                        ticker: position.ticker.clone(),
+                        account: String::new(),
+                        shares: vec![],
+                        actions: vec![],
+                    });
+                    stocks.len() - 1
+                }
+            };
+            let consolidated = &mut stocks[index];
+            consolidated.shares.extend(position.shares.iter().cloned());
+            for action in position.actions.iter() {
+                if !consolidated.actions.contains(action) {
+                    consolidated.actions.push(action.clone());
+                }
+            }
+        }
+        for position in stocks.iter_mut() {
+            position.shares.sort_by_key(|s| s.date);
+        }
+        Portfolio {
+            stocks,
+            ..self.clone()
+        }
+    }
+
+    #[allow(dead_code)]
     pub fn new_stock(&mut self, stock: Positions) -> Result<(), Box<dyn std::error::Error>> {
-        self.merge_postions(&vec![stock])?;
+        self.merge_postions(&[stock])?;
         Ok(())
     }
 
+    /// Adds the new lots and corporate actions of `to_be_merged_pos`, near duplicates are
+    /// skipped.
     pub fn merge_postions(
         &mut self,
-        to_be_merged_pos: &Vec<Positions>,
+        to_be_merged_pos: &[Positions],
     ) -> Result<(), Box<dyn std::error::Error>> {
-        for imp_pos in to_be_merged_pos.iter() {
-            let mut ticker_exists = false;
-            for pos in self.stocks.iter_mut() {
-                if imp_pos.ticker == pos.ticker {
-                    ticker_exists = true;
-                    for imp_pos_stock in imp_pos.shares.iter() {
-                        let mut share_exist = false;
-                        for pos_stock in pos.shares.iter() {
-                            if (imp_pos_stock.metadata == pos_stock.metadata)
-                                && (imp_pos_stock.date == pos_stock.date)
-                                && (imp_pos_stock.price == pos_stock.price)
-                            {
-                                share_exist = true;
-                                debug!("Share already exists {:?}", imp_pos_stock);
-                            }
-                        }
-                        if !share_exist {
-                            debug!("Adding share {:?} to {}", imp_pos_stock, pos.ticker);
-                            pos.shares.push(imp_pos_stock.clone());
-                        }
+        self.merge_resolved(to_be_merged_pos, |_| Resolution::Keep)
+    }
+
+    /// Like [`Portfolio::merge_postions`] with `resolve` deciding about every near duplicate,
+    /// in the order of [`Portfolio::preview_merge`].
+    pub fn merge_resolved(
+        &mut self,
+        to_be_merged_pos: &[Positions],
+        mut resolve: impl FnMut(&NearDuplicate) -> Resolution,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for preview in self.preview_merge(to_be_merged_pos) {
+            for share in preview.duplicates.iter() {
+                debug!("Share already exists {:?}", share);
+                // Lots stored before ids and ESPP terms were introduced take them from the
+                // imported lot
+                for existing in self
+                    .stocks
+                    .iter_mut()
+                    .filter(|p| p.is(&preview.account, &preview.ticker))
+                    .flat_map(|p| p.shares.iter_mut())
+                    .filter(|s| s.same_lot(share) && s.same_values(share))
+                {
+                    if existing.id.is_empty() {
+                        existing.id = share.id.clone();
+                    }
+                    if existing.espp.is_none() {
+                        existing.espp = share.espp.clone();
                     }
                 }
             }
-            if !ticker_exists {
-                debug!("Adding ticker {}", imp_pos.ticker);
-                self.stocks.push(Positions {
-                    ticker: imp_pos.ticker.clone(),
-                    shares: imp_pos.shares.clone(),
-                });
+            let mut shares = preview.added;
+            let mut replaced = vec![];
+            for near_duplicate in preview.near_duplicates {
+                match resolve(&near_duplicate) {
+                    Resolution::Replace => replaced.push(near_duplicate),
+                    Resolution::Keep => debug!("Keeping share {:?}", near_duplicate.existing),
+                    Resolution::Add => shares.push(near_duplicate.imported),
+                }
+            }
+            let pos = match self
+                .stocks
+                .iter_mut()
+                .position(|p| p.is(&preview.account, &preview.ticker))
+            {
+                Some(index) => &mut self.stocks[index],
+                None => {
+                    debug!("Adding ticker {}", preview.ticker);
+                    self.stocks.push(Positions {
+                        ticker: preview.ticker,
+                        account: preview.account,
+                        shares: vec![],
+                        actions: vec![],
+                    });
+                    self.stocks.last_mut().unwrap()
+                }
+            };
+            for mut share in shares {
+                // A near duplicate added next to a lot with its id gets an id of its own
+                let base = share.id.clone();
+                let mut count = 1;
+                while !share.id.is_empty() && pos.shares.iter().any(|s| s.id == share.id) {
+                    count += 1;
+                    share.id = format!("{}-{}", base, count);
+                }
+                debug!("Adding share {:?} to {}", share, pos.ticker);
+                pos.shares.push(share);
+            }
+            // The lot replaced may be one added earlier in the same import
+            for near_duplicate in replaced {
+                if let Some(existing) = pos
+                    .shares
+                    .iter_mut()
+                    .find(|s| **s == near_duplicate.existing)
+                {
+                    debug!("Replacing share {:?}", existing);
+                    *existing = near_duplicate.imported;
+                }
+            }
+            for action in preview.actions {
+                debug!("Adding corporate action {:?} to {}", action, pos.ticker);
+                pos.actions.push(action);
             }
         }
         Ok(())
     }
 
-    pub fn load_from_disk(&mut self) -> Result<(), std::io::Error> {
-        let mut file = match File::open(PORTFOLO_CONFIG_FILE) {
-            Ok(res) => res,
-            Err(err) => match err.kind() {
-                std::io::ErrorKind::NotFound => {
-                    return Ok(());
+    /// Shows what `merge_postions` would do without changing the portfolio. Lots are matched
+    /// with [`Stock::same_lot`]: a lot matching an existing lot with equal values is a duplicate
+    /// and skipped, a lot matching an existing lot with another price or number of units is a
+    /// near duplicate left for a [`Resolution`], everything else is added.
+    pub fn preview_merge(&self, to_be_merged_pos: &[Positions]) -> Vec<MergePreview> {
+        let mut previews: Vec<MergePreview> = vec![];
+        for imp_pos in to_be_merged_pos.iter() {
+            let existing_position = self
+                .stocks
+                .iter()
+                .find(|p| p.is(&imp_pos.account, &imp_pos.ticker));
+            let existing = existing_position
+                .map(|p| p.shares.as_slice())
+                .unwrap_or(&[]);
+            let existing_actions = existing_position
+                .map(|p| p.actions.as_slice())
+                .unwrap_or(&[]);
+            let preview = match previews
+                .iter()
+                .position(|p| p.ticker == imp_pos.ticker && p.account == imp_pos.account)
+            {
+                Some(index) => &mut previews[index],
+                None => {
+                    previews.push(MergePreview {
+                        ticker: imp_pos.ticker.clone(),
+                        account: imp_pos.account.clone(),
+                        new_ticker: existing_position.is_none(),
+                        added: vec![],
+                        duplicates: vec![],
+                        near_duplicates: vec![],
+                        actions: vec![],
+                    });
+                    previews.last_mut().unwrap()
                 }
-                _ => {
-                    todo!("Unhandled error");
+            };
+            for imp_pos_stock in imp_pos.shares.iter() {
+                // Lots added earlier in the same import count as existing as well
+                let mut known = existing
+                    .iter()
+                    .chain(preview.added.iter())
+                    .chain(preview.near_duplicates.iter().map(|n| &n.imported));
+                if known
+                    .clone()
+                    .any(|s| s.same_lot(imp_pos_stock) && s.same_values(imp_pos_stock))
+                {
+                    preview.duplicates.push(imp_pos_stock.clone());
+                } else if let Some(similar) = known.find(|s| s.same_lot(imp_pos_stock)) {
+                    preview.near_duplicates.push(NearDuplicate {
+                        existing: similar.clone(),
+                        imported: imp_pos_stock.clone(),
+                    });
+                } else {
+                    preview.added.push(imp_pos_stock.clone());
                 }
-            },
+            }
+            for action in imp_pos.actions.iter() {
+                if !existing_actions.contains(action) && !preview.actions.contains(action) {
+                    preview.actions.push(action.clone());
+                }
+            }
+        }
+        previews
+    }
+
+    /// Adds a corporate action to the positions of `ticker` in every account. Spin-offs,
+    /// mergers and ticker changes create the position of their new ticker in the accounts
+    /// that do not hold it yet.
+    pub fn add_corporate_action(
+        &mut self,
+        ticker: &str,
+        action: CorporateAction,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let valid = match &action.kind {
+            CorporateActionKind::Split {
+                numerator,
+                denominator,
+            } => *numerator > Decimal::ZERO && *denominator > Decimal::ZERO,
+            CorporateActionKind::SpinOff {
+                ratio,
+                cost_allocation,
+                ..
+            } => *ratio > Decimal::ZERO && (Decimal::ZERO..=Decimal::ONE).contains(cost_allocation),
+            CorporateActionKind::Merger {
+                ratio,
+                cash_per_share,
+                cost_allocation,
+                ..
+            } => {
+                // Every part of the cost basis has to end up in the new shares or the cash
+                *ratio >= Decimal::ZERO
+                    && *cash_per_share >= Decimal::ZERO
+                    && (Decimal::ZERO..=Decimal::ONE).contains(cost_allocation)
+                    && (*ratio > Decimal::ZERO || cost_allocation.is_zero())
+                    && (*cash_per_share > Decimal::ZERO || *cost_allocation == Decimal::ONE)
+            }
+            CorporateActionKind::TickerChange { .. } | CorporateActionKind::CusipChange { .. } => {
+                true
+            }
         };
+        if !valid {
+            return Err(format!("Invalid corporate action {:?}", action.kind).into());
+        }
+        let mut accounts = vec![];
+        for position in self.stocks.iter_mut().filter(|p| p.ticker == ticker) {
+            if !position.actions.contains(&action) {
+                position.actions.push(action.clone());
+            }
+            accounts.push(position.account.clone());
+        }
+        if accounts.is_empty() {
+            return Err(format!("{} is not in the portfolio", ticker).into());
+        }
+        if let Some(new_ticker) = action.new_ticker() {
+            for account in accounts {
+                if !self.stocks.iter().any(|p| p.is(&account, new_ticker)) {
+                    debug!("Adding ticker {} to account {:?}", new_ticker, account);
+                    self.stocks.push(Positions {
+                        ticker: new_ticker.to_string(),
+                        account,
+                        shares: vec![],
+                        actions: vec![],
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Ticker a position is known as today, following its ticker changes and mergers.
+    pub fn current_ticker(&self, ticker: &str) -> String {
+        let mut current = ticker.to_string();
+        // Bounded by the number of positions in case of a loop of ticker changes
+        for _ in 0..=self.stocks.len() {
+            let renamed = self
+                .stocks
+                .iter()
+                .filter(|p| p.ticker == current)
+                .flat_map(|p| p.actions.iter())
+                .find_map(|a| match &a.kind {
+                    CorporateActionKind::TickerChange { new_ticker }
+                    | CorporateActionKind::Merger { new_ticker, .. } => Some(new_ticker.clone()),
+                    _ => None,
+                });
+            match renamed {
+                Some(new_ticker) if new_ticker != current => current = new_ticker,
+                _ => break,
+            }
+        }
+        current
+    }
+
+    /// Lot with the id `id` in the position of `ticker` in `account`, ids are only unique
+    /// within a position.
+    pub fn lot_mut(&mut self, account: &str, ticker: &str, id: &str) -> Option<&mut Stock> {
+        self.stocks
+            .iter_mut()
+            .filter(|p| p.is(account, ticker))
+            .flat_map(|p| p.shares.iter_mut())
+            .find(|s| !id.is_empty() && s.id == id)
+    }
+
+    /// Removes the lot with the id `id` from the position of `ticker` in `account`,
+    /// returning it when it was found.
+    pub fn remove_lot(&mut self, account: &str, ticker: &str, id: &str) -> Option<Stock> {
+        self.stocks
+            .iter_mut()
+            .filter(|p| p.is(account, ticker))
+            .find_map(|p| {
+                let index = p.shares.iter().position(|s| !id.is_empty() && s.id == id)?;
+                Some(p.shares.remove(index))
+            })
+    }
+
+    pub fn load_from_disk(path: &Path) -> Result<Portfolio, std::io::Error> {
+        let mut file = File::open(path)?;
         let mut porfolio_data = String::new();
         file.read_to_string(&mut porfolio_data)?;
         let port: Portfolio = serde_json::from_str(porfolio_data.as_str())?;
-        *self = port;
-        Ok(())
+        Ok(port)
     }
-    pub fn store_to_disk(&self) -> Result<(), std::io::Error> {
+    pub fn store_to_disk(&self, path: &Path) -> Result<(), std::io::Error> {
         let portfolio_json = serde_json::to_string(self)?;
-        let mut file = File::create(PORTFOLO_CONFIG_FILE)?;
+        let mut file = File::create(path)?;
         file.write_all(portfolio_json.as_bytes())?;
         Ok(())
     }
-    pub fn export_csv_to_disk(&self) -> Result<(), std::io::Error> {
-        let mut output_string = String::new();
-
-        output_string = ("Ticker,Date,Units,Price,Value,Action,Currency,Metadata").to_string();
+    pub fn export_csv_to_disk(&self, path: &Path) -> Result<(), std::io::Error> {
+        let mut writer = csv::Writer::from_path(path)?;
+        writer.write_record([
+            "Ticker", "Date", "Units", "Price", "Value", "Action", "Currency", "Metadata", "Fee",
+            "Id", "Account",
+        ])?;
         for pos in self.stocks.iter() {
             for ticker in pos.shares.iter() {
-                output_string = format!(
-                    "{}\n{},{},{},{},{},{},{},{}",
-                    output_string,
-                    pos.ticker,
-                    ticker.date,
-                    ticker.unit,
-                    ticker.price,
-                    ticker.price * ticker.unit,
+                writer.write_record([
+                    pos.ticker.clone(),
+                    ticker.date.to_string(),
+                    ticker.unit.to_string(),
+                    ticker.price.to_string(),
+                    crate::money::round_money(ticker.price * ticker.unit, &ticker.currency)
+                        .to_string(),
                     match ticker.action {
                         Action::Buy => "Buy",
                         Action::Sell => "Sell",
-                    },
-                    ticker.currency,
-                    ticker.metadata
-                )
+                        Action::Dividend => "Dividend",
+                        Action::WithholdingTax => "WithholdingTax",
+                    }
+                    .to_string(),
+                    ticker.currency.clone(),
+                    ticker.metadata.clone(),
+                    ticker.fee.to_string(),
+                    ticker.id.clone(),
+                    pos.account.clone(),
+                ])?;
             }
         }
-        let mut file = File::create(PORTFOLO_CONFIG_FILE_CSV)?;
-        file.write_all(output_string.as_bytes())?;
-        Ok(())
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn share(date: &str, price: Decimal, unit: Decimal, metadata: &str) -> Stock {
+        Stock {
+            date: chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap(),
+            price,
+            currency: "USD".to_string(),
+            unit,
+            action: Action::Buy,
+            metadata: metadata.to_string(),
+            fee: Decimal::ZERO,
+            id: String::new(),
+            espp: None,
+            lots: vec![],
+        }
+    }
+
+    fn lot(id: &str, date: &str, price: Decimal, unit: Decimal, metadata: &str) -> Stock {
+        Stock {
+            id: id.to_string(),
+            ..share(date, price, unit, metadata)
+        }
+    }
+
+    #[test]
+    fn test_preview_merge() {
+        let mut portfolio = Portfolio::new().unwrap();
+        portfolio
+            .merge_postions(&[Positions {
+                ticker: "ACME".to_string(),
+                account: String::new(),
+                shares: vec![share("2023-03-15", dec!(120.5), dec!(10.0), "RSU-1001-1")],
+                actions: vec![],
+            }])
+            .unwrap();
+        let imported = vec![
+            Positions {
+                ticker: "ACME".to_string(),
+                account: String::new(),
+                shares: vec![
+                    share("2023-03-15", dec!(120.5), dec!(10.0), "RSU-1001-1"),
+                    share("2023-03-15", dec!(120.5), dec!(7.0), "RSU-1001-1"),
+                    share("2023-09-29", dec!(98.25), dec!(12.0), "ESPP"),
+                    share("2023-09-29", dec!(98.25), dec!(12.0), "ESPP"),
+                ],
+                actions: vec![],
+            },
+            Positions {
+                ticker: "NOVO".to_string(),
+                account: String::new(),
+                shares: vec![share("2023-11-02", dec!(710.0), dec!(5.0), "")],
+                actions: vec![],
+            },
+        ];
+        let previews = portfolio.preview_merge(&imported);
+        assert_eq!(previews.len(), 2);
+        let acme = &previews[0];
+        assert!(!acme.new_ticker);
+        assert_eq!(
+            acme.added,
+            vec![share("2023-09-29", dec!(98.25), dec!(12.0), "ESPP")]
+        );
+        assert_eq!(acme.duplicates.len(), 2);
+        assert_eq!(
+            acme.near_duplicates,
+            vec![NearDuplicate {
+                existing: share("2023-03-15", dec!(120.5), dec!(10.0), "RSU-1001-1"),
+                imported: share("2023-03-15", dec!(120.5), dec!(7.0), "RSU-1001-1"),
+            }]
+        );
+        assert!(previews[1].new_ticker);
+        assert_eq!(previews[1].added.len(), 1);
+
+        // The preview leaves the portfolio alone, merging skips near duplicates by default
+        assert_eq!(portfolio.stocks[0].shares.len(), 1);
+        let mut kept = portfolio.clone();
+        kept.merge_postions(&imported).unwrap();
+        assert_eq!(
+            kept.stocks[0].shares,
+            vec![
+                share("2023-03-15", dec!(120.5), dec!(10.0), "RSU-1001-1"),
+                share("2023-09-29", dec!(98.25), dec!(12.0), "ESPP"),
+            ]
+        );
+        assert_eq!(kept.stocks[1].shares.len(), 1);
+        assert!(
+            kept.preview_merge(&imported)
+                .iter()
+                .all(|p| p.added.is_empty())
+        );
+
+        let mut added = portfolio.clone();
+        added
+            .merge_resolved(&imported, |_| Resolution::Add)
+            .unwrap();
+        assert_eq!(added.stocks[0].shares.len(), 3);
+
+        portfolio
+            .merge_resolved(&imported, |_| Resolution::Replace)
+            .unwrap();
+        assert_eq!(
+            portfolio.stocks[0].shares,
+            vec![
+                share("2023-03-15", dec!(120.5), dec!(7.0), "RSU-1001-1"),
+                share("2023-09-29", dec!(98.25), dec!(12.0), "ESPP"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_by_id() {
+        let mut portfolio = Portfolio::new().unwrap();
+        portfolio
+            .new_stock(Positions {
+                ticker: "ACME".to_string(),
+                account: String::new(),
+                shares: vec![share("2023-03-15", dec!(120.5), dec!(10.0), "RSU-1001-1")],
+                actions: vec![],
+            })
+            .unwrap();
+        // Two ESPP purchases on the same day at the same price are different lots
+        let imported = vec![Positions {
+            ticker: "ACME".to_string(),
+            account: String::new(),
+            shares: vec![
+                lot(
+                    "ETRADE-RSU-1001-1",
+                    "2023-03-15",
+                    dec!(120.5),
+                    dec!(10.0),
+                    "RSU-1001-1",
+                ),
+                lot(
+                    "ETRADE-ESPP-2023-09-29",
+                    "2023-09-29",
+                    dec!(98.25),
+                    dec!(12.0),
+                    "ESPP",
+                ),
+                lot(
+                    "ETRADE-ESPP-2023-09-29-2",
+                    "2023-09-29",
+                    dec!(98.25),
+                    dec!(12.0),
+                    "ESPP",
+                ),
+            ],
+            actions: vec![],
+        }];
+        let preview = &portfolio.preview_merge(&imported)[0];
+        assert_eq!(preview.added.len(), 2);
+        assert_eq!(preview.duplicates.len(), 1);
+        portfolio.merge_postions(&imported).unwrap();
+        assert_eq!(portfolio.stocks[0].shares, imported[0].shares);
+        // Importing again changes nothing, an edited lot is still matched by its id
+        portfolio
+            .lot_mut("", "ACME", "ETRADE-ESPP-2023-09-29-2")
+            .unwrap()
+            .unit = dec!(11.5);
+        let preview = &portfolio.preview_merge(&imported)[0];
+        assert!(preview.added.is_empty());
+        assert_eq!(preview.duplicates.len(), 2);
+        assert_eq!(preview.near_duplicates.len(), 1);
+        portfolio.merge_postions(&imported).unwrap();
+        assert_eq!(portfolio.stocks[0].shares.len(), 3);
+        let mut added = portfolio.clone();
+        added
+            .merge_resolved(&imported, |_| Resolution::Add)
+            .unwrap();
+        assert_eq!(added.stocks[0].shares[3].id, "ETRADE-ESPP-2023-09-29-2-2");
+        // Ids are only unique within a position, the same id in another account is kept
+        let mut other = imported[0].clone();
+        other.account = "spouse".to_string();
+        portfolio.stocks.push(other);
+        let id = "ETRADE-ESPP-2023-09-29";
+        assert!(portfolio.lot_mut("", "BETA", id).is_none());
+        assert!(portfolio.remove_lot("", "ACME", id).is_some());
+        assert!(portfolio.remove_lot("", "ACME", id).is_none());
+        assert_eq!(portfolio.stocks[0].shares.len(), 2);
+        assert_eq!(portfolio.stocks[1].shares.len(), 3);
+    }
+
+    #[test]
+    fn test_accounts() {
+        let mut portfolio = Portfolio::new().unwrap();
+        let position = |account: &str, shares: Vec<Stock>| Positions {
+            ticker: "ACME".to_string(),
+            account: account.to_string(),
+            shares,
+            actions: vec![],
+        };
+        portfolio
+            .merge_postions(&[
+                position(
+                    "etrade",
+                    vec![share("2023-03-15", dec!(120.5), dec!(10.0), "")],
+                ),
+                position(
+                    "nordnet",
+                    vec![share("2023-09-29", dec!(98.25), dec!(4.0), "")],
+                ),
+            ])
+            .unwrap();
+        // The same lot in another account is not a duplicate
+        let previews = portfolio.preview_merge(&[position(
+            "nordnet",
+            vec![share("2023-03-15", dec!(120.5), dec!(10.0), "")],
+        )]);
+        assert_eq!(previews[0].added.len(), 1);
+        assert!(!previews[0].new_ticker);
+        assert_eq!(portfolio.account_view("etrade").stocks.len(), 1);
+        let consolidated = portfolio.consolidated();
+        assert_eq!(consolidated.stocks.len(), 1);
+        assert_eq!(consolidated.stocks[0].shares.len(), 2);
+        // Corporate actions apply to the ticker in every account
+        let ticker_change = CorporateAction {
+            date: chrono::NaiveDate::from_ymd_opt(2024, 1, 2).unwrap(),
+            kind: CorporateActionKind::TickerChange {
+                new_ticker: "ACMX".to_string(),
+            },
+            metadata: "".to_string(),
+        };
+        portfolio
+            .add_corporate_action("ACME", ticker_change)
+            .unwrap();
+        assert!(portfolio.stocks[..2].iter().all(|p| p.actions.len() == 1));
+        assert_eq!(portfolio.stocks.len(), 4);
+        assert_eq!(portfolio.consolidated().stocks[0].actions.len(), 1);
+    }
+
+    #[test]
+    fn test_json_numbers() {
+        // Stored portfolios keep plain JSON numbers and read back without rounding residue
+        let stock = share("2023-06-30", dec!(98.76), dec!(12.3456), "ESPP");
+        let json = serde_json::to_string(&stock).unwrap();
+        assert!(json.contains("\"unit\":12.3456"));
+        assert_eq!(serde_json::from_str::<Stock>(&json).unwrap(), stock);
+        // Prices computed on import, like taxable gain / units of an RSU, keep all digits
+        let rsu = share(
+            "2023-03-15",
+            dec!(617.28) / dec!(3.5),
+            dec!(3.5),
+            "RSU-1001-1",
+        );
+        let json = serde_json::to_string(&rsu).unwrap();
+        assert!(json.contains("\"price\":176.36571428571428571428571429"));
+        let reloaded = serde_json::from_str::<Stock>(&json).unwrap();
+        assert!(reloaded.same_values(&rsu));
+        assert_eq!(reloaded, rsu);
+    }
+
+    #[test]
+    fn test_add_corporate_action() {
+        let mut portfolio = Portfolio::new().unwrap();
+        portfolio
+            .new_stock(Positions {
+                ticker: "ACME".to_string(),
+                account: String::new(),
+                shares: vec![share("2023-03-15", dec!(120.5), dec!(10.0), "RSU-1001-1")],
+                actions: vec![],
+            })
+            .unwrap();
+        let spin_off = |cost_allocation: Decimal| CorporateAction {
+            date: chrono::NaiveDate::from_ymd_opt(2024, 4, 1).unwrap(),
+            kind: CorporateActionKind::SpinOff {
+                new_ticker: "SPIN".to_string(),
+                ratio: dec!(0.25),
+                cost_allocation,
+            },
+            metadata: "".to_string(),
+        };
+        assert!(
+            portfolio
+                .add_corporate_action("ACME", spin_off(dec!(1.5)))
+                .is_err()
+        );
+        assert!(
+            portfolio
+                .add_corporate_action("NONE", spin_off(dec!(0.1)))
+                .is_err()
+        );
+        for _ in 0..2 {
+            portfolio
+                .add_corporate_action("ACME", spin_off(dec!(0.1)))
+                .unwrap();
+        }
+        assert_eq!(portfolio.stocks[0].actions, vec![spin_off(dec!(0.1))]);
+        assert_eq!(portfolio.stocks[1].ticker, "SPIN");
+        assert!(portfolio.stocks[1].shares.is_empty());
+        let merger =
+            |ratio: Decimal, cash_per_share: Decimal, cost_allocation: Decimal| CorporateAction {
+                date: chrono::NaiveDate::from_ymd_opt(2024, 6, 3).unwrap(),
+                kind: CorporateActionKind::Merger {
+                    new_ticker: "BUYER".to_string(),
+                    ratio,
+                    cash_per_share,
+                    cost_allocation,
+                },
+                metadata: "".to_string(),
+            };
+        // Basis allocated to shares that are not issued or left without shares or cash
+        for invalid in [
+            merger(dec!(0), dec!(50), dec!(0.4)),
+            merger(dec!(0.5), dec!(0), dec!(0.6)),
+            merger(dec!(0), dec!(0), dec!(0)),
+        ] {
+            assert!(portfolio.add_corporate_action("ACME", invalid).is_err());
+        }
+        for valid in [
+            merger(dec!(0), dec!(50), dec!(0)),
+            merger(dec!(0.5), dec!(0), dec!(1)),
+            merger(dec!(0.5), dec!(20), dec!(0.6)),
+        ] {
+            assert!(portfolio.add_corporate_action("ACME", valid).is_ok());
+        }
     }
 }
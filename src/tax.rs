@@ -1,100 +1,529 @@
+use std::collections::BTreeMap;
 use std::io::Write;
 
-use crate::portfolio::{Action, Portfolio, Stock};
+use crate::fx::FxRates;
+use crate::money::round_money;
+use crate::portfolio::{Action, Portfolio};
 use chrono::Datelike;
-use log::{debug, info};
+use log::{error, info, warn};
+use rust_decimal::Decimal;
+
+mod lots;
+mod norway;
+mod us;
+
+pub use lots::{LOT_SELECTIONS, LotBook, LotSelection, RealizedLot};
+pub use norway::NorwegianTaxService;
+pub use us::UsTaxService;
 pub struct TaxCalculatorService {}
 static OUTPUT_FILE: &str = "output.csv";
+static DIVIDEND_OUTPUT_FILE: &str = "dividends.csv";
+/// Decimals of prices in the output, split adjusted prices and fees per share can have
+/// many more.
+const OUTPUT_PRICE_DECIMALS: u32 = 6;
+
+/// Gains or losses by tax year.
+pub type GainsByYear = BTreeMap<i32, Decimal>;
+
+/// Which lots a sale is matched against.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum TaxScope {
+    /// Lots held in the account of the sale, like the cost basis reported by US brokers.
+    PerAccount,
+    /// Lots held in any taxable account of the owner of the sale, as in Norway where shares
+    /// of the same company are sold in FIFO order across brokers. Tax-sheltered accounts
+    /// are matched per account in both scopes.
+    PerPerson,
+}
+
+/// Dividends of one ticker in one tax year and currency.
+#[derive(PartialEq, Debug, Clone)]
+pub struct DividendSummary {
+    pub year: i32,
+    pub ticker: String,
+    pub currency: String,
+    pub gross: Decimal,
+    pub withholding_tax: Decimal,
+}
+
 impl TaxCalculatorService {
     fn overwrite_to_output_file(content: &str) -> Result<(), std::io::Error> {
-        let filename = format!("{}/output/{}", env!("CARGO_MANIFEST_DIR"), OUTPUT_FILE);
+        Self::write_output_file(OUTPUT_FILE, content)
+    }
+    fn write_output_file(name: &str, content: &str) -> Result<(), std::io::Error> {
+        let filename = format!("{}/output/{}", env!("CARGO_MANIFEST_DIR"), name);
         let mut file = std::fs::File::create(filename)?;
         file.write_all(content.as_bytes())?;
-        info!("Writing output to {}", OUTPUT_FILE);
+        info!("Writing output to {}", name);
         Ok(())
     }
 
-    pub fn fifo_calculation(portfolio: &Portfolio) {
-        let mut output_string = String::new();
+    /// Sums dividends and withheld tax per tax year, ticker and currency. Dividends paid
+    /// before a ticker change count for the current ticker.
+    pub fn dividend_summary(portfolio: &Portfolio) -> Vec<DividendSummary> {
+        let mut summary: Vec<DividendSummary> = vec![];
         for ticker in portfolio.stocks.iter() {
-            info!("FIFO {}:", ticker.ticker);
-            let mut sell: Vec<Stock> = vec![];
-            let mut buy: Vec<Stock> = vec![];
             for stock in ticker.shares.iter() {
-                match stock.action {
-                    Action::Buy => buy.push(stock.clone()),
-                    Action::Sell => sell.push(stock.clone()),
+                let (gross, withholding_tax) = match stock.action {
+                    Action::Dividend => (stock.price * stock.unit, Decimal::ZERO),
+                    Action::WithholdingTax => (Decimal::ZERO, stock.price * stock.unit),
+                    Action::Buy | Action::Sell => continue,
+                };
+                let year = stock.date.year();
+                let current_ticker = portfolio.current_ticker(&ticker.ticker);
+                match summary.iter_mut().find(|s| {
+                    s.year == year && s.ticker == current_ticker && s.currency == stock.currency
+                }) {
+                    Some(entry) => {
+                        entry.gross += gross;
+                        entry.withholding_tax += withholding_tax;
+                    }
+                    None => summary.push(DividendSummary {
+                        year,
+                        ticker: current_ticker,
+                        currency: stock.currency.clone(),
+                        gross,
+                        withholding_tax,
+                    }),
                 }
             }
-            sell.sort_by_key(|k| k.date);
-            buy.sort_by(|a, b| b.date.cmp(&a.date)); // Reverse sort for FIFO function
-            let mut year = 0;
-            let mut last_year = 0;
-            let mut sum_shares_sold = 0.0;
-            let mut sum_gains_loss = 0.0;
-            output_string = (
-                "Ticker,Date,UnitsSold,GainOrLoss,BuyPrice,SellPrice,Diff,Profit,Currency,SellMetadata"
-            ).to_string();
-            for sell_stock in sell.iter_mut() {
-                year = sell_stock.date.year();
-                if last_year != year {
-                    info!("{}: {} {}", last_year, sum_shares_sold, sum_gains_loss);
-                    sum_gains_loss = 0.0;
-                    sum_shares_sold = 0.0;
+        }
+        for entry in summary.iter_mut() {
+            entry.gross = round_money(entry.gross, &entry.currency);
+            entry.withholding_tax = round_money(entry.withholding_tax, &entry.currency);
+        }
+        summary.sort_by(|a, b| {
+            (a.year, &a.ticker, &a.currency).cmp(&(b.year, &b.ticker, &b.currency))
+        });
+        summary
+    }
+
+    /// Writes the dividends of every tax year to `output/dividends.csv` and logs the totals.
+    pub fn dividend_report(portfolio: &Portfolio) {
+        let summary = Self::dividend_summary(portfolio);
+        let mut output_string = "Year,Ticker,Currency,Gross,WithholdingTax,Net".to_string();
+        for entry in summary.iter() {
+            info!(
+                "{} {} \t {} {} withheld {} net {}",
+                entry.year,
+                entry.ticker,
+                entry.currency,
+                entry.gross,
+                entry.withholding_tax,
+                entry.gross - entry.withholding_tax
+            );
+            output_string = format!(
+                "{}\n{},{},{},{},{},{}",
+                output_string,
+                entry.year,
+                entry.ticker,
+                entry.currency,
+                entry.gross,
+                entry.withholding_tax,
+                entry.gross - entry.withholding_tax
+            );
+        }
+        if let Err(err) = Self::write_output_file(DIVIDEND_OUTPUT_FILE, output_string.as_str()) {
+            error!("Failed to write {}: {}", DIVIDEND_OUTPUT_FILE, err);
+        }
+    }
+
+    /// Splits the portfolio into the groups of positions whose lots are matched together
+    /// in `scope`. Positions in the default account belong to an owner without a name.
+    pub fn tax_pools(portfolio: &Portfolio, scope: TaxScope) -> Vec<Portfolio> {
+        let mut pools: Vec<(String, Portfolio)> = vec![];
+        for position in portfolio.stocks.iter() {
+            let account = portfolio.account(&position.account);
+            let sheltered = account.is_some_and(|a| a.kind.is_tax_sheltered());
+            let key = match scope {
+                TaxScope::PerPerson if !sheltered => {
+                    format!("owner {}", account.map(|a| a.owner.as_str()).unwrap_or(""))
                 }
-                while sell_stock.unit > 0.0 {
-                    //debug! {"{:?}", sell_stock};
-                    let mut buy_pos = buy.pop().unwrap();
-                    let gains = sell_stock.price - buy_pos.price;
-
-                    let mut unit_sold = sell_stock.unit;
-                    let old_buy = buy_pos.unit;
-                    buy_pos.unit -= sell_stock.unit;
-                    sell_stock.unit -= old_buy;
-                    if buy_pos.currency != sell_stock.currency {
-                        todo!("Implement currency conversion logic.")
-                    }
+                _ => format!("account {}", position.account),
+            };
+            match pools.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, pool)) => pool.stocks.push(position.clone()),
+                None => pools.push((
+                    key.clone(),
+                    Portfolio {
+                        name: portfolio.name.clone(),
+                        description: key,
+                        accounts: portfolio.accounts.clone(),
+                        stocks: vec![position.clone()],
+                    },
+                )),
+            }
+        }
+        pools.into_iter().map(|(_, pool)| pool).collect()
+    }
 
-                    if buy_pos.unit < 0.0 {
-                        unit_sold = old_buy;
-                    }
-                    let g_lstring = if gains >= 0.0 { "gain" } else { "loss" };
-                    info!(
-                        "{} {} \t {} {} {} {} \t {} {}",
-                        sell_stock.date,
-                        unit_sold,
-                        g_lstring,
-                        buy_pos.price,
-                        sell_stock.price,
-                        gains,
-                        gains * unit_sold,
-                        buy_pos.currency
-                    );
-                    output_string = format!(
-                        "{}\n{},{},{},{},{},{},{},{},{},{}",
-                        output_string,
-                        ticker.ticker,
-                        sell_stock.date,
-                        unit_sold,
-                        g_lstring,
-                        buy_pos.price,
-                        sell_stock.price,
-                        gains,
-                        gains * unit_sold,
-                        buy_pos.currency,
-                        sell_stock.metadata
-                    );
-                    sum_gains_loss += gains * unit_sold;
-                    sum_shares_sold += unit_sold;
-                    if buy_pos.unit > 0.0 {
-                        buy.push(buy_pos);
+    /// Matches every sale with the lots held in its pool of `scope` in the order of
+    /// `selection`, after applying the corporate actions between the buy and the sale.
+    pub fn realize(
+        portfolio: &Portfolio,
+        scope: TaxScope,
+        selection: LotSelection,
+    ) -> Result<Vec<RealizedLot>, Box<dyn std::error::Error>> {
+        let mut realized = vec![];
+        for pool in Self::tax_pools(portfolio, scope) {
+            realized.extend_from_slice(LotBook::from_portfolio(&pool, selection)?.realized());
+        }
+        Ok(realized)
+    }
+
+    /// Splits realized lots into lots sold in taxable accounts and lots sold in
+    /// tax-sheltered accounts.
+    fn split_sheltered(
+        portfolio: &Portfolio,
+        realized: Vec<RealizedLot>,
+    ) -> (Vec<RealizedLot>, Vec<RealizedLot>) {
+        realized.into_iter().partition(|lot| {
+            !portfolio
+                .account(&lot.sell_account)
+                .is_some_and(|a| a.kind.is_tax_sheltered())
+        })
+    }
+
+    /// Realized lots with gains in `currency`, or in the currency they were traded in when
+    /// `currency` is `None`. Lots bought in another currency than they were sold in need a
+    /// reporting currency.
+    pub fn realize_in_currency(
+        portfolio: &Portfolio,
+        scope: TaxScope,
+        selection: LotSelection,
+        fx: &FxRates,
+        currency: Option<&str>,
+    ) -> Result<Vec<RealizedLot>, Box<dyn std::error::Error>> {
+        let realized = Self::realize(portfolio, scope, selection)?;
+        match currency {
+            Some(currency) => realized
+                .iter()
+                .map(|lot| lot.convert(fx, currency))
+                .collect(),
+            None => {
+                if let Some(lot) = realized.iter().find(|l| l.buy_currency != l.currency) {
+                    return Err(format!(
+                        "{} bought in {} and sold in {}, choose a reporting currency",
+                        lot.ticker, lot.buy_currency, lot.currency
+                    )
+                    .into());
+                }
+                Ok(realized)
+            }
+        }
+    }
+
+    /// Gains of sales in taxable accounts per tax year for every lot selection, to compare
+    /// the outcome of the methods.
+    pub fn compare_selections(
+        portfolio: &Portfolio,
+        scope: TaxScope,
+        fx: &FxRates,
+        currency: Option<&str>,
+    ) -> Result<Vec<(LotSelection, GainsByYear)>, Box<dyn std::error::Error>> {
+        let mut comparison = vec![];
+        for selection in LOT_SELECTIONS {
+            // HIFO and LOFO fail on lots bought in several currencies, the others still compare
+            let realized =
+                match Self::realize_in_currency(portfolio, scope, selection, fx, currency) {
+                    Ok(realized) => realized,
+                    Err(err) if selection != LotSelection::Fifo => {
+                        warn!("{:?} left out of the comparison: {}", selection, err);
+                        continue;
                     }
+                    Err(err) => return Err(err),
+                };
+            let mut gains = GainsByYear::new();
+            for lot in Self::split_sheltered(portfolio, realized).0 {
+                *gains.entry(lot.sell_date.year()).or_default() += lot.gain();
+            }
+            comparison.push((selection, gains));
+        }
+        Ok(comparison)
+    }
+
+    /// Logs the gains per tax year of every lot selection.
+    pub fn comparison_report(
+        portfolio: &Portfolio,
+        scope: TaxScope,
+        fx: &FxRates,
+        currency: Option<&str>,
+    ) {
+        let comparison = match Self::compare_selections(portfolio, scope, fx, currency) {
+            Ok(comparison) => comparison,
+            Err(err) => {
+                error!("Comparing lot selections failed: {}", err);
+                return;
+            }
+        };
+        let mut years: Vec<i32> = comparison
+            .iter()
+            .flat_map(|(_, gains)| gains.keys().copied())
+            .collect();
+        years.sort();
+        years.dedup();
+        for year in years {
+            for (selection, gains) in comparison.iter() {
+                info!(
+                    "{} {:?}: \t {}",
+                    year,
+                    selection,
+                    gains.get(&year).copied().unwrap_or_default()
+                );
+            }
+        }
+    }
+
+    /// Writes the realized gains of sales in taxable accounts to `output/output.csv` and logs
+    /// the totals per ticker and year, converted to `currency` when it is given. Sales take
+    /// their units from the lots in the order of `selection`.
+    pub fn gains_calculation(
+        portfolio: &Portfolio,
+        scope: TaxScope,
+        selection: LotSelection,
+        fx: &FxRates,
+        currency: Option<&str>,
+    ) {
+        info!("Matching sales with lots by {:?}", selection);
+        let realized = match Self::realize_in_currency(portfolio, scope, selection, fx, currency) {
+            Ok(realized) => realized,
+            Err(err) => {
+                error!("Gains calculation failed: {}", err);
+                return;
+            }
+        };
+        let (realized, sheltered) = Self::split_sheltered(portfolio, realized);
+        if !sheltered.is_empty() {
+            info!(
+                "{} lots sold in tax-sheltered accounts are not reported",
+                sheltered.len()
+            );
+        }
+        let mut output_string = (
+            "Ticker,Date,UnitsSold,GainOrLoss,BuyPrice,SellPrice,Diff,Profit,Currency,SellMetadata,BuyDate,OriginalUnits,OriginalBuyPrice,BuyMetadata,Proceeds,CostBasis,BuyId,SellId,BuyAccount,SellAccount"
+        ).to_string();
+        // Units sold and gains by ticker and year
+        let mut sums: Vec<(String, i32, Decimal, Decimal)> = vec![];
+        for lot in realized.iter() {
+            let gains = (lot.sell_price - lot.buy_price).round_dp(OUTPUT_PRICE_DECIMALS);
+            let buy_price = lot.buy_price.round_dp(OUTPUT_PRICE_DECIMALS);
+            let sell_price = lot.sell_price.round_dp(OUTPUT_PRICE_DECIMALS);
+            let g_lstring = if gains >= Decimal::ZERO {
+                "gain"
+            } else {
+                "loss"
+            };
+            info!(
+                "{} {} {} \t {} {} {} {} \t {} {}",
+                lot.ticker,
+                lot.sell_date,
+                lot.units,
+                g_lstring,
+                buy_price,
+                sell_price,
+                gains,
+                lot.gain(),
+                lot.currency
+            );
+            output_string = format!(
+                "{}\n{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                output_string,
+                lot.ticker,
+                lot.sell_date,
+                lot.units,
+                g_lstring,
+                buy_price,
+                sell_price,
+                gains,
+                lot.gain(),
+                lot.currency,
+                lot.sell_metadata,
+                lot.buy_date,
+                lot.original_units,
+                lot.original_buy_price.round_dp(OUTPUT_PRICE_DECIMALS),
+                lot.buy_metadata,
+                lot.proceeds(),
+                lot.cost_basis(),
+                lot.buy_id,
+                lot.sell_id,
+                lot.buy_account,
+                lot.sell_account
+            );
+            let year = lot.sell_date.year();
+            match sums
+                .iter_mut()
+                .find(|(ticker, y, _, _)| *ticker == lot.ticker && *y == year)
+            {
+                Some(sum) => {
+                    sum.2 += lot.units;
+                    sum.3 += lot.gain();
                 }
-                last_year = year;
+                None => sums.push((lot.ticker.clone(), year, lot.units, lot.gain())),
             }
-            info!("{}: {} {}", year, sum_shares_sold, sum_gains_loss);
-            debug!("{:?}", buy.last());
-            Self::overwrite_to_output_file(output_string.as_str());
         }
+        for (ticker, year, units, gains) in sums.iter() {
+            info!("{} {}: {} {}", ticker, year, units, gains);
+        }
+        if let Err(err) = Self::overwrite_to_output_file(output_string.as_str()) {
+            error!("Failed to write {}: {}", OUTPUT_FILE, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_tax_scope() {
+        let test_portfolio = format!(
+            "{}/test_files/test_portfolio_accounts.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let portfolio: Portfolio =
+            serde_json::from_str(&std::fs::read_to_string(test_portfolio).unwrap()).unwrap();
+        let sale = |scope: TaxScope, account: &str| {
+            let realized =
+                TaxCalculatorService::realize(&portfolio, scope, LotSelection::Fifo).unwrap();
+            let mut lots = realized.into_iter().filter(|l| l.sell_account == account);
+            let lot = lots.next().unwrap();
+            assert!(lots.next().is_none());
+            lot
+        };
+        // Per account the Nordnet sale takes the Nordnet lot
+        let lot = sale(TaxScope::PerAccount, "nordnet");
+        assert_eq!(
+            (lot.buy_account.as_str(), lot.gain()),
+            ("nordnet", dec!(500))
+        );
+        // Per person it takes the older E*Trade lot of the same owner
+        let lot = sale(TaxScope::PerPerson, "nordnet");
+        assert_eq!(lot.buy_id, "ETRADE-RSU-1001-1");
+        assert_eq!(
+            (lot.buy_account.as_str(), lot.gain()),
+            ("etrade", dec!(1000))
+        );
+        // The ASK is matched on its own in both scopes
+        let lot = sale(TaxScope::PerPerson, "ask");
+        assert_eq!((lot.buy_account.as_str(), lot.gain()), ("ask", dec!(600)));
+    }
+
+    #[test]
+    fn test_compare_selections() {
+        let test_portfolio = format!(
+            "{}/test_files/test_portfolio_accounts.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let portfolio: Portfolio =
+            serde_json::from_str(&std::fs::read_to_string(test_portfolio).unwrap()).unwrap();
+        let comparison = TaxCalculatorService::compare_selections(
+            &portfolio,
+            TaxScope::PerPerson,
+            &FxRates::new(),
+            None,
+        )
+        .unwrap();
+        // The Nordnet sale takes the E*Trade lot bought at 100 or its own lot bought at 200,
+        // the ASK sale is left out
+        let gains: Vec<(LotSelection, Decimal)> = comparison
+            .iter()
+            .map(|(selection, gains)| (*selection, gains[&2023]))
+            .collect();
+        assert_eq!(
+            gains,
+            vec![
+                (LotSelection::Fifo, dec!(1000)),
+                (LotSelection::Lifo, dec!(500)),
+                (LotSelection::Hifo, dec!(500)),
+                (LotSelection::Lofo, dec!(1000)),
+                (LotSelection::SpecificId, dec!(1000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reporting_currency() {
+        let test_portfolio = format!(
+            "{}/test_files/test_portfolio_fx.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let portfolio: Portfolio =
+            serde_json::from_str(&std::fs::read_to_string(test_portfolio).unwrap()).unwrap();
+        let mut fx = FxRates::new();
+        fx.load_file(&format!(
+            "{}/test_files/fx/norges_bank.csv",
+            env!("CARGO_MANIFEST_DIR")
+        ))
+        .unwrap();
+        // BETA is bought in USD and sold in NOK
+        assert!(
+            TaxCalculatorService::realize_in_currency(
+                &portfolio,
+                TaxScope::PerPerson,
+                LotSelection::Fifo,
+                &fx,
+                None
+            )
+            .is_err()
+        );
+        let realized = TaxCalculatorService::realize_in_currency(
+            &portfolio,
+            TaxScope::PerPerson,
+            LotSelection::Fifo,
+            &fx,
+            Some("NOK"),
+        )
+        .unwrap();
+        let gains: Vec<(&str, &str, Decimal)> = realized
+            .iter()
+            .map(|l| (l.ticker.as_str(), l.currency.as_str(), l.gain()))
+            .collect();
+        // Bought at 10.2677 NOK per USD, ACME sold at 10.7260
+        assert_eq!(
+            gains,
+            vec![
+                ("ACME", "NOK", dec!(1530.90)),
+                ("BETA", "NOK", dec!(732.30))
+            ]
+        );
+        assert!(
+            TaxCalculatorService::realize_in_currency(
+                &portfolio,
+                TaxScope::PerPerson,
+                LotSelection::Fifo,
+                &FxRates::new(),
+                Some("NOK")
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_dividend_summary() {
+        let test_portfolio = format!(
+            "{}/test_files/test_portfolio_nordnet.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let portfolio: Portfolio =
+            serde_json::from_str(&std::fs::read_to_string(test_portfolio).unwrap()).unwrap();
+        let summary = TaxCalculatorService::dividend_summary(&portfolio);
+        let entry = |ticker: &str, currency: &str, gross: Decimal, withholding_tax: Decimal| {
+            DividendSummary {
+                year: 2024,
+                ticker: ticker.to_string(),
+                currency: currency.to_string(),
+                gross,
+                withholding_tax,
+            }
+        };
+        assert_eq!(
+            summary,
+            vec![
+                entry("AAPL", "USD", dec!(0.75), dec!(0.11)),
+                entry("EQNR", "NOK", dec!(72.0), dec!(0.0)),
+            ]
+        );
     }
 }
@@ -0,0 +1,806 @@
+use std::collections::{HashMap, VecDeque};
+
+use log::{debug, info};
+use rust_decimal::Decimal;
+
+use crate::fx::FxRates;
+use crate::money::round_money;
+use crate::portfolio::{
+    Action, CorporateAction, CorporateActionKind, EsppPurchase, Portfolio, Stock,
+};
+
+/// Fees of a trade spread over its units.
+fn fee_per_unit(stock: &Stock) -> Decimal {
+    if stock.unit.is_zero() {
+        Decimal::ZERO
+    } else {
+        stock.fee / stock.unit
+    }
+}
+
+/// A buy that is still held, with units and price adjusted for the corporate actions since
+/// the buy.
+#[derive(Debug, Clone)]
+pub struct OpenLot {
+    pub buy: Stock,
+    /// Account holding the lot.
+    pub account: String,
+    pub unit: Decimal,
+    pub price: Decimal,
+    /// The units still held counted in shares at the time of the buy.
+    pub original_unit: Decimal,
+    /// Cost basis per share bought, less the basis moved to spin-offs.
+    pub original_price: Decimal,
+}
+
+/// Part of a buy matched with a sale. Buy prices include the fees of the buy and sell
+/// prices are net of the fees of the sale.
+#[derive(PartialEq, Debug, Clone)]
+pub struct RealizedLot {
+    pub ticker: String,
+    pub buy_date: chrono::NaiveDate,
+    pub sell_date: chrono::NaiveDate,
+    /// Units sold, counted in shares at the time of the sale.
+    pub units: Decimal,
+    /// The units sold counted in shares at the time of the buy.
+    pub original_units: Decimal,
+    /// Buy price per share at the time of the sale, adjusted for splits and the basis moved
+    /// to spin-offs.
+    pub buy_price: Decimal,
+    /// Cost basis per share at the time of the buy, `original_units * original_buy_price`
+    /// is the same basis as `units * buy_price`.
+    pub original_buy_price: Decimal,
+    pub sell_price: Decimal,
+    /// Currency of the sell price.
+    pub currency: String,
+    /// Currency of the buy prices, lots bought in another currency than they are sold in
+    /// have to be converted with [`RealizedLot::convert`] before gains can be computed.
+    pub buy_currency: String,
+    pub buy_metadata: String,
+    pub sell_metadata: String,
+    /// `Stock::id` of the buy the units were taken from.
+    pub buy_id: String,
+    /// `Stock::id` of the sell, empty for the cash paid in a merger.
+    pub sell_id: String,
+    /// Accounts of the buy and the sale, they differ when lots are matched per person.
+    pub buy_account: String,
+    pub sell_account: String,
+    /// ESPP terms of the buy, per share at the time of the buy.
+    pub espp: Option<EsppPurchase>,
+}
+
+impl RealizedLot {
+    /// Proceeds of the units sold, rounded to the smallest unit of the currency.
+    pub fn proceeds(&self) -> Decimal {
+        round_money(self.units * self.sell_price, &self.currency)
+    }
+    /// Cost basis of the units sold, rounded to the smallest unit of the currency.
+    pub fn cost_basis(&self) -> Decimal {
+        round_money(self.units * self.buy_price, &self.buy_currency)
+    }
+    /// Gain or loss of the rounded proceeds and cost basis, so sums of gains reconcile with
+    /// broker statements listing rounded amounts per lot.
+    pub fn gain(&self) -> Decimal {
+        self.proceeds() - self.cost_basis()
+    }
+    /// The lot in `currency`, with the buy prices converted at the rate of the buy date and
+    /// the sell price at the rate of the sell date.
+    pub fn convert(
+        &self,
+        fx: &FxRates,
+        currency: &str,
+    ) -> Result<RealizedLot, Box<dyn std::error::Error>> {
+        let buy_rate = fx.rate(self.buy_date, &self.buy_currency, currency)?;
+        let sell_rate = fx.rate(self.sell_date, &self.currency, currency)?;
+        Ok(RealizedLot {
+            buy_price: self.buy_price * buy_rate,
+            original_buy_price: self.original_buy_price * buy_rate,
+            sell_price: self.sell_price * sell_rate,
+            currency: currency.to_string(),
+            buy_currency: currency.to_string(),
+            espp: self.espp.as_ref().map(|espp| EsppPurchase {
+                purchase_price: espp.purchase_price * buy_rate,
+                grant_fmv: espp.grant_fmv * buy_rate,
+                purchase_fmv: espp.purchase_fmv * buy_rate,
+                ..espp.clone()
+            }),
+            ..self.clone()
+        })
+    }
+}
+
+enum EventKind<'a> {
+    CorporateAction(&'a CorporateAction),
+    Buy(&'a Stock),
+    Sell(&'a Stock),
+}
+
+struct Event<'a> {
+    date: chrono::NaiveDate,
+    account: &'a str,
+    ticker: &'a str,
+    kind: EventKind<'a>,
+}
+
+impl Event<'_> {
+    /// Corporate actions are effective from the start of their date, so on the same date
+    /// they come before trades and buys come before sells.
+    fn order(&self) -> u8 {
+        match self.kind {
+            EventKind::CorporateAction(_) => 0,
+            EventKind::Buy(_) => 1,
+            EventKind::Sell(_) => 2,
+        }
+    }
+}
+
+/// Which open lot a sale takes its units from.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum LotSelection {
+    /// Oldest lot first.
+    Fifo,
+    /// Newest lot first.
+    Lifo,
+    /// Lot with the highest cost per share first, the oldest of equal lots.
+    Hifo,
+    /// Lot with the lowest cost per share first, the oldest of equal lots.
+    Lofo,
+    /// The lots whose ids are listed in `Stock::lots` of the sale, in the listed order,
+    /// then the oldest lots.
+    SpecificId,
+}
+
+pub static LOT_SELECTIONS: [LotSelection; 5] = [
+    LotSelection::Fifo,
+    LotSelection::Lifo,
+    LotSelection::Hifo,
+    LotSelection::Lofo,
+    LotSelection::SpecificId,
+];
+
+impl LotSelection {
+    /// Index in `queue` of the lot `sell` takes units from next, `queue` is ordered by buy
+    /// date.
+    fn pick(&self, queue: &VecDeque<OpenLot>, sell: &Stock) -> Option<usize> {
+        let oldest = if queue.is_empty() { None } else { Some(0) };
+        match self {
+            LotSelection::Fifo => oldest,
+            LotSelection::Lifo => queue.len().checked_sub(1),
+            // min_by returns the first of equal lots, the oldest
+            LotSelection::Hifo => queue
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| b.price.cmp(&a.price))
+                .map(|(i, _)| i),
+            LotSelection::Lofo => queue
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.price.cmp(&b.price))
+                .map(|(i, _)| i),
+            LotSelection::SpecificId => sell
+                .lots
+                .iter()
+                .find_map(|id| queue.iter().position(|lot| lot.buy.id == *id))
+                .or(oldest),
+        }
+    }
+}
+
+impl std::str::FromStr for LotSelection {
+    type Err = String;
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        LOT_SELECTIONS
+            .iter()
+            .find(|s| format!("{:?}", s).eq_ignore_ascii_case(name.replace('-', "").as_str()))
+            .copied()
+            .ok_or_else(|| {
+                format!(
+                    "Unknown lot selection {}, use fifo, lifo, hifo, lofo or specific-id",
+                    name
+                )
+            })
+    }
+}
+
+/// Open lots of every ticker, matched with sales in the order of a [`LotSelection`] while
+/// the trades and corporate actions of a portfolio are replayed in date order.
+pub struct LotBook {
+    queues: HashMap<String, VecDeque<OpenLot>>,
+    realized: Vec<RealizedLot>,
+    selection: LotSelection,
+}
+
+impl LotBook {
+    pub fn new(selection: LotSelection) -> Self {
+        LotBook {
+            queues: HashMap::new(),
+            realized: vec![],
+            selection,
+        }
+    }
+    /// Replays all trades and corporate actions of `portfolio`. Positions of the same ticker
+    /// in several accounts share one queue, a corporate action listed by several of them is
+    /// applied once.
+    pub fn from_portfolio(
+        portfolio: &Portfolio,
+        selection: LotSelection,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_portfolio_until(portfolio, selection, chrono::NaiveDate::MAX)
+    }
+    /// Replays the trades and corporate actions of `portfolio` up to and including `until`,
+    /// the open lots are the lots held at the end of that day.
+    pub fn from_portfolio_until(
+        portfolio: &Portfolio,
+        selection: LotSelection,
+        until: chrono::NaiveDate,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut events = vec![];
+        let mut actions: Vec<(&str, &CorporateAction)> = vec![];
+        for position in portfolio.stocks.iter() {
+            for stock in position.shares.iter() {
+                let kind = match stock.action {
+                    Action::Buy => EventKind::Buy(stock),
+                    Action::Sell => EventKind::Sell(stock),
+                    Action::Dividend | Action::WithholdingTax => continue,
+                };
+                events.push(Event {
+                    date: stock.date,
+                    account: position.account.as_str(),
+                    ticker: position.ticker.as_str(),
+                    kind,
+                });
+            }
+            for action in position.actions.iter() {
+                if actions.contains(&(position.ticker.as_str(), action)) {
+                    continue;
+                }
+                actions.push((position.ticker.as_str(), action));
+                events.push(Event {
+                    date: action.date,
+                    account: position.account.as_str(),
+                    ticker: position.ticker.as_str(),
+                    kind: EventKind::CorporateAction(action),
+                });
+            }
+        }
+        events.retain(|e| e.date <= until);
+        events.sort_by_key(|e| (e.date, e.order()));
+        let mut book = LotBook::new(selection);
+        for event in events.iter() {
+            book.apply(event)?;
+        }
+        Ok(book)
+    }
+    fn apply(&mut self, event: &Event) -> Result<(), Box<dyn std::error::Error>> {
+        match event.kind {
+            EventKind::Buy(stock) => {
+                self.queues
+                    .entry(event.ticker.to_string())
+                    .or_default()
+                    .push_back(OpenLot {
+                        buy: stock.clone(),
+                        account: event.account.to_string(),
+                        unit: stock.unit,
+                        price: stock.price + fee_per_unit(stock),
+                        original_unit: stock.unit,
+                        original_price: stock.price + fee_per_unit(stock),
+                    });
+            }
+            EventKind::Sell(stock) => self.sell(event.ticker, event.account, stock)?,
+            EventKind::CorporateAction(action) => match &action.kind {
+                CorporateActionKind::Split {
+                    numerator,
+                    denominator,
+                } => {
+                    info!(
+                        "{} split {} on {}",
+                        event.ticker,
+                        action.split_ratio(),
+                        action.date
+                    );
+                    for lot in self.queues.entry(event.ticker.to_string()).or_default() {
+                        lot.unit = lot.unit * numerator / denominator;
+                        lot.price = lot.price * denominator / numerator;
+                    }
+                }
+                CorporateActionKind::TickerChange { new_ticker } => {
+                    info!(
+                        "{} renamed to {} on {}",
+                        event.ticker, new_ticker, action.date
+                    );
+                    let lots = self.queues.remove(event.ticker).unwrap_or_default();
+                    self.insert_lots(new_ticker, lots);
+                }
+                CorporateActionKind::CusipChange { .. } => {
+                    debug!("{} {:?}", event.ticker, action.kind);
+                }
+                CorporateActionKind::SpinOff {
+                    new_ticker,
+                    ratio,
+                    cost_allocation,
+                } => {
+                    info!(
+                        "{} spun off {} {} per share on {}",
+                        event.ticker, ratio, new_ticker, action.date
+                    );
+                    let mut spun_off = vec![];
+                    for lot in self.queues.entry(event.ticker.to_string()).or_default() {
+                        spun_off.push(OpenLot {
+                            buy: lot.buy.clone(),
+                            account: lot.account.clone(),
+                            unit: lot.unit * ratio,
+                            price: lot.price * cost_allocation / ratio,
+                            original_unit: lot.original_unit,
+                            original_price: lot.original_price * cost_allocation,
+                        });
+                        lot.price *= Decimal::ONE - cost_allocation;
+                        lot.original_price *= Decimal::ONE - cost_allocation;
+                    }
+                    self.insert_lots(new_ticker, spun_off);
+                }
+                CorporateActionKind::Merger {
+                    new_ticker,
+                    ratio,
+                    cash_per_share,
+                    cost_allocation,
+                } => {
+                    info!(
+                        "{} merged into {} for {} shares and {} cash per share on {}",
+                        event.ticker, new_ticker, ratio, cash_per_share, action.date
+                    );
+                    let lots = self.queues.remove(event.ticker).unwrap_or_default();
+                    let mut converted = vec![];
+                    for lot in lots {
+                        if *cash_per_share > Decimal::ZERO {
+                            // The cash pays for the part of the basis not moving to the new shares
+                            self.realized.push(RealizedLot {
+                                ticker: event.ticker.to_string(),
+                                buy_date: lot.buy.date,
+                                sell_date: action.date,
+                                units: lot.unit,
+                                original_units: lot.original_unit,
+                                buy_price: lot.price * (Decimal::ONE - cost_allocation),
+                                original_buy_price: lot.original_price
+                                    * (Decimal::ONE - cost_allocation),
+                                sell_price: *cash_per_share,
+                                currency: lot.buy.currency.clone(),
+                                buy_currency: lot.buy.currency.clone(),
+                                buy_metadata: lot.buy.metadata.clone(),
+                                sell_metadata: action.metadata.clone(),
+                                buy_id: lot.buy.id.clone(),
+                                sell_id: String::new(),
+                                buy_account: lot.account.clone(),
+                                sell_account: lot.account.clone(),
+                                espp: lot.buy.espp.clone(),
+                            });
+                        }
+                        if *ratio > Decimal::ZERO {
+                            converted.push(OpenLot {
+                                unit: lot.unit * ratio,
+                                price: lot.price * cost_allocation / ratio,
+                                original_unit: lot.original_unit,
+                                original_price: lot.original_price * cost_allocation,
+                                buy: lot.buy,
+                                account: lot.account,
+                            });
+                        }
+                    }
+                    self.insert_lots(new_ticker, converted);
+                }
+            },
+        }
+        Ok(())
+    }
+    /// Adds lots moved from another ticker, keeping the queue ordered by acquisition date.
+    fn insert_lots(&mut self, ticker: &str, lots: impl IntoIterator<Item = OpenLot>) {
+        let queue = self.queues.entry(ticker.to_string()).or_default();
+        queue.extend(lots);
+        queue.make_contiguous().sort_by_key(|lot| lot.buy.date);
+    }
+    fn sell(
+        &mut self,
+        ticker: &str,
+        account: &str,
+        sell: &Stock,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let queue = self.queues.entry(ticker.to_string()).or_default();
+        // Prices of lots bought in different currencies can not be compared
+        if matches!(self.selection, LotSelection::Hifo | LotSelection::Lofo)
+            && let Some(first) = queue.front()
+            && queue
+                .iter()
+                .any(|lot| lot.buy.currency != first.buy.currency)
+        {
+            return Err(format!(
+                "{:?} can not order the {} lots sold on {}, they were bought in different currencies",
+                self.selection, ticker, sell.date
+            )
+            .into());
+        }
+        let mut remaining = sell.unit;
+        while remaining > Decimal::ZERO {
+            let index = self.selection.pick(queue, sell);
+            let lot = match index.and_then(|i| queue.get_mut(i)) {
+                Some(lot) => lot,
+                None => {
+                    return Err(format!(
+                        "Sold {} {} on {} but only {} were held",
+                        sell.unit,
+                        ticker,
+                        sell.date,
+                        sell.unit - remaining
+                    )
+                    .into());
+                }
+            };
+            let units = remaining.min(lot.unit);
+            let original_units = if units == lot.unit {
+                lot.original_unit
+            } else {
+                lot.original_unit * units / lot.unit
+            };
+            self.realized.push(RealizedLot {
+                ticker: ticker.to_string(),
+                buy_date: lot.buy.date,
+                sell_date: sell.date,
+                units,
+                original_units,
+                buy_price: lot.price,
+                original_buy_price: lot.original_price,
+                sell_price: sell.price - fee_per_unit(sell),
+                currency: sell.currency.clone(),
+                buy_currency: lot.buy.currency.clone(),
+                buy_metadata: lot.buy.metadata.clone(),
+                sell_metadata: sell.metadata.clone(),
+                buy_id: lot.buy.id.clone(),
+                sell_id: sell.id.clone(),
+                buy_account: lot.account.clone(),
+                sell_account: account.to_string(),
+                espp: lot.buy.espp.clone(),
+            });
+            lot.unit -= units;
+            lot.original_unit -= original_units;
+            remaining -= units;
+            if lot.unit.is_zero()
+                && let Some(index) = index
+            {
+                queue.remove(index);
+            }
+        }
+        Ok(())
+    }
+    pub fn realized(&self) -> &[RealizedLot] {
+        &self.realized
+    }
+    /// Tickers with lots still held, sorted by name.
+    pub fn tickers(&self) -> Vec<&str> {
+        let mut tickers: Vec<&str> = self
+            .queues
+            .iter()
+            .filter(|(_, queue)| !queue.is_empty())
+            .map(|(ticker, _)| ticker.as_str())
+            .collect();
+        tickers.sort();
+        tickers
+    }
+    /// Lots of `ticker` still held, oldest first.
+    pub fn open_lots(&self, ticker: &str) -> Vec<&OpenLot> {
+        self.queues
+            .get(ticker)
+            .map(|q| q.iter().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::portfolio::Positions;
+
+    use super::*;
+
+    fn stock(date: &str, action: Action, unit: Decimal, price: Decimal) -> Stock {
+        Stock {
+            id: format!("{}-{:?}", date, action),
+            date: chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap(),
+            price,
+            currency: "USD".to_string(),
+            unit,
+            action,
+            metadata: "".to_string(),
+            fee: Decimal::ZERO,
+            espp: None,
+            lots: vec![],
+        }
+    }
+
+    fn corporate_action(date: &str, kind: CorporateActionKind) -> CorporateAction {
+        CorporateAction {
+            date: chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap(),
+            kind,
+            metadata: "".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_split_and_ticker_change() {
+        let mut portfolio = Portfolio::new().unwrap();
+        portfolio.stocks = vec![
+            Positions {
+                ticker: "OLD".to_string(),
+                account: String::new(),
+                shares: vec![
+                    stock("2020-01-10", Action::Buy, dec!(10.0), dec!(400.0)),
+                    stock("2020-06-01", Action::Buy, dec!(5.0), dec!(500.0)),
+                ],
+                actions: vec![
+                    corporate_action(
+                        "2020-08-31",
+                        CorporateActionKind::Split {
+                            numerator: dec!(4.0),
+                            denominator: dec!(1.0),
+                        },
+                    ),
+                    corporate_action(
+                        "2021-01-04",
+                        CorporateActionKind::TickerChange {
+                            new_ticker: "NEW".to_string(),
+                        },
+                    ),
+                ],
+            },
+            Positions {
+                ticker: "NEW".to_string(),
+                account: String::new(),
+                shares: vec![
+                    stock("2021-02-01", Action::Buy, dec!(8.0), dec!(130.0)),
+                    // 40 shares of the first lot and 10 of the second
+                    stock("2021-03-01", Action::Sell, dec!(50.0), dec!(150.0)),
+                ],
+                actions: vec![],
+            },
+        ];
+        let book = LotBook::from_portfolio(&portfolio, LotSelection::Fifo).unwrap();
+        let realized = book.realized();
+        assert_eq!(realized.len(), 2);
+        assert_eq!(realized[0].ticker, "NEW");
+        assert_eq!(realized[0].units, dec!(40.0));
+        assert_eq!(realized[0].original_units, dec!(10.0));
+        assert_eq!(realized[0].buy_price, dec!(100.0));
+        assert_eq!(realized[0].original_buy_price, dec!(400.0));
+        assert_eq!(realized[0].gain(), dec!(2000.0));
+        assert_eq!(realized[1].units, dec!(10.0));
+        assert_eq!(realized[1].original_units, dec!(2.5));
+        assert_eq!(realized[1].buy_price, dec!(125.0));
+        let open = book.open_lots("NEW");
+        assert_eq!(open.len(), 2);
+        assert_eq!(open[0].unit, dec!(10.0));
+        assert_eq!(open[1].unit, dec!(8.0));
+        assert!(book.open_lots("OLD").is_empty());
+    }
+
+    #[test]
+    fn test_spin_off_and_merger() {
+        let mut portfolio = Portfolio::new().unwrap();
+        portfolio.stocks = vec![
+            Positions {
+                ticker: "PARENT".to_string(),
+                account: String::new(),
+                shares: vec![
+                    stock("2019-05-02", Action::Buy, dec!(10.0), dec!(100.0)),
+                    stock("2023-01-10", Action::Sell, dec!(5.0), dec!(120.0)),
+                ],
+                actions: vec![],
+            },
+            Positions {
+                ticker: "TARGET".to_string(),
+                account: String::new(),
+                shares: vec![stock("2021-03-01", Action::Buy, dec!(4.0), dec!(50.0))],
+                actions: vec![],
+            },
+        ];
+        portfolio
+            .add_corporate_action(
+                "PARENT",
+                corporate_action(
+                    "2022-04-01",
+                    CorporateActionKind::SpinOff {
+                        new_ticker: "CHILD".to_string(),
+                        ratio: dec!(0.5),
+                        cost_allocation: dec!(0.2),
+                    },
+                ),
+            )
+            .unwrap();
+        portfolio
+            .add_corporate_action(
+                "TARGET",
+                corporate_action(
+                    "2022-06-01",
+                    CorporateActionKind::Merger {
+                        new_ticker: "PARENT".to_string(),
+                        ratio: dec!(0.5),
+                        cash_per_share: dec!(30.0),
+                        cost_allocation: dec!(0.6),
+                    },
+                ),
+            )
+            .unwrap();
+        // The spin-off creates the position of the new shares
+        let child = portfolio.stocks.iter_mut().find(|p| p.ticker == "CHILD");
+        child
+            .unwrap()
+            .shares
+            .push(stock("2023-02-01", Action::Sell, dec!(5.0), dec!(30.0)));
+        assert_eq!(portfolio.current_ticker("TARGET"), "PARENT");
+
+        let book = LotBook::from_portfolio(&portfolio, LotSelection::Fifo).unwrap();
+        let realized = book.realized();
+        assert_eq!(realized.len(), 3);
+        // Cash of the merger for the part of the basis not moved to PARENT
+        assert_eq!(realized[0].ticker, "TARGET");
+        assert_eq!(realized[0].sell_date.to_string(), "2022-06-01");
+        assert_eq!(realized[0].units, dec!(4.0));
+        assert_eq!(realized[0].buy_price, dec!(20.0));
+        assert_eq!(realized[0].gain(), dec!(40.0));
+        // The oldest PARENT lot is sold first, with 80% of its basis
+        assert_eq!(realized[1].ticker, "PARENT");
+        assert_eq!(realized[1].buy_date.to_string(), "2019-05-02");
+        assert_eq!(realized[1].buy_price, dec!(80.0));
+        assert_eq!(realized[2].ticker, "CHILD");
+        assert_eq!(realized[2].buy_date.to_string(), "2019-05-02");
+        assert_eq!(realized[2].units, dec!(5.0));
+        assert_eq!(realized[2].original_units, dec!(10.0));
+        assert_eq!(realized[2].buy_price, dec!(40.0));
+        assert_eq!(realized[2].original_buy_price, dec!(20.0));
+        assert_eq!(
+            realized.iter().map(|r| r.cost_basis()).sum::<Decimal>(),
+            dec!(680)
+        );
+        let open = book.open_lots("PARENT");
+        assert_eq!(open.len(), 2);
+        assert_eq!(open[0].unit, dec!(5.0));
+        assert_eq!(open[1].buy.date.to_string(), "2021-03-01");
+        assert_eq!(open[1].unit, dec!(2.0));
+        assert_eq!(open[1].price, dec!(60.0));
+    }
+
+    #[test]
+    fn test_fees() {
+        let mut portfolio = Portfolio::new().unwrap();
+        let mut buy = stock("2023-01-10", Action::Buy, dec!(10.0), dec!(100.0));
+        buy.fee = dec!(5.0);
+        let mut sell = stock("2023-06-01", Action::Sell, dec!(4.0), dec!(110.0));
+        sell.fee = dec!(2.0);
+        portfolio.stocks = vec![Positions {
+            ticker: "ACME".to_string(),
+            account: String::new(),
+            shares: vec![buy, sell],
+            actions: vec![],
+        }];
+        let book = LotBook::from_portfolio(&portfolio, LotSelection::Fifo).unwrap();
+        let realized = &book.realized()[0];
+        assert_eq!(realized.buy_price, dec!(100.5));
+        assert_eq!(realized.sell_price, dec!(109.5));
+        assert_eq!(realized.gain(), dec!(36.0));
+    }
+
+    #[test]
+    fn test_fractional_shares() {
+        let mut portfolio = Portfolio::new().unwrap();
+        portfolio.stocks = vec![Positions {
+            ticker: "ACME".to_string(),
+            account: String::new(),
+            shares: vec![
+                stock("2023-06-30", Action::Buy, dec!(12.3456), dec!(98.76)),
+                stock("2023-12-29", Action::Buy, dec!(7.1), dec!(101.1)),
+                stock("2024-02-01", Action::Sell, dec!(4.1152), dec!(110)),
+                stock("2024-03-01", Action::Sell, dec!(4.1152), dec!(110)),
+                stock("2024-04-01", Action::Sell, dec!(4.1152), dec!(110)),
+                stock("2024-05-01", Action::Sell, dec!(7.1), dec!(110)),
+            ],
+            actions: vec![],
+        }];
+        let book = LotBook::from_portfolio(&portfolio, LotSelection::Fifo).unwrap();
+        // The ESPP lot is used up exactly, the last sale only takes the second lot
+        assert_eq!(book.realized().len(), 4);
+        assert_eq!(book.realized()[3].buy_price, dec!(101.1));
+        assert_eq!(book.realized()[3].buy_id, "2023-12-29-Buy");
+        assert_eq!(book.realized()[3].sell_id, "2024-05-01-Sell");
+        assert!(book.open_lots("ACME").is_empty());
+        assert_eq!(
+            book.realized().iter().map(|r| r.gain()).sum::<Decimal>(),
+            dec!(201.94)
+        );
+    }
+
+    #[test]
+    fn test_reverse_split_and_oversell() {
+        let mut portfolio = Portfolio::new().unwrap();
+        portfolio.stocks = vec![Positions {
+            ticker: "ACME".to_string(),
+            account: String::new(),
+            shares: vec![
+                stock("2022-01-03", Action::Buy, dec!(100.0), dec!(2.0)),
+                stock("2022-05-02", Action::Sell, dec!(10.0), dec!(25.0)),
+            ],
+            actions: vec![corporate_action(
+                "2022-05-02",
+                CorporateActionKind::Split {
+                    numerator: dec!(1.0),
+                    denominator: dec!(10.0),
+                },
+            )],
+        }];
+        let book = LotBook::from_portfolio(&portfolio, LotSelection::Fifo).unwrap();
+        assert_eq!(book.realized()[0].original_units, dec!(100.0));
+        assert_eq!(book.realized()[0].buy_price, dec!(20.0));
+
+        portfolio.stocks[0]
+            .shares
+            .push(stock("2022-06-01", Action::Sell, dec!(1.0), dec!(25.0)));
+        assert!(LotBook::from_portfolio(&portfolio, LotSelection::Fifo).is_err());
+    }
+
+    #[test]
+    fn test_lot_selection() {
+        let mut sell = stock("2023-04-03", Action::Sell, dec!(15), dec!(250));
+        sell.lots = vec!["2023-03-01-Buy".to_string()];
+        let mut portfolio = Portfolio::new().unwrap();
+        portfolio.stocks = vec![Positions {
+            ticker: "ACME".to_string(),
+            account: String::new(),
+            shares: vec![
+                stock("2023-01-02", Action::Buy, dec!(10), dec!(100)),
+                stock("2023-02-01", Action::Buy, dec!(10), dec!(300)),
+                stock("2023-03-01", Action::Buy, dec!(10), dec!(200)),
+                sell,
+            ],
+            actions: vec![],
+        }];
+        let sold = |selection: LotSelection| -> Vec<(String, Decimal)> {
+            let book = LotBook::from_portfolio(&portfolio, selection).unwrap();
+            assert_eq!(book.open_lots("ACME").len(), 2);
+            book.realized()
+                .iter()
+                .map(|r| (r.buy_id.clone(), r.units))
+                .collect()
+        };
+        let lots = |lots: &[(&str, Decimal)]| -> Vec<(String, Decimal)> {
+            lots.iter()
+                .map(|(id, units)| (format!("{}-Buy", id), *units))
+                .collect()
+        };
+        assert_eq!(
+            sold(LotSelection::Fifo),
+            lots(&[("2023-01-02", dec!(10)), ("2023-02-01", dec!(5))])
+        );
+        assert_eq!(
+            sold(LotSelection::Lifo),
+            lots(&[("2023-03-01", dec!(10)), ("2023-02-01", dec!(5))])
+        );
+        assert_eq!(
+            sold(LotSelection::Hifo),
+            lots(&[("2023-02-01", dec!(10)), ("2023-03-01", dec!(5))])
+        );
+        assert_eq!(
+            sold(LotSelection::Lofo),
+            lots(&[("2023-01-02", dec!(10)), ("2023-03-01", dec!(5))])
+        );
+        // The listed lot first, the rest from the oldest lot
+        assert_eq!(
+            sold(LotSelection::SpecificId),
+            lots(&[("2023-03-01", dec!(10)), ("2023-01-02", dec!(5))])
+        );
+
+        // HIFO and LOFO can not compare the prices of lots bought in different currencies
+        portfolio.stocks[0].shares[1].currency = "EUR".to_string();
+        assert!(LotBook::from_portfolio(&portfolio, LotSelection::Hifo).is_err());
+        assert!(LotBook::from_portfolio(&portfolio, LotSelection::Fifo).is_ok());
+
+        assert_eq!("specific-id".parse(), Ok(LotSelection::SpecificId));
+        assert_eq!("HIFO".parse(), Ok(LotSelection::Hifo));
+        assert!("average".parse::<LotSelection>().is_err());
+    }
+}
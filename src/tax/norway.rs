@@ -0,0 +1,629 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+
+use chrono::{Datelike, NaiveDate};
+use log::{error, info, warn};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use super::{LotBook, LotSelection, TaxCalculatorService, TaxScope};
+use crate::fx::FxRates;
+use crate::money::round_money;
+use crate::portfolio::{Action, CorporateActionKind, Portfolio};
+
+static RF1159_OUTPUT_FILE: &str = "rf1159.csv";
+static SHIELDING_OUTPUT_FILE: &str = "shielding.csv";
+const NOK: &str = "NOK";
+
+/// The shielding deduction was introduced in 2006, lots get no shielding for earlier years.
+const FIRST_SHIELDING_YEAR: i32 = 2006;
+
+/// Shielding rates after tax (skjermingsrente) published by Skatteetaten, as fractions.
+/// Years missing here are given with `--shielding-rate`.
+const SHIELDING_RATES: [(i32, Decimal); 20] = [
+    (2006, dec!(0.021)),
+    (2007, dec!(0.034)),
+    (2008, dec!(0.044)),
+    (2009, dec!(0.014)),
+    (2010, dec!(0.017)),
+    (2011, dec!(0.018)),
+    (2012, dec!(0.013)),
+    (2013, dec!(0.012)),
+    (2014, dec!(0.011)),
+    (2015, dec!(0.008)),
+    (2016, dec!(0.004)),
+    (2017, dec!(0.007)),
+    (2018, dec!(0.008)),
+    (2019, dec!(0.013)),
+    (2020, dec!(0.006)),
+    (2021, dec!(0.005)),
+    (2022, dec!(0.017)),
+    (2023, dec!(0.032)),
+    (2024, dec!(0.039)),
+    (2025, dec!(0.037)),
+];
+
+/// Shielding of one lot in the tax year, amounts in NOK.
+#[derive(PartialEq, Debug, Clone)]
+pub struct LotShielding {
+    pub owner: String,
+    pub ticker: String,
+    pub buy_id: String,
+    pub account: String,
+    pub buy_date: NaiveDate,
+    /// Units held at the end of the year, zero for lots sold during the year.
+    pub units: Decimal,
+    /// Cost basis of the units held at the end of the year.
+    pub basis: Decimal,
+    /// Shielding deduction of the year, only lots held at the end of the year get one.
+    pub shielding: Decimal,
+    pub used_on_dividends: Decimal,
+    pub used_on_gains: Decimal,
+    /// Unused shielding carried to the next year, it adds to the shielding basis.
+    pub unused: Decimal,
+}
+
+/// The figures of one company on the RF-1159 foreign shares form, amounts in NOK.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Rf1159Entry {
+    pub owner: String,
+    pub ticker: String,
+    /// Units held at the end of the year.
+    pub units: Decimal,
+    pub dividends: Decimal,
+    pub withholding_tax: Decimal,
+    pub shielding_on_dividends: Decimal,
+    pub gains: Decimal,
+    pub losses: Decimal,
+    pub shielding_on_gains: Decimal,
+    /// Unused shielding of the lots held at the end of the year.
+    pub unused_shielding: Decimal,
+}
+
+impl Rf1159Entry {
+    pub fn taxable_dividends(&self) -> Decimal {
+        self.dividends - self.shielding_on_dividends
+    }
+    /// Gains less losses less the shielding used on gains.
+    pub fn taxable_gain(&self) -> Decimal {
+        self.gains - self.losses - self.shielding_on_gains
+    }
+}
+
+/// Norwegian tax on shares held by individuals. Gains, losses and dividends are reported
+/// in NOK and reduced by the shielding deduction (skjermingsfradrag): every year the owner
+/// at December 31 gets the shielding rate of the year on the basis of every lot, the cost
+/// basis plus the shielding not used in earlier years. The shielding is used on the
+/// dividends of the year first, the rest is carried forward and can reduce the gain of a
+/// sale of the lot but not make a loss. Shielding left when a lot is sold is lost.
+pub struct NorwegianTaxService {}
+
+/// Lots by account, the ticker the position is known as today and buy id, see
+/// [`Portfolio::current_ticker`]. Buy ids are only unique within a position.
+type LotKey = (String, String, String);
+
+fn lot_key(pool: &Portfolio, account: &str, ticker: &str, buy_id: &str) -> LotKey {
+    (
+        account.to_string(),
+        pool.current_ticker(ticker),
+        buy_id.to_string(),
+    )
+}
+
+/// Unused shielding per NOK of basis of a lot. Spun off shares keep the buy id of the shares
+/// they came from and take their shielding until they get their own at the end of the year.
+struct ShieldingState<'a> {
+    pool: &'a Portfolio,
+    ratios: HashMap<LotKey, Decimal>,
+}
+
+impl ShieldingState<'_> {
+    fn get(&self, account: &str, ticker: &str, buy_id: &str) -> Decimal {
+        let mut key = lot_key(self.pool, account, ticker, buy_id);
+        // Bounded by the number of positions in case of a loop of spin-offs
+        for _ in 0..=self.pool.stocks.len() {
+            if let Some(ratio) = self.ratios.get(&key) {
+                return *ratio;
+            }
+            match self.spun_off_from(&key.1) {
+                Some(parent) => key.1 = parent,
+                None => break,
+            }
+        }
+        Decimal::ZERO
+    }
+
+    /// Current ticker of the position `ticker` was spun off from.
+    fn spun_off_from(&self, ticker: &str) -> Option<String> {
+        self.pool.stocks.iter().find_map(|p| {
+            p.actions
+                .iter()
+                .any(|a| match &a.kind {
+                    CorporateActionKind::SpinOff { new_ticker, .. } => {
+                        self.pool.current_ticker(new_ticker) == ticker
+                    }
+                    _ => false,
+                })
+                .then(|| self.pool.current_ticker(&p.ticker))
+        })
+    }
+}
+
+impl NorwegianTaxService {
+    /// The built-in shielding rates with `overrides` added, `overrides` are `YEAR=PERCENT`
+    /// like `2024=3.4`.
+    pub fn shielding_rates(overrides: &[String]) -> Result<BTreeMap<i32, Decimal>, Box<dyn Error>> {
+        let mut rates: BTreeMap<i32, Decimal> = SHIELDING_RATES.into_iter().collect();
+        for rate in overrides.iter() {
+            let (year, percent) = rate
+                .split_once('=')
+                .ok_or_else(|| format!("Shielding rate {} is not YEAR=PERCENT", rate))?;
+            let percent: Decimal = percent.trim().replace(',', ".").parse()?;
+            rates.insert(year.trim().parse()?, percent / dec!(100));
+        }
+        Ok(rates)
+    }
+
+    /// Shielding of every lot and the RF-1159 figures of every owner and company in `year`.
+    /// Lots in tax-sheltered accounts are left out, the shares of an owner are matched in
+    /// FIFO order across all their taxable accounts.
+    pub fn report(
+        portfolio: &Portfolio,
+        fx: &FxRates,
+        rates: &BTreeMap<i32, Decimal>,
+        year: i32,
+    ) -> Result<(Vec<Rf1159Entry>, Vec<LotShielding>), Box<dyn Error>> {
+        let mut entries = vec![];
+        let mut lots = vec![];
+        for pool in TaxCalculatorService::tax_pools(portfolio, TaxScope::PerPerson) {
+            let account = pool
+                .stocks
+                .first()
+                .and_then(|p| portfolio.account(&p.account));
+            if account.is_some_and(|a| a.kind.is_tax_sheltered()) {
+                continue;
+            }
+            let owner = account.map(|a| a.owner.clone()).unwrap_or_default();
+            let (pool_entries, pool_lots) = Self::pool_report(&pool, &owner, fx, rates, year)?;
+            entries.extend(pool_entries);
+            lots.extend(pool_lots);
+        }
+        Ok((entries, lots))
+    }
+
+    fn pool_report(
+        pool: &Portfolio,
+        owner: &str,
+        fx: &FxRates,
+        rates: &BTreeMap<i32, Decimal>,
+        year: i32,
+    ) -> Result<(Vec<Rf1159Entry>, Vec<LotShielding>), Box<dyn Error>> {
+        let mut realized = vec![];
+        for lot in LotBook::from_portfolio(pool, LotSelection::Fifo)?.realized() {
+            realized.push(lot.convert(fx, NOK)?);
+        }
+        let first_year = pool
+            .stocks
+            .iter()
+            .flat_map(|p| p.shares.iter())
+            .map(|s| s.date.year())
+            .min()
+            .unwrap_or(year);
+        // Only lots held into the reported year need the shielding of earlier years
+        let mut needed: HashSet<(String, String)> = realized
+            .iter()
+            .filter(|l| l.sell_date.year() == year)
+            .map(|l| (l.buy_account.clone(), l.buy_id.clone()))
+            .collect();
+        let year_end = NaiveDate::from_ymd_opt(year, 12, 31)
+            .ok_or_else(|| format!("Invalid tax year {}", year))?;
+        let book = LotBook::from_portfolio_until(pool, LotSelection::Fifo, year_end)?;
+        for ticker in book.tickers() {
+            needed.extend(
+                book.open_lots(ticker)
+                    .iter()
+                    .map(|l| (l.account.clone(), l.buy.id.clone())),
+            );
+        }
+        let mut state = ShieldingState {
+            pool,
+            ratios: HashMap::new(),
+        };
+        let mut entries: Vec<Rf1159Entry> = vec![];
+        let mut lots = vec![];
+        for current_year in first_year..=year {
+            let reported = current_year == year;
+            // Sales use the shielding carried from earlier years, the lot gets none for
+            // the year it is sold in
+            for lot in realized
+                .iter()
+                .filter(|l| l.sell_date.year() == current_year)
+            {
+                let basis = lot.cost_basis();
+                let gain = lot.gain();
+                let used = (state.get(&lot.buy_account, &lot.ticker, &lot.buy_id) * basis)
+                    .min(gain.max(Decimal::ZERO));
+                if reported {
+                    let entry = entry(&mut entries, owner, &pool.current_ticker(&lot.ticker));
+                    if gain >= Decimal::ZERO {
+                        entry.gains += gain;
+                    } else {
+                        entry.losses -= gain;
+                    }
+                    entry.shielding_on_gains += used;
+                    lots.push(LotShielding {
+                        owner: owner.to_string(),
+                        ticker: lot.ticker.clone(),
+                        buy_id: lot.buy_id.clone(),
+                        account: lot.buy_account.clone(),
+                        buy_date: lot.buy_date,
+                        units: Decimal::ZERO,
+                        basis: Decimal::ZERO,
+                        shielding: Decimal::ZERO,
+                        used_on_dividends: Decimal::ZERO,
+                        used_on_gains: used,
+                        unused: Decimal::ZERO,
+                    });
+                }
+            }
+
+            // Dividends per share held at the payment, counted per share bought so splits
+            // later in the year do not change them
+            let mut dividends: HashMap<LotKey, Decimal> = HashMap::new();
+            for position in pool.stocks.iter() {
+                for stock in position.shares.iter() {
+                    if stock.date.year() != current_year
+                        || !matches!(stock.action, Action::Dividend | Action::WithholdingTax)
+                    {
+                        continue;
+                    }
+                    let amount =
+                        stock.price * stock.unit * fx.rate(stock.date, &stock.currency, NOK)?;
+                    if reported {
+                        let entry =
+                            entry(&mut entries, owner, &pool.current_ticker(&position.ticker));
+                        match stock.action {
+                            Action::Dividend => entry.dividends += amount,
+                            _ => entry.withholding_tax += amount,
+                        }
+                    }
+                    if stock.action != Action::Dividend {
+                        continue;
+                    }
+                    let book = LotBook::from_portfolio_until(pool, LotSelection::Fifo, stock.date)?;
+                    let held = book.open_lots(&position.ticker);
+                    let units: Decimal = held.iter().map(|l| l.unit).sum();
+                    if units.is_zero() {
+                        warn!(
+                            "{} dividend on {} without shares held, no shielding is used on it",
+                            position.ticker, stock.date
+                        );
+                        continue;
+                    }
+                    for lot in held {
+                        *dividends
+                            .entry(lot_key(pool, &lot.account, &position.ticker, &lot.buy.id))
+                            .or_default() += amount / units * lot.unit / lot.original_unit;
+                    }
+                }
+            }
+
+            let year_end = NaiveDate::from_ymd_opt(current_year, 12, 31)
+                .ok_or_else(|| format!("Invalid tax year {}", current_year))?;
+            let book = LotBook::from_portfolio_until(pool, LotSelection::Fifo, year_end)?;
+            let mut ratios = HashMap::new();
+            for ticker in book.tickers() {
+                for lot in book.open_lots(ticker) {
+                    if !needed.contains(&(lot.account.clone(), lot.buy.id.clone())) {
+                        continue;
+                    }
+                    let key = lot_key(pool, &lot.account, ticker, &lot.buy.id);
+                    let rate = shielding_rate(rates, current_year)?;
+                    let basis = lot.original_unit
+                        * lot.original_price
+                        * fx.rate(lot.buy.date, &lot.buy.currency, NOK)?;
+                    let carried = state.get(&lot.account, ticker, &lot.buy.id) * basis;
+                    let shielding = (basis + carried) * rate;
+                    let dividend =
+                        dividends.get(&key).copied().unwrap_or(Decimal::ZERO) * lot.original_unit;
+                    let used = (carried + shielding).min(dividend);
+                    let unused = carried + shielding - used;
+                    if !basis.is_zero() {
+                        ratios.insert(key, unused / basis);
+                    }
+                    if reported {
+                        let entry = entry(&mut entries, owner, &pool.current_ticker(ticker));
+                        entry.units += lot.unit;
+                        entry.shielding_on_dividends += used;
+                        entry.unused_shielding += unused;
+                        lots.push(LotShielding {
+                            owner: owner.to_string(),
+                            ticker: ticker.to_string(),
+                            buy_id: lot.buy.id.clone(),
+                            account: lot.account.clone(),
+                            buy_date: lot.buy.date,
+                            units: lot.unit,
+                            basis,
+                            shielding,
+                            used_on_dividends: used,
+                            used_on_gains: Decimal::ZERO,
+                            unused,
+                        });
+                    }
+                }
+            }
+            state.ratios.extend(ratios);
+        }
+        for entry in entries.iter_mut() {
+            for amount in [
+                &mut entry.dividends,
+                &mut entry.withholding_tax,
+                &mut entry.shielding_on_dividends,
+                &mut entry.gains,
+                &mut entry.losses,
+                &mut entry.shielding_on_gains,
+                &mut entry.unused_shielding,
+            ] {
+                *amount = round_money(*amount, NOK);
+            }
+        }
+        for lot in lots.iter_mut() {
+            for amount in [
+                &mut lot.basis,
+                &mut lot.shielding,
+                &mut lot.used_on_dividends,
+                &mut lot.used_on_gains,
+                &mut lot.unused,
+            ] {
+                *amount = round_money(*amount, NOK);
+            }
+        }
+        entries.sort_by(|a, b| a.ticker.cmp(&b.ticker));
+        Ok((entries, lots))
+    }
+
+    /// Writes the RF-1159 figures of `year` to `output/rf1159.csv` and the shielding of every
+    /// lot to `output/shielding.csv`.
+    pub fn rf1159_report(
+        portfolio: &Portfolio,
+        fx: &FxRates,
+        rates: &BTreeMap<i32, Decimal>,
+        year: i32,
+    ) {
+        let (entries, lots) = match Self::report(portfolio, fx, rates, year) {
+            Ok(report) => report,
+            Err(err) => {
+                error!("Norwegian tax report failed: {}", err);
+                return;
+            }
+        };
+        let mut output_string = "Owner,Ticker,Units,Dividends,WithholdingTax,ShieldingOnDividends,TaxableDividends,Gains,Losses,ShieldingOnGains,TaxableGain,UnusedShielding".to_string();
+        for entry in entries.iter() {
+            info!(
+                "{} {} {}: dividends {} shielding {} \t gains {} losses {} shielding {} \t unused {}",
+                year,
+                entry.owner,
+                entry.ticker,
+                entry.dividends,
+                entry.shielding_on_dividends,
+                entry.gains,
+                entry.losses,
+                entry.shielding_on_gains,
+                entry.unused_shielding
+            );
+            output_string = format!(
+                "{}\n{},{},{},{},{},{},{},{},{},{},{},{}",
+                output_string,
+                entry.owner,
+                entry.ticker,
+                entry.units,
+                entry.dividends,
+                entry.withholding_tax,
+                entry.shielding_on_dividends,
+                entry.taxable_dividends(),
+                entry.gains,
+                entry.losses,
+                entry.shielding_on_gains,
+                entry.taxable_gain(),
+                entry.unused_shielding
+            );
+        }
+        if let Err(err) =
+            TaxCalculatorService::write_output_file(RF1159_OUTPUT_FILE, &output_string)
+        {
+            error!("Failed to write {}: {}", RF1159_OUTPUT_FILE, err);
+        }
+        let mut output_string = "Owner,Ticker,BuyId,Account,BuyDate,Units,Basis,Shielding,UsedOnDividends,UsedOnGains,Unused".to_string();
+        for lot in lots.iter() {
+            output_string = format!(
+                "{}\n{},{},{},{},{},{},{},{},{},{},{}",
+                output_string,
+                lot.owner,
+                lot.ticker,
+                lot.buy_id,
+                lot.account,
+                lot.buy_date,
+                lot.units,
+                lot.basis,
+                lot.shielding,
+                lot.used_on_dividends,
+                lot.used_on_gains,
+                lot.unused
+            );
+        }
+        if let Err(err) =
+            TaxCalculatorService::write_output_file(SHIELDING_OUTPUT_FILE, &output_string)
+        {
+            error!("Failed to write {}: {}", SHIELDING_OUTPUT_FILE, err);
+        }
+    }
+}
+
+/// Shielding rate of `year`, zero before the shielding deduction was introduced.
+fn shielding_rate(rates: &BTreeMap<i32, Decimal>, year: i32) -> Result<Decimal, Box<dyn Error>> {
+    if year < FIRST_SHIELDING_YEAR {
+        return Ok(Decimal::ZERO);
+    }
+    rates.get(&year).copied().ok_or_else(|| {
+        format!(
+            "No shielding rate for {}, add it with --shielding-rate {}=PERCENT",
+            year, year
+        )
+        .into()
+    })
+}
+
+/// The entry of `ticker`, added when the owner has none yet.
+fn entry<'a>(entries: &'a mut Vec<Rf1159Entry>, owner: &str, ticker: &str) -> &'a mut Rf1159Entry {
+    match entries.iter().position(|e| e.ticker == ticker) {
+        Some(index) => &mut entries[index],
+        None => {
+            entries.push(Rf1159Entry {
+                owner: owner.to_string(),
+                ticker: ticker.to_string(),
+                ..Default::default()
+            });
+            entries.last_mut().unwrap()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portfolio::{CorporateAction, Positions};
+
+    #[test]
+    fn test_shielding() {
+        let test_portfolio = format!(
+            "{}/test_files/test_portfolio_norway.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let portfolio: Portfolio =
+            serde_json::from_str(&std::fs::read_to_string(test_portfolio).unwrap()).unwrap();
+        let fx = FxRates::new();
+        let rates = NorwegianTaxService::shielding_rates(&[]).unwrap();
+
+        // 2021 gives 0.5% of 20000, 2022 1.7% of 20100, of which 200 are used on the dividend
+        let (entries, lots) = NorwegianTaxService::report(&portfolio, &fx, &rates, 2022).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].owner, "Ola");
+        assert_eq!(entries[0].units, dec!(100));
+        assert_eq!(entries[0].dividends, dec!(200));
+        assert_eq!(entries[0].taxable_dividends(), Decimal::ZERO);
+        assert_eq!(entries[0].unused_shielding, dec!(241.70));
+        assert_eq!(lots[0].shielding, dec!(341.70));
+
+        // The 40 shares sold take their part of the unused shielding off the gain of 2000,
+        // the 60 left and the new lot get the shielding of 2023
+        let (entries, lots) = NorwegianTaxService::report(&portfolio, &fx, &rates, 2023).unwrap();
+        let entry = &entries[0];
+        assert_eq!((entry.ticker.as_str(), entry.units), ("EQNR", dec!(70)));
+        assert_eq!((entry.gains, entry.losses), (dec!(2000), Decimal::ZERO));
+        assert_eq!(entry.shielding_on_gains, dec!(96.68));
+        assert_eq!(entry.taxable_gain(), dec!(1903.32));
+        assert_eq!(entry.unused_shielding, dec!(629.66));
+        let unused: Vec<(&str, Decimal)> =
+            lots.iter().map(|l| (l.buy_id.as_str(), l.unused)).collect();
+        assert_eq!(
+            unused,
+            vec![
+                ("NORDNET-1000000001", Decimal::ZERO),
+                ("NORDNET-1000000001", dec!(533.66)),
+                ("NORDNET-1000000004", dec!(96))
+            ]
+        );
+
+        assert!(NorwegianTaxService::report(&portfolio, &fx, &rates, 2026).is_err());
+        let rates = NorwegianTaxService::shielding_rates(&["2026=3,4".to_string()]).unwrap();
+        assert!(NorwegianTaxService::report(&portfolio, &fx, &rates, 2026).is_ok());
+
+        // Once the lot of 2021 is sold, later years need no rates from before the lot of 2023
+        let mut sold = portfolio.clone();
+        let mut sale = sold.stocks[0].shares[2].clone();
+        sale.date = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        sale.unit = dec!(60);
+        sale.id = "NORDNET-1000000006".to_string();
+        sold.stocks[0].shares.push(sale);
+        let rates: BTreeMap<i32, Decimal> = rates.into_iter().filter(|(y, _)| *y >= 2023).collect();
+        assert!(NorwegianTaxService::report(&sold, &fx, &rates, 2024).is_err());
+        let (entries, lots) = NorwegianTaxService::report(&sold, &fx, &rates, 2025).unwrap();
+        assert_eq!(entries[0].units, dec!(10));
+        assert_eq!(lots.len(), 1);
+    }
+
+    #[test]
+    fn test_shielding_lineage() {
+        let test_portfolio = format!(
+            "{}/test_files/test_portfolio_norway.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let portfolio: Portfolio =
+            serde_json::from_str(&std::fs::read_to_string(test_portfolio).unwrap()).unwrap();
+        let fx = FxRates::new();
+        let rates = NorwegianTaxService::shielding_rates(&[]).unwrap();
+        let date = |d: &str| NaiveDate::parse_from_str(d, "%Y-%m-%d").unwrap();
+        let action = |kind: CorporateActionKind| CorporateAction {
+            date: date("2022-12-01"),
+            kind,
+            metadata: String::new(),
+        };
+
+        // A lot with the same id in another account of the owner gets neither the shielding
+        // carried from 2021 nor the dividend paid before it was bought
+        let mut two_accounts = portfolio.clone();
+        let mut dnb = two_accounts.accounts[0].clone();
+        dnb.id = "dnb".to_string();
+        two_accounts.accounts.push(dnb);
+        let mut buy = two_accounts.stocks[0].shares[0].clone();
+        buy.date = date("2022-06-01");
+        buy.unit = dec!(10);
+        two_accounts.stocks.push(Positions {
+            ticker: "EQNR".to_string(),
+            account: "dnb".to_string(),
+            shares: vec![buy],
+            actions: vec![],
+        });
+        let (_, lots) = NorwegianTaxService::report(&two_accounts, &fx, &rates, 2022).unwrap();
+        let unused: Vec<(&str, Decimal)> = lots
+            .iter()
+            .map(|l| (l.account.as_str(), l.unused))
+            .collect();
+        assert_eq!(unused, vec![("nordnet", dec!(241.70)), ("dnb", dec!(34))]);
+
+        // The shielding and the dividend follow the lot to its new ticker, the trades of
+        // 2023 are left out as they are on the old ticker
+        let mut renamed = portfolio.clone();
+        renamed.stocks[0].shares.truncate(2);
+        renamed
+            .add_corporate_action(
+                "EQNR",
+                action(CorporateActionKind::TickerChange {
+                    new_ticker: "EQUI".to_string(),
+                }),
+            )
+            .unwrap();
+        let (entries, _) = NorwegianTaxService::report(&renamed, &fx, &rates, 2022).unwrap();
+        assert_eq!(entries[0].ticker, "EQUI");
+        assert_eq!(entries[0].taxable_dividends(), Decimal::ZERO);
+        assert_eq!(entries[0].unused_shielding, dec!(241.70));
+
+        // Half the basis and the shielding carried from 2021 go to the spun off shares, the
+        // dividend paid before the spin-off only to the old ones
+        let mut spun_off = portfolio.clone();
+        spun_off
+            .add_corporate_action(
+                "EQNR",
+                action(CorporateActionKind::SpinOff {
+                    new_ticker: "SPIN".to_string(),
+                    ratio: Decimal::ONE,
+                    cost_allocation: dec!(0.5),
+                }),
+            )
+            .unwrap();
+        let (_, lots) = NorwegianTaxService::report(&spun_off, &fx, &rates, 2022).unwrap();
+        let unused: Vec<(&str, Decimal)> =
+            lots.iter().map(|l| (l.ticker.as_str(), l.unused)).collect();
+        assert_eq!(unused, vec![("EQNR", dec!(20.85)), ("SPIN", dec!(220.85))]);
+    }
+}
@@ -0,0 +1,466 @@
+use std::error::Error;
+
+use chrono::{Datelike, Months};
+use log::{error, info};
+use rust_decimal::Decimal;
+
+use super::{LotSelection, RealizedLot, TaxCalculatorService, TaxScope};
+use crate::fx::FxRates;
+use crate::money::round_money;
+use crate::portfolio::{Form1099B, Portfolio};
+
+static FORM_8949_OUTPUT_FILE: &str = "form8949.csv";
+static SCHEDULE_D_OUTPUT_FILE: &str = "schedule_d.csv";
+const USD: &str = "USD";
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Term {
+    /// Held one year or less.
+    Short,
+    /// Held more than one year.
+    Long,
+}
+
+/// Check box of a Form 8949 part, A to C are short-term and D to F long-term.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub enum Form8949Box {
+    /// Basis reported to the IRS on Form 1099-B.
+    A,
+    /// Form 1099-B without the basis reported to the IRS.
+    B,
+    /// No Form 1099-B.
+    C,
+    D,
+    E,
+    F,
+}
+
+impl Form8949Box {
+    fn new(term: Term, form_1099b: bool, basis_reported: bool) -> Self {
+        match (term, form_1099b, basis_reported) {
+            (Term::Short, true, true) => Form8949Box::A,
+            (Term::Short, true, false) => Form8949Box::B,
+            (Term::Short, false, _) => Form8949Box::C,
+            (Term::Long, true, true) => Form8949Box::D,
+            (Term::Long, true, false) => Form8949Box::E,
+            (Term::Long, false, _) => Form8949Box::F,
+        }
+    }
+    pub fn term(&self) -> Term {
+        match self {
+            Form8949Box::A | Form8949Box::B | Form8949Box::C => Term::Short,
+            Form8949Box::D | Form8949Box::E | Form8949Box::F => Term::Long,
+        }
+    }
+    /// Line of Schedule D the totals of the box go to.
+    pub fn schedule_d_line(&self) -> &'static str {
+        match self {
+            Form8949Box::A => "1b",
+            Form8949Box::B => "2",
+            Form8949Box::C => "3",
+            Form8949Box::D => "8b",
+            Form8949Box::E => "9",
+            Form8949Box::F => "10",
+        }
+    }
+}
+
+/// One row of Form 8949, amounts in USD.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Form8949Line {
+    pub form_box: Form8949Box,
+    /// Column (a), e.g. `10 sh. ACME`.
+    pub description: String,
+    pub date_acquired: chrono::NaiveDate,
+    pub date_sold: chrono::NaiveDate,
+    pub proceeds: Decimal,
+    pub cost_basis: Decimal,
+    /// Adjustment codes of column (f).
+    pub code: String,
+    /// Column (g), added to the gain.
+    pub adjustment: Decimal,
+    /// ESPP disposition of the sale, `None` for other shares.
+    pub disposition: Option<Disposition>,
+    /// Compensation income of an ESPP sale reported as wages, it is part of the cost basis.
+    pub ordinary_income: Decimal,
+    pub buy_id: String,
+    pub sell_id: String,
+}
+
+impl Form8949Line {
+    /// Column (h).
+    pub fn gain(&self) -> Decimal {
+        self.proceeds - self.cost_basis + self.adjustment
+    }
+}
+
+/// Kind of sale of ESPP shares.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Disposition {
+    /// Sold more than two years after the grant and more than one year after the purchase.
+    Qualifying,
+    Disqualifying,
+}
+
+/// Split of the gain of a sale of ESPP shares, amounts in USD.
+#[derive(PartialEq, Debug, Clone)]
+pub struct EsppIncome {
+    pub disposition: Disposition,
+    /// Compensation income reported as wages.
+    pub ordinary_income: Decimal,
+    /// Price paid for the shares plus the ordinary income, the capital gain is the proceeds
+    /// less this basis.
+    pub cost_basis: Decimal,
+}
+
+/// Totals of one Form 8949 box in a tax year, as carried to Schedule D.
+#[derive(PartialEq, Debug, Clone)]
+pub struct ScheduleDTotal {
+    pub year: i32,
+    pub form_box: Form8949Box,
+    pub proceeds: Decimal,
+    pub cost_basis: Decimal,
+    pub adjustment: Decimal,
+    pub gain: Decimal,
+}
+
+/// US capital gains of sales matched per account, reported on Form 8949 and Schedule D.
+pub struct UsTaxService {}
+
+impl UsTaxService {
+    /// Shares are held long-term when sold more than one year after the buy, a sale on the
+    /// anniversary of the buy is still short-term.
+    pub fn term(lot: &RealizedLot) -> Term {
+        match lot.buy_date.checked_add_months(Months::new(12)) {
+            Some(anniversary) if lot.sell_date > anniversary => Term::Long,
+            _ => Term::Short,
+        }
+    }
+
+    /// Splits the gain of a sale of ESPP shares. A disqualifying disposition has the discount
+    /// at the purchase date as ordinary income, whatever the sale price. A qualifying one has
+    /// the discount at the grant date as ordinary income, at most the gain over the price
+    /// paid. The discount rate is derived from the purchase price and the lower of the two
+    /// fair market values, as in plans with a lookback.
+    pub fn espp_income(lot: &RealizedLot) -> Option<EsppIncome> {
+        let espp = lot.espp.as_ref()?;
+        let held_after = |date: chrono::NaiveDate, months: u32| {
+            date.checked_add_months(Months::new(months))
+                .is_some_and(|end| lot.sell_date > end)
+        };
+        let disposition = if held_after(espp.grant_date, 24) && held_after(lot.buy_date, 12) {
+            Disposition::Qualifying
+        } else {
+            Disposition::Disqualifying
+        };
+        // Basis of the lot with the price paid instead of the fair market value
+        let discount = lot.original_units * (espp.purchase_fmv - espp.purchase_price);
+        let paid = lot.original_units * lot.original_buy_price - discount;
+        let ordinary_income = match disposition {
+            Disposition::Disqualifying => discount,
+            Disposition::Qualifying => {
+                let lower_fmv = espp.grant_fmv.min(espp.purchase_fmv);
+                let rate = if lower_fmv > Decimal::ZERO {
+                    Decimal::ONE - espp.purchase_price / lower_fmv
+                } else {
+                    Decimal::ZERO
+                };
+                (lot.proceeds() - paid)
+                    .min(lot.original_units * rate * espp.grant_fmv)
+                    .max(Decimal::ZERO)
+            }
+        };
+        Some(EsppIncome {
+            disposition,
+            ordinary_income: round_money(ordinary_income, &lot.buy_currency),
+            cost_basis: round_money(paid + ordinary_income, &lot.buy_currency),
+        })
+    }
+
+    /// Box and adjustment code of a realized lot, from the Form 1099-B of the account of the
+    /// sale. Sales without the basis reported, like RSU and ESPP shares whose basis is left
+    /// out or too low, go in box B or E with code B and the correct basis.
+    fn classify(lot: &RealizedLot, form_1099b: Form1099B) -> (Form8949Box, &'static str) {
+        let form_box = Form8949Box::new(
+            Self::term(lot),
+            form_1099b != Form1099B::None,
+            form_1099b != Form1099B::BasisNotReported,
+        );
+        let code = match form_1099b {
+            Form1099B::BasisNotReported => "B",
+            _ => "",
+        };
+        (form_box, code)
+    }
+
+    /// Form 8949 rows of all sales in taxable accounts, in USD. Sales take their units from
+    /// the lots in the order of `selection`, like the method chosen at the broker.
+    pub fn form_8949(
+        portfolio: &Portfolio,
+        selection: LotSelection,
+        fx: &FxRates,
+    ) -> Result<Vec<Form8949Line>, Box<dyn Error>> {
+        let realized = TaxCalculatorService::realize_in_currency(
+            portfolio,
+            TaxScope::PerAccount,
+            selection,
+            fx,
+            Some(USD),
+        )?;
+        let mut lines = vec![];
+        for lot in realized.iter() {
+            let account = portfolio.account(&lot.sell_account);
+            if account.is_some_and(|a| a.kind.is_tax_sheltered()) {
+                continue;
+            }
+            let (form_box, code) =
+                Self::classify(lot, account.map(|a| a.form_1099b).unwrap_or_default());
+            let espp = Self::espp_income(lot);
+            lines.push(Form8949Line {
+                form_box,
+                description: format!("{} sh. {}", lot.units.normalize(), lot.ticker),
+                date_acquired: lot.buy_date,
+                date_sold: lot.sell_date,
+                proceeds: lot.proceeds(),
+                cost_basis: espp
+                    .as_ref()
+                    .map(|e| e.cost_basis)
+                    .unwrap_or_else(|| lot.cost_basis()),
+                code: code.to_string(),
+                adjustment: Decimal::ZERO,
+                disposition: espp.as_ref().map(|e| e.disposition),
+                ordinary_income: espp.as_ref().map(|e| e.ordinary_income).unwrap_or_default(),
+                buy_id: lot.buy_id.clone(),
+                sell_id: lot.sell_id.clone(),
+            });
+        }
+        lines.sort_by_key(|l| (l.date_sold.year(), l.form_box, l.date_sold, l.date_acquired));
+        Ok(lines)
+    }
+
+    /// Sums the rows of every tax year and box.
+    pub fn schedule_d(lines: &[Form8949Line]) -> Vec<ScheduleDTotal> {
+        let mut totals: Vec<ScheduleDTotal> = vec![];
+        for line in lines.iter() {
+            let year = line.date_sold.year();
+            let total = match totals
+                .iter()
+                .position(|t| t.year == year && t.form_box == line.form_box)
+            {
+                Some(index) => &mut totals[index],
+                None => {
+                    totals.push(ScheduleDTotal {
+                        year,
+                        form_box: line.form_box,
+                        proceeds: Decimal::ZERO,
+                        cost_basis: Decimal::ZERO,
+                        adjustment: Decimal::ZERO,
+                        gain: Decimal::ZERO,
+                    });
+                    totals.last_mut().unwrap()
+                }
+            };
+            total.proceeds += line.proceeds;
+            total.cost_basis += line.cost_basis;
+            total.adjustment += line.adjustment;
+            total.gain += line.gain();
+        }
+        totals.sort_by_key(|t| (t.year, t.form_box));
+        totals
+    }
+
+    /// Writes the Form 8949 rows to `output/form8949.csv` and the Schedule D totals to
+    /// `output/schedule_d.csv`, and logs the net short-term and long-term gain of every year.
+    /// The rows have the columns of the form to be copied into tax software or the IRS
+    /// fillable form, the PDF itself is not filled in.
+    pub fn form_8949_report(portfolio: &Portfolio, selection: LotSelection, fx: &FxRates) {
+        let lines = match Self::form_8949(portfolio, selection, fx) {
+            Ok(lines) => lines,
+            Err(err) => {
+                error!("Form 8949 failed: {}", err);
+                return;
+            }
+        };
+        let mut output_string =
+            "Year,Box,Description,DateAcquired,DateSold,Proceeds,CostBasis,Code,Adjustment,GainOrLoss,EsppDisposition,OrdinaryIncome,BuyId,SellId"
+                .to_string();
+        for line in lines.iter() {
+            output_string = format!(
+                "{}\n{},{:?},{},{},{},{},{},{},{},{},{},{},{},{}",
+                output_string,
+                line.date_sold.year(),
+                line.form_box,
+                line.description,
+                line.date_acquired.format("%m/%d/%Y"),
+                line.date_sold.format("%m/%d/%Y"),
+                line.proceeds,
+                line.cost_basis,
+                line.code,
+                line.adjustment,
+                line.gain(),
+                line.disposition
+                    .map(|d| format!("{:?}", d))
+                    .unwrap_or_default(),
+                line.ordinary_income,
+                line.buy_id,
+                line.sell_id
+            );
+        }
+        if let Err(err) =
+            TaxCalculatorService::write_output_file(FORM_8949_OUTPUT_FILE, &output_string)
+        {
+            error!("Failed to write {}: {}", FORM_8949_OUTPUT_FILE, err);
+        }
+        let totals = Self::schedule_d(&lines);
+        let mut output_string =
+            "Year,Line,Box,Proceeds,CostBasis,Adjustment,GainOrLoss".to_string();
+        for total in totals.iter() {
+            output_string = format!(
+                "{}\n{},{},{:?},{},{},{},{}",
+                output_string,
+                total.year,
+                total.form_box.schedule_d_line(),
+                total.form_box,
+                total.proceeds,
+                total.cost_basis,
+                total.adjustment,
+                total.gain
+            );
+        }
+        let mut years: Vec<i32> = totals.iter().map(|t| t.year).collect();
+        years.dedup();
+        for year in years {
+            let net = |term: Term| -> Decimal {
+                totals
+                    .iter()
+                    .filter(|t| t.year == year && t.form_box.term() == term)
+                    .map(|t| t.gain)
+                    .sum()
+            };
+            let ordinary_income: Decimal = lines
+                .iter()
+                .filter(|l| l.date_sold.year() == year)
+                .map(|l| l.ordinary_income)
+                .sum();
+            info!(
+                "{}: short-term {} (line 7) long-term {} (line 15) ESPP ordinary income {}",
+                year,
+                net(Term::Short),
+                net(Term::Long),
+                ordinary_income
+            );
+        }
+        if let Err(err) =
+            TaxCalculatorService::write_output_file(SCHEDULE_D_OUTPUT_FILE, &output_string)
+        {
+            error!("Failed to write {}: {}", SCHEDULE_D_OUTPUT_FILE, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_form_8949() {
+        let test_portfolio = format!(
+            "{}/test_files/test_portfolio_us.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let portfolio: Portfolio =
+            serde_json::from_str(&std::fs::read_to_string(test_portfolio).unwrap()).unwrap();
+        let lines =
+            UsTaxService::form_8949(&portfolio, LotSelection::Fifo, &FxRates::new()).unwrap();
+        let rows: Vec<(Form8949Box, &str, &str, Decimal)> = lines
+            .iter()
+            .map(|l| {
+                (
+                    l.form_box,
+                    l.description.as_str(),
+                    l.code.as_str(),
+                    l.gain(),
+                )
+            })
+            .collect();
+        // RSU shares sold on the anniversary of the vest are short-term
+        assert_eq!(
+            rows,
+            vec![
+                (Form8949Box::A, "10 sh. BETA", "", dec!(95)),
+                (Form8949Box::B, "5 sh. ACME", "B", dec!(250)),
+                (Form8949Box::E, "5 sh. ACME", "B", dec!(-100)),
+                (Form8949Box::F, "100 sh. GAMMA", "", dec!(500)),
+            ]
+        );
+        let totals = UsTaxService::schedule_d(&lines);
+        let lines: Vec<(&str, Decimal, Decimal, Decimal)> = totals
+            .iter()
+            .map(|t| {
+                (
+                    t.form_box.schedule_d_line(),
+                    t.proceeds,
+                    t.cost_basis,
+                    t.gain,
+                )
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                ("1b", dec!(595), dec!(500), dec!(95)),
+                ("2", dec!(750), dec!(500), dec!(250)),
+                ("9", dec!(400), dec!(500), dec!(-100)),
+                ("10", dec!(2500), dec!(2000), dec!(500)),
+            ]
+        );
+        assert!(totals.iter().all(|t| t.year == 2023));
+    }
+
+    #[test]
+    fn test_espp_disposition() {
+        let test_portfolio = format!(
+            "{}/test_files/test_portfolio_espp.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let portfolio: Portfolio =
+            serde_json::from_str(&std::fs::read_to_string(test_portfolio).unwrap()).unwrap();
+        let lines =
+            UsTaxService::form_8949(&portfolio, LotSelection::Fifo, &FxRates::new()).unwrap();
+        let rows: Vec<_> = lines
+            .iter()
+            .map(|l| {
+                (
+                    l.form_box,
+                    l.disposition,
+                    l.ordinary_income,
+                    l.cost_basis,
+                    l.gain(),
+                )
+            })
+            .collect();
+        // Sold within a year of the purchase the discount of 35 at the purchase is income,
+        // held long enough only the 15% discount on the grant date FMV of 100 is
+        assert_eq!(
+            rows,
+            vec![
+                (
+                    Form8949Box::B,
+                    Some(Disposition::Disqualifying),
+                    dec!(350),
+                    dec!(1200),
+                    dec!(300)
+                ),
+                (
+                    Form8949Box::E,
+                    Some(Disposition::Qualifying),
+                    dec!(150),
+                    dec!(1000),
+                    dec!(500)
+                ),
+            ]
+        );
+    }
+}
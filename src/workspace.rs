@@ -0,0 +1,181 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+
+use crate::fx::FxRates;
+use crate::portfolio::Portfolio;
+
+/// Directory holding several portfolios, e.g. one per person and one for a company, stored
+/// as `<name>.json` next to each other. The file name is the name of the portfolio.
+pub struct Workspace {
+    dir: PathBuf,
+}
+
+impl Workspace {
+    /// Opens the workspace in `dir`, creating the directory if it does not exist yet.
+    pub fn open(dir: &Path) -> Result<Workspace, std::io::Error> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Workspace {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// Names of the portfolios in the workspace, sorted.
+    pub fn list(&self) -> Result<Vec<String>, std::io::Error> {
+        let mut names = vec![];
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "json")
+                && let Some(name) = path.file_stem().and_then(|s| s.to_str())
+            {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.file(name, "json").is_ok_and(|path| path.exists())
+    }
+
+    /// Path of the file of portfolio `name` with `extension`. Names are used as file names,
+    /// so they can not be empty or contain path separators.
+    pub fn file(&self, name: &str, extension: &str) -> Result<PathBuf, Box<dyn Error>> {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(format!("Invalid portfolio name {:?}", name).into());
+        }
+        Ok(self.dir.join(format!("{}.{}", name, extension)))
+    }
+
+    /// Loads portfolio `name`, a portfolio not in the workspace yet is created empty.
+    pub fn load(&self, name: &str) -> Result<Portfolio, Box<dyn Error>> {
+        let path = self.file(name, "json")?;
+        let mut portfolio = if path.exists() {
+            debug!("Loading {}", path.display());
+            Portfolio::load_from_disk(&path)?
+        } else {
+            info!("Creating portfolio {}", name);
+            Portfolio::new()?
+        };
+        portfolio.name = name.to_string();
+        Ok(portfolio)
+    }
+
+    /// Stores `portfolio` under its name.
+    pub fn store(&self, portfolio: &Portfolio) -> Result<(), Box<dyn Error>> {
+        let path = self.file(&portfolio.name, "json")?;
+        debug!("Storing {}", path.display());
+        portfolio.store_to_disk(&path)?;
+        Ok(())
+    }
+
+    /// Exchange rates of all files in the `rates` directory of the workspace.
+    pub fn fx_rates(&self) -> Result<FxRates, Box<dyn Error>> {
+        let mut fx = FxRates::new();
+        let dir = self.dir.join("rates");
+        if dir.is_dir() {
+            for file in crate::importer::collect_files(&dir.to_string_lossy())? {
+                fx.load_file(&file)?;
+            }
+        }
+        Ok(fx)
+    }
+
+    /// Copies the portfolio earlier versions stored in `legacy` into an empty workspace as
+    /// portfolio `test_portfolio` and returns its name. The file itself is left in place.
+    pub fn migrate(&self, legacy: &Path) -> Result<Option<String>, Box<dyn Error>> {
+        if !legacy.is_file() || !self.list()?.is_empty() {
+            return Ok(None);
+        }
+        info!("Copying {} into the workspace", legacy.display());
+        let mut portfolio = Portfolio::load_from_disk(legacy)?;
+        portfolio.name = "test_portfolio".to_string();
+        self.store(&portfolio)?;
+        Ok(Some(portfolio.name))
+    }
+
+    /// Copies portfolio `from` to the new portfolio `to`.
+    pub fn copy(&self, from: &str, to: &str) -> Result<(), Box<dyn Error>> {
+        if !self.exists(from) {
+            return Err(format!("There is no portfolio {}", from).into());
+        }
+        if self.exists(to) {
+            return Err(format!("Portfolio {} already exists", to).into());
+        }
+        let mut portfolio = self.load(from)?;
+        portfolio.name = to.to_string();
+        self.store(&portfolio)
+    }
+
+    /// Merges the accounts and positions of portfolio `from` into portfolio `into`, lots
+    /// already in `into` are skipped like on an import.
+    pub fn merge(&self, from: &str, into: &str) -> Result<(), Box<dyn Error>> {
+        if !self.exists(from) {
+            return Err(format!("There is no portfolio {}", from).into());
+        }
+        let source = self.load(from)?;
+        let mut target = self.load(into)?;
+        for account in source.accounts.iter() {
+            match target.account(&account.id) {
+                Some(existing) if existing != account => {
+                    return Err(format!(
+                        "Account {} differs between {} and {}",
+                        account.id, from, into
+                    )
+                    .into());
+                }
+                Some(_) => {}
+                None => target.add_account(account.clone())?,
+            }
+        }
+        target.merge_postions(&source.stocks)?;
+        self.store(&target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workspace() {
+        let dir = std::env::temp_dir().join(format!("rustyfolio-workspace-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let workspace = Workspace::open(&dir).unwrap();
+        let test_portfolio = format!(
+            "{}/test_files/test_portfolio_accounts.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let mut me = Portfolio::load_from_disk(Path::new(&test_portfolio)).unwrap();
+        me.name = "me".to_string();
+        workspace.store(&me).unwrap();
+        assert!(workspace.load("../me").is_err());
+        assert!(workspace.load("spouse").unwrap().stocks.is_empty());
+
+        workspace.copy("me", "copy").unwrap();
+        assert!(workspace.copy("me", "copy").is_err());
+        assert_eq!(workspace.load("copy").unwrap().stocks, me.stocks);
+
+        // Merging into a new portfolio takes everything, merging again adds nothing
+        workspace.merge("me", "household").unwrap();
+        workspace.merge("copy", "household").unwrap();
+        let household = workspace.load("household").unwrap();
+        assert_eq!(household.accounts, me.accounts);
+        assert_eq!(household.stocks, me.stocks);
+        assert_eq!(workspace.list().unwrap(), vec!["copy", "household", "me"]);
+
+        // Only an empty workspace takes the portfolio of earlier versions
+        assert_eq!(workspace.migrate(Path::new(&test_portfolio)).unwrap(), None);
+        let empty = Workspace::open(&dir.join("empty")).unwrap();
+        assert_eq!(empty.migrate(&dir.join("missing.json")).unwrap(), None);
+        assert_eq!(
+            empty.migrate(Path::new(&test_portfolio)).unwrap(),
+            Some("test_portfolio".to_string())
+        );
+        assert_eq!(empty.load("test_portfolio").unwrap().stocks, me.stocks);
+        assert_eq!(empty.migrate(Path::new(&test_portfolio)).unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}